@@ -49,6 +49,13 @@ const ItemText = styled.div`
   user-select: none;
 `;
 
+const ItemIcon = styled.i`
+  margin-left: 8px;
+  font-size: 12px;
+  width: 12px;
+  text-align: center;
+`;
+
 const SectionText = styled.div`
   margin: 8px;
   font-size: 14px;
@@ -97,6 +104,7 @@ export class ExplorerItem extends React.Component<ExplorerItemProps> {
     const { monospace } = props;
     return (
       <ExplorerItemContainer onClick={props.onClick}>
+        {props.icon ? <ItemIcon className={`fa fa-${props.icon}`} /> : null}
         <ItemText style={{ fontFamily: monospace ? MONOSPACE_FONT_FAMILY : 'inherit' }}>{props.text}</ItemText>
       </ExplorerItemContainer>
     )
@@ -140,7 +148,7 @@ export class ExplorerSection extends React.Component<ExplorerSectionProps> {
       <ExplorerSectionContainer>
         <SectionText>{SECTION_NAMES[id] || id}</SectionText>
         {pages.map(page => (
-          <ExplorerItem onClick={this.onClick_(page.id)} text={page.name} monospace={shouldMonospaceName(page)} />
+          <ExplorerItem onClick={this.onClick_(page.id)} text={page.name} icon={(page.metadata || {})['icon']} monospace={shouldMonospaceName(page)} />
         ))}
       </ExplorerSectionContainer>
     )
@@ -20,4 +20,12 @@ ReactDom.render((
       </ConnectedRouter>
     </Provider>
   </HashRouter>
-), mount);
\ No newline at end of file
+), mount);
+
+// When served by `drydoc serve`, reload whenever `drydoc watch` regenerates
+// the site. Static hosts don't provide this endpoint, in which case the
+// EventSource simply fails to connect.
+if (window.EventSource) {
+  const reload = new EventSource('__drydoc/reload');
+  reload.addEventListener('reload', () => window.location.reload());
+}
\ No newline at end of file
@@ -23,6 +23,11 @@ where
   pub res: Sender<Option<V>>,
 }
 
+/// Removes every entry.
+pub struct Clear {
+  pub res: Sender<()>,
+}
+
 pub enum Msg<K, V>
 where
   K: 'static + Send + Sync + Eq + Hash,
@@ -30,6 +35,7 @@ where
 {
   Insert(Insert<K, V>),
   Remove(Remove<K, V>),
+  Clear(Clear),
 }
 
 impl<K, V> From<Insert<K, V>> for Msg<K, V>
@@ -52,6 +58,16 @@ where
   }
 }
 
+impl<K, V> From<Clear> for Msg<K, V>
+where
+  K: 'static + Send + Sync + Eq + Hash,
+  V: 'static + Send + Sync,
+{
+  fn from(value: Clear) -> Self {
+    Self::Clear(value)
+  }
+}
+
 /// An actor-based implementation of a HashMap.
 pub struct Store<K, V>
 where
@@ -81,6 +97,10 @@ where
         Msg::Remove(Remove { key, res }) => {
           let _ = res.send(self.entries.remove(&key));
         }
+        Msg::Clear(Clear { res }) => {
+          self.entries.clear();
+          let _ = res.send(());
+        }
       }
     }
   }
@@ -120,4 +140,11 @@ where
     self.send(Remove { key, res: tx })?;
     Ok(rx.await.unwrap())
   }
+
+  pub async fn clear(&self) -> Result<(), SendError> {
+    let (tx, rx) = channel();
+    self.send(Clear { res: tx })?;
+    rx.await.unwrap();
+    Ok(())
+  }
 }
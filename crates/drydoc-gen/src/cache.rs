@@ -0,0 +1,412 @@
+//! A persistent cache of generated bundles.
+//!
+//! Each `Generate` decl is hashed together with the namespace it is generated
+//! into, the exact version of its generator and the contents of every file
+//! its parameters refer to. If a bundle
+//! was previously stored under the same hash, it is reused instead of
+//! invoking the generator again.
+
+use std::{
+  collections::HashMap,
+  future::Future,
+  path::{Path, PathBuf},
+  pin::Pin,
+  sync::{Arc, Mutex},
+};
+
+use drydoc_model::{
+  bundle::Bundle,
+  decl::{Generate, GeneratorSpec},
+  ns::Namespace,
+};
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{error::GenError, linked};
+
+/// A hash of a `Generate` decl and its inputs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+  /// Hash `generate` (excluding its children) as it would be generated by
+  /// `generator` (the package and version its spec resolves to) into
+  /// `namespace` from the config file at `path`. Input directories within
+  /// `ignored` aren't hashed.
+  pub async fn compute(
+    generate: &Generate,
+    generator: &str,
+    namespace: &Namespace,
+    path: &Path,
+    ignored: &[PathBuf],
+  ) -> Result<Self, GenError> {
+    let mut hasher = Sha256::default();
+
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hasher.update([0]);
+    hasher.update(namespace.to_string());
+    hasher.update([0]);
+    hasher.update(&generate.id);
+    hasher.update([0]);
+    hasher.update(generator);
+    hasher.update([0]);
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    // Local generators aren't versioned, so changing one must invalidate
+    // the bundles it generated
+    if let GeneratorSpec::Path(generator) = &generate.using {
+      let generator = dir.join(generator);
+      if generator.exists() {
+        hash_path(&mut hasher, generator, ignored, true).await?;
+      }
+    }
+
+    // Map keys are sorted, so equal parameters always serialize identically
+    let params = serde_json::to_string(&generate.with).expect("Params are valid JSON");
+    hasher.update(params);
+    hasher.update([0]);
+
+    for value in generate.with.values() {
+      for input in input_paths(dir, value) {
+        hash_path(&mut hasher, input, ignored, true).await?;
+      }
+    }
+
+    Ok(Self(hex::encode(hasher.finalize())))
+  }
+}
+
+/// Guess which paths a parameter value refers to. Generators resolve paths
+/// relative to the directory of the config file. Every string within
+/// `value` is considered, and since older generators accept comma-separated
+/// lists of paths, so is each part of a comma-separated string.
+pub fn input_paths(dir: &Path, value: &Value) -> Vec<PathBuf> {
+  let mut ret = Vec::new();
+  collect_input_paths(dir, value, &mut ret);
+  ret
+}
+
+fn collect_input_paths(dir: &Path, value: &Value, paths: &mut Vec<PathBuf>) {
+  let value = match value {
+    Value::String(value) => value.as_str(),
+    Value::Array(values) => {
+      for value in values {
+        collect_input_paths(dir, value, paths);
+      }
+      return;
+    }
+    Value::Object(values) => {
+      for value in values.values() {
+        collect_input_paths(dir, value, paths);
+      }
+      return;
+    }
+    _ => return,
+  };
+
+  let candidates = std::iter::once(value).chain(value.split(',').map(|part| part.trim()));
+  for candidate in candidates {
+    if candidate.is_empty() {
+      continue;
+    }
+
+    let path = dir.join(candidate);
+    if path.exists() && !paths.contains(&path) {
+      paths.push(path);
+    }
+  }
+}
+
+/// Directories of version control systems and build tools, which are large
+/// and change without affecting what's generated, so are never hashed.
+const SKIPPED_DIRS: [&str; 6] = [
+  ".git",
+  ".hg",
+  ".svn",
+  "target",
+  "node_modules",
+  "__pycache__",
+];
+
+/// Hash the name and contents of the file or directory at `path`, skipping
+/// the directories in `ignored` and `SKIPPED_DIRS`. Symbolic links within
+/// directories are hashed by their target rather than followed, so that
+/// links to an ancestor don't recurse forever. `path` itself is followed if
+/// `follow`.
+fn hash_path<'a>(
+  hasher: &'a mut Sha256,
+  path: PathBuf,
+  ignored: &'a [PathBuf],
+  follow: bool,
+) -> Pin<Box<dyn Future<Output = Result<(), GenError>> + 'a>> {
+  Box::pin(async move {
+    let metadata = if follow {
+      tokio::fs::metadata(&path).await
+    } else {
+      tokio::fs::symlink_metadata(&path).await
+    }
+    .map_err(|err| GenError::io(&path, err))?;
+
+    let is_dir = metadata.is_dir();
+    if is_dir && std::fs::canonicalize(&path).is_ok_and(|path| ignored.contains(&path)) {
+      return Ok(());
+    }
+
+    hasher.update(path.to_string_lossy().as_bytes());
+    hasher.update([0]);
+
+    if metadata.file_type().is_symlink() {
+      let target = tokio::fs::read_link(&path)
+        .await
+        .map_err(|err| GenError::io(&path, err))?;
+      hasher.update(b"-> ");
+      hasher.update(target.to_string_lossy().as_bytes());
+      hasher.update([0]);
+    } else if is_dir {
+      let mut dir = tokio::fs::read_dir(&path)
+        .await
+        .map_err(|err| GenError::io(&path, err))?;
+
+      // Directory listings aren't ordered, so sort them first
+      let mut entries = Vec::new();
+      while let Some(entry) = dir
+        .next_entry()
+        .await
+        .map_err(|err| GenError::io(&path, err))?
+      {
+        let skipped = SKIPPED_DIRS.iter().any(|name| entry.file_name() == *name);
+        if !skipped {
+          entries.push(entry.path());
+        }
+      }
+      entries.sort();
+
+      for entry in entries {
+        hash_path(hasher, entry, ignored, false).await?;
+      }
+    } else {
+      let contents = tokio::fs::read(&path)
+        .await
+        .map_err(|err| GenError::io(&path, err))?;
+      hasher.update(contents);
+      hasher.update([0]);
+    }
+
+    Ok(())
+  })
+}
+
+/// A directory of previously generated bundles.
+#[derive(Clone)]
+pub struct BuildCache {
+  dir: PathBuf,
+  /// Where the website is written
+  output: PathBuf,
+}
+
+impl BuildCache {
+  pub fn new<P: AsRef<Path>, O: AsRef<Path>>(dir: P, output: O) -> Self {
+    Self {
+      dir: dir.as_ref().to_path_buf(),
+      output: output.as_ref().to_path_buf(),
+    }
+  }
+
+  /// The directories that change on every build, so are never hashed as
+  /// inputs (e.g., when a parameter names the whole project with `.`).
+  /// Canonicalized, as they're compared to the canonical paths of inputs.
+  pub fn ignored(&self) -> Vec<PathBuf> {
+    [&self.dir, &self.output]
+      .iter()
+      .filter_map(|dir| std::fs::canonicalize(dir).ok())
+      .collect()
+  }
+
+  fn entry_path(&self, key: &CacheKey) -> PathBuf {
+    self.dir.join(format!("{}.json", key.0))
+  }
+
+  /// Retrieve the bundle stored under `key`, if any. Unreadable entries,
+  /// and those referring to files that were since removed, are treated as
+  /// cache misses.
+  pub async fn get(&self, key: &CacheKey) -> Option<Bundle> {
+    let contents = tokio::fs::read(self.entry_path(key)).await.ok()?;
+    let bundle: Bundle = serde_json::from_slice(contents.as_slice()).ok()?;
+    Some(bundle).filter(|bundle| linked::is_complete(&bundle.resources))
+  }
+
+  /// Store `bundle` under `key`.
+  pub async fn put(&self, key: &CacheKey, bundle: &Bundle) -> Result<(), GenError> {
+    tokio::fs::create_dir_all(&self.dir)
+      .await
+      .map_err(|err| GenError::io(&self.dir, err))?;
+
+    // Write to a temporary file first so an interrupted run never leaves a
+    // truncated entry behind.
+    let path = self.entry_path(key);
+    let tmp_path = path.with_extension("json.tmp");
+    let contents = serde_json::to_vec(bundle).map_err(|err| GenError::io(&path, err))?;
+    tokio::fs::write(&tmp_path, contents)
+      .await
+      .map_err(|err| GenError::io(&tmp_path, err))?;
+    tokio::fs::rename(&tmp_path, &path)
+      .await
+      .map_err(|err| GenError::io(&path, err))
+  }
+}
+
+/// The bundles generated for each decl (excluding its children) during a
+/// `drydoc watch` session, keyed by the decl's path, so that decls whose
+/// inputs haven't changed aren't regenerated when others have.
+#[derive(Clone, Default)]
+pub struct Memo(Arc<Mutex<HashMap<String, Bundle>>>);
+
+impl Memo {
+  pub fn get(&self, path: &str) -> Option<Bundle> {
+    self.0.lock().unwrap().get(path).cloned()
+  }
+
+  pub fn put(&self, path: String, bundle: Bundle) {
+    self.0.lock().unwrap().insert(path, bundle);
+  }
+
+  pub fn clear(&self) {
+    self.0.lock().unwrap().clear();
+  }
+
+  /// Forget the bundle of the decl at `path`.
+  pub fn invalidate(&self, path: &str) {
+    self.0.lock().unwrap().remove(path);
+  }
+
+  /// Forget the bundles of the decl at `path` and its descendants.
+  pub fn invalidate_tree(&self, path: &str) {
+    let prefix = format!("{}/", path);
+    self
+      .0
+      .lock()
+      .unwrap()
+      .retain(|decl, _| decl != path && !decl.starts_with(prefix.as_str()));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An empty directory for the test `name`.
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("drydoc-cache-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::canonicalize(dir).unwrap()
+  }
+
+  fn generate(with: Value) -> Generate {
+    serde_json::from_value(serde_json::json!({
+      "id": "api",
+      "using": "clang@^1.0",
+      "with": with,
+    }))
+    .unwrap()
+  }
+
+  async fn key(generate: &Generate, generator: &str, dir: &Path, ignored: &[PathBuf]) -> CacheKey {
+    let namespace = Namespace::new("root");
+    CacheKey::compute(
+      generate,
+      generator,
+      &namespace,
+      &dir.join("drydoc.yaml"),
+      ignored,
+    )
+    .await
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn depends_on_resolved_generator() {
+    let dir = temp_dir("generator");
+    let generate = generate(serde_json::json!({ "flag": true }));
+
+    let old = key(&generate, "clang@1.0.0", &dir, &[]).await;
+    assert_eq!(old, key(&generate, "clang@1.0.0", &dir, &[]).await);
+    assert_ne!(old, key(&generate, "clang@1.1.0", &dir, &[]).await);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn depends_on_inputs() {
+    let dir = temp_dir("inputs");
+    std::fs::create_dir(dir.join("src")).unwrap();
+    std::fs::write(dir.join("src/a.h"), "int a;").unwrap();
+    let generate = generate(serde_json::json!({ "paths": "src, missing" }));
+
+    let old = key(&generate, "clang@1.0.0", &dir, &[]).await;
+    std::fs::write(dir.join("src/a.h"), "int b;").unwrap();
+    let changed = key(&generate, "clang@1.0.0", &dir, &[]).await;
+    assert_ne!(old, changed);
+
+    std::fs::write(dir.join("src/b.h"), "").unwrap();
+    assert_ne!(changed, key(&generate, "clang@1.0.0", &dir, &[]).await);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn skips_ignored_dirs() {
+    let dir = temp_dir("ignored");
+    std::fs::create_dir(dir.join(".drydoc-cache")).unwrap();
+    std::fs::write(dir.join("a.h"), "int a;").unwrap();
+    let cache = BuildCache::new(dir.join(".drydoc-cache"), dir.join("html"));
+    let ignored = cache.ignored();
+    assert_eq!(ignored, vec![dir.join(".drydoc-cache")]);
+
+    let generate = generate(serde_json::json!({ "paths": "." }));
+    let old = key(&generate, "clang@1.0.0", &dir, &ignored).await;
+    std::fs::write(dir.join(".drydoc-cache/entry.json"), "{}").unwrap();
+    assert_eq!(old, key(&generate, "clang@1.0.0", &dir, &ignored).await);
+
+    std::fs::write(dir.join("a.h"), "int b;").unwrap();
+    assert_ne!(old, key(&generate, "clang@1.0.0", &dir, &ignored).await);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn skips_vcs_and_build_dirs() {
+    let dir = temp_dir("skipped");
+    std::fs::create_dir(dir.join(".git")).unwrap();
+    std::fs::create_dir(dir.join("target")).unwrap();
+    std::fs::write(dir.join("a.h"), "int a;").unwrap();
+
+    let generate = generate(serde_json::json!({ "paths": "." }));
+    let old = key(&generate, "clang@1.0.0", &dir, &[]).await;
+    std::fs::write(dir.join(".git/HEAD"), "ref: refs/heads/main").unwrap();
+    std::fs::write(dir.join("target/a.o"), "").unwrap();
+    assert_eq!(old, key(&generate, "clang@1.0.0", &dir, &[]).await);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn hashes_symlinks_without_following_them() {
+    let dir = temp_dir("symlinks");
+    std::fs::create_dir(dir.join("src")).unwrap();
+    std::fs::write(dir.join("src/a.h"), "int a;").unwrap();
+    std::os::unix::fs::symlink(".", dir.join("src/loop")).unwrap();
+    // Named inputs are followed
+    std::os::unix::fs::symlink("src", dir.join("include")).unwrap();
+
+    let generate = generate(serde_json::json!({ "paths": "include" }));
+    let old = key(&generate, "clang@1.0.0", &dir, &[]).await;
+    std::fs::write(dir.join("src/a.h"), "int b;").unwrap();
+    let changed = key(&generate, "clang@1.0.0", &dir, &[]).await;
+    assert_ne!(old, changed);
+
+    std::fs::remove_file(dir.join("src/loop")).unwrap();
+    std::os::unix::fs::symlink("..", dir.join("src/loop")).unwrap();
+    assert_ne!(changed, key(&generate, "clang@1.0.0", &dir, &[]).await);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,23 @@
+//! Validate the configuration without generating anything.
+
+use clap::Clap;
+use log::info;
+
+use crate::{config, error::GenError, validate, GenCtx, GenOpts};
+
+/// Check the configuration for errors without running any generators or
+/// writing any output.
+#[derive(Clap, Debug)]
+pub struct CheckOpts {}
+
+pub async fn check(opts: &GenOpts, _check_opts: &CheckOpts, ctx: &GenCtx) -> Result<(), GenError> {
+  let (root, errors) = config::load_tree(&opts.config, &ctx.preprocess, &ctx.resources).await?;
+  let summary = validate::validate(&root, errors, &ctx.mgr).await?;
+
+  info!(
+    "{} is valid ({} decls using {} generators)",
+    opts.config, summary.decls, summary.generators
+  );
+
+  Ok(())
+}
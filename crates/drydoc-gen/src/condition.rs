@@ -0,0 +1,219 @@
+//! Evaluation of the `when` conditions that include or exclude decls.
+//!
+//! Conditions are evaluated before decls are deserialized, so this works on
+//! the raw configuration rather than `drydoc_model::decl::Condition`, which
+//! describes them in the schema.
+
+use std::collections::HashSet;
+
+use crate::value::{Mapping, Value};
+
+/// The state conditions are evaluated against.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+  /// The profiles selected with `--profile`
+  pub profiles: HashSet<String>,
+}
+
+/// A string, or a list of strings.
+fn strings<'a>(field: &str, value: &'a Value) -> Result<Vec<&'a str>, String> {
+  let expected = || format!("Expected `{}` to be a string or a list of strings", field);
+  match value {
+    Value::String(value) => Ok(vec![value.as_str()]),
+    Value::Sequence(values) => values
+      .iter()
+      .map(|value| value.as_str().ok_or_else(expected))
+      .collect(),
+    _ => Err(expected()),
+  }
+}
+
+/// A list of conditions.
+fn conditions<'a>(field: &str, value: &'a Value) -> Result<&'a [Value], String> {
+  match value {
+    Value::Sequence(conditions) => Ok(conditions.as_slice()),
+    _ => Err(format!("Expected `{}` to be a list of conditions", field)),
+  }
+}
+
+/// Whether every field of `condition` holds.
+fn evaluate_mapping(condition: &Mapping, context: &Context) -> Result<bool, String> {
+  let mut result = true;
+  for (field, value) in condition {
+    let holds = match field.as_str() {
+      // Any of the profiles is selected
+      "profile" => strings(field, value)?
+        .iter()
+        .any(|profile| context.profiles.contains(*profile)),
+      // Every variable is set to a non-empty value
+      "env" => strings(field, value)?
+        .iter()
+        .all(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty())),
+      // The target is any of the operating systems
+      "os" => strings(field, value)?.contains(&std::env::consts::OS),
+      "not" => !evaluate(value, context)?,
+      "all" => {
+        let mut all = true;
+        for condition in conditions(field, value)? {
+          all &= evaluate(condition, context)?;
+        }
+        all
+      }
+      "any" => {
+        let mut any = false;
+        for condition in conditions(field, value)? {
+          any |= evaluate(condition, context)?;
+        }
+        any
+      }
+      field => {
+        return Err(format!(
+          "Unknown condition `{}` (expected `profile`, `env`, `os`, `not`, `all` or `any`)",
+          field
+        ))
+      }
+    };
+
+    // Keep going, so that invalid conditions are always reported
+    result &= holds;
+  }
+
+  Ok(result)
+}
+
+/// Whether the `when` condition `condition` holds.
+pub fn evaluate(condition: &Value, context: &Context) -> Result<bool, String> {
+  match condition {
+    Value::Bool(value) => Ok(*value),
+    Value::Mapping(condition) => evaluate_mapping(condition, context),
+    _ => Err("Expected `when` to be a condition (e.g., `profile: public`)".to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::value::Format;
+
+  fn evaluate_yaml(condition: &str, profiles: &[&str]) -> Result<bool, String> {
+    let context = Context {
+      profiles: profiles.iter().map(|profile| profile.to_string()).collect(),
+    };
+    evaluate(&Format::Yaml.parse(condition).unwrap(), &context)
+  }
+
+  fn holds(condition: &str) -> bool {
+    evaluate_yaml(condition, &["public", "beta"]).unwrap()
+  }
+
+  #[test]
+  fn evaluates_booleans() {
+    assert!(holds("true"));
+    assert!(!holds("false"));
+    assert!(holds("{}"));
+  }
+
+  #[test]
+  fn evaluates_profiles() {
+    assert!(holds("profile: public"));
+    assert!(holds("profile: [internal, beta]"));
+    assert!(!holds("profile: internal"));
+    assert!(!holds("profile: []"));
+    assert!(!evaluate_yaml("profile: public", &[]).unwrap());
+  }
+
+  #[test]
+  fn evaluates_env() {
+    std::env::set_var("DRYDOC_CONDITION_SET", "1");
+    std::env::set_var("DRYDOC_CONDITION_EMPTY", "");
+    std::env::remove_var("DRYDOC_CONDITION_UNSET");
+
+    assert!(holds("env: DRYDOC_CONDITION_SET"));
+    assert!(!holds("env: DRYDOC_CONDITION_EMPTY"));
+    assert!(!holds("env: DRYDOC_CONDITION_UNSET"));
+    // Every variable must be set
+    assert!(!holds(
+      "env: [DRYDOC_CONDITION_SET, DRYDOC_CONDITION_UNSET]"
+    ));
+  }
+
+  #[test]
+  fn evaluates_os() {
+    let os = std::env::consts::OS;
+    assert!(holds(format!("os: {}", os).as_str()));
+    assert!(holds(format!("os: [plan9, {}]", os).as_str()));
+    assert!(!holds("os: plan9"));
+  }
+
+  #[test]
+  fn combines_conditions() {
+    assert!(holds("not: { profile: internal }"));
+    assert!(!holds("not: true"));
+    assert!(holds("all: [true, { profile: public }]"));
+    assert!(!holds("all: [true, false]"));
+    assert!(holds("all: []"));
+    assert!(holds("any: [false, { profile: beta }]"));
+    assert!(!holds("any: [false, { profile: internal }]"));
+    assert!(!holds("any: []"));
+    // Every field of a mapping must hold
+    assert!(holds("{ profile: public, not: false }"));
+    assert!(!holds("{ profile: public, os: plan9 }"));
+  }
+
+  #[test]
+  fn rejects_invalid_conditions() {
+    let error = |condition: &str| evaluate_yaml(condition, &[]).unwrap_err();
+
+    assert_eq!(
+      error("public"),
+      "Expected `when` to be a condition (e.g., `profile: public`)"
+    );
+    assert_eq!(
+      error("platform: linux"),
+      "Unknown condition `platform` (expected `profile`, `env`, `os`, `not`, `all` or `any`)"
+    );
+    assert_eq!(
+      error("profile: [public, 1]"),
+      "Expected `profile` to be a string or a list of strings"
+    );
+    assert_eq!(
+      error("any: true"),
+      "Expected `any` to be a list of conditions"
+    );
+    assert_eq!(
+      error("not: [true]"),
+      "Expected `when` to be a condition (e.g., `profile: public`)"
+    );
+    // Even if an earlier condition doesn't hold
+    assert_eq!(
+      error("{ profile: public, os: 1 }"),
+      "Expected `os` to be a string or a list of strings"
+    );
+    assert_eq!(
+      error("any: [true, { unknown: 1 }]"),
+      "Unknown condition `unknown` (expected `profile`, `env`, `os`, `not`, `all` or `any`)"
+    );
+  }
+
+  #[test]
+  fn accepts_modeled_conditions() {
+    use drydoc_model::decl::{Condition, Conditions, Strings};
+
+    let condition = Condition::All(Conditions {
+      profile: Some(Strings::One("public".to_string())),
+      env: Some(Strings::Many(Vec::new())),
+      os: Some(Strings::Many(vec![std::env::consts::OS.to_string()])),
+      not: Some(Box::new(Condition::Constant(false))),
+      all: Some(vec![Condition::Constant(true)]),
+      any: Some(vec![Condition::All(Conditions::default())]),
+    });
+    let condition: Value =
+      serde_yaml::from_value(serde_yaml::to_value(&condition).unwrap()).unwrap();
+
+    let context = Context {
+      profiles: vec!["public".to_string()].into_iter().collect(),
+    };
+    assert_eq!(evaluate(&condition, &context), Ok(true));
+  }
+}
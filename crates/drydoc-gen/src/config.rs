@@ -0,0 +1,1032 @@
+//! Loading of `drydoc.yaml` configuration files.
+
+use std::{
+  collections::{HashMap, HashSet},
+  future::Future,
+  path::{Path, PathBuf},
+  pin::Pin,
+  sync::Arc,
+};
+
+use drydoc_model::decl::{Decl, Generate, GeneratorSpec, Import};
+use futures::future::join_all;
+
+use crate::{
+  error::{GenError, Location},
+  preprocessor::{self, Scope},
+  remote::{self, Remote},
+  resource::{GetError, UriCache},
+  source::{Mark, SourceMap},
+  value::{Format, Value},
+};
+
+/// Where a decl was declared.
+#[derive(Debug, Clone)]
+pub struct Origin {
+  /// The configuration file the decl was declared in
+  pub file: Arc<PathBuf>,
+  source: Arc<SourceMap>,
+  /// Path of the decl within `file`'s document
+  pointer: String,
+  /// The `import` decls through which `file` was reached, innermost first
+  included_from: Arc<Vec<Location>>,
+  /// How substitutions in `file` and the files it imports are run
+  options: Arc<preprocessor::Options>,
+  /// The variables and templates in scope at each `import` decl in `file`,
+  /// by pointer
+  imports: Arc<HashMap<String, Scope>>,
+  /// The pointers into `file`'s source of decls that moved when excluded
+  /// decls were removed, keyed by `pointer`
+  pointers: Arc<HashMap<String, String>>,
+  /// Where `file` was fetched from, if it was imported by URI
+  remote: Option<Arc<Remote>>,
+  /// The contents of every URI fetched so far
+  resources: UriCache,
+}
+
+impl Origin {
+  fn root(
+    file: PathBuf,
+    source: SourceMap,
+    included_from: Vec<Location>,
+    options: Arc<preprocessor::Options>,
+    resources: UriCache,
+  ) -> Self {
+    Self {
+      file: Arc::new(file),
+      source: Arc::new(source),
+      pointer: String::new(),
+      included_from: Arc::new(included_from),
+      options,
+      imports: Arc::new(HashMap::new()),
+      pointers: Arc::new(HashMap::new()),
+      remote: None,
+      resources,
+    }
+  }
+
+  /// The origin of this decl's `index`th child.
+  pub fn child(&self, index: usize) -> Self {
+    Self {
+      file: self.file.clone(),
+      source: self.source.clone(),
+      pointer: format!("{}/children/{}", self.pointer, index),
+      included_from: self.included_from.clone(),
+      options: self.options.clone(),
+      imports: self.imports.clone(),
+      pointers: self.pointers.clone(),
+      remote: self.remote.clone(),
+      resources: self.resources.clone(),
+    }
+  }
+
+  /// The directory relative paths in the decl are relative to.
+  pub fn dir(&self) -> &Path {
+    self.file.parent().unwrap_or_else(|| Path::new(""))
+  }
+
+  /// `spec`, with a local generator's path made relative to the working
+  /// directory instead of this decl's file.
+  pub fn resolve_generator(&self, spec: &GeneratorSpec) -> GeneratorSpec {
+    match spec {
+      GeneratorSpec::Path(path) => {
+        // Canonicalize so that the same generator is always identified by
+        // the same path, however it was referred to
+        let path = self.dir().join(path);
+        GeneratorSpec::Path(std::fs::canonicalize(&path).unwrap_or(path))
+      }
+      spec => spec.clone(),
+    }
+  }
+
+  /// The local file `file` is read from: `file` itself, the file or archive
+  /// it was extracted from if it was imported by a `file://` URI, or `None`
+  /// if it was fetched over HTTP.
+  pub fn local_file(&self) -> Option<PathBuf> {
+    match &self.remote {
+      Some(remote) => remote.local_path(),
+      None => Some(self.file.as_ref().clone()),
+    }
+  }
+
+  /// Path of the decl within `file`'s source.
+  fn source_pointer(&self) -> &str {
+    self
+      .pointers
+      .get(&self.pointer)
+      .unwrap_or(&self.pointer)
+      .as_str()
+  }
+
+  /// The location of the decl.
+  pub fn location(&self) -> Location {
+    self.locate(self.source.mark(self.source_pointer()))
+  }
+
+  /// The location of the decl's field `name`, falling back to the location
+  /// of the decl if the field isn't present. Nested fields are separated by
+  /// `/` (e.g., `with/path`).
+  pub fn field_location(&self, name: &str) -> Location {
+    let pointer = format!("{}/{}", self.source_pointer(), name);
+    match self.source.mark(pointer.as_str()) {
+      Some(mark) => self.locate(Some(mark)),
+      None => self.location(),
+    }
+  }
+
+  fn locate(&self, mark: Option<Mark>) -> Location {
+    Location {
+      file: self.file.as_ref().clone(),
+      mark,
+      excerpt: mark
+        .and_then(|mark| self.source.line(mark.line))
+        .map(|line| line.to_string()),
+      included_from: self.included_from.as_ref().clone(),
+    }
+  }
+}
+
+fn parse(origin: &Origin, format: Format, contents: &str) -> Result<Value, GenError> {
+  format.parse(contents).map_err(|err| GenError::Config {
+    location: Box::new(origin.locate(err.mark)),
+    message: format!("Invalid {}", format.name()),
+    cause: Some(err.cause),
+  })
+}
+
+/// Check the parts of `value` that `Decl` deserialization would otherwise
+/// reject without a location.
+fn validate(origin: &Origin, value: &Value) -> Result<(), GenError> {
+  let map = match value {
+    Value::Mapping(map) => map,
+    _ => return Err(GenError::config(origin.location(), "Expected a decl")),
+  };
+
+  let field = |name: &str| map.get(name);
+
+  let string_field = |name: &str| match field(name) {
+    None => Err(GenError::config(
+      origin.location(),
+      format!("Missing field `{}`", name),
+    )),
+    Some(Value::String(value)) => Ok(value.as_str()),
+    Some(_) => Err(GenError::config(
+      origin.field_location(name),
+      format!("Expected `{}` to be a string", name),
+    )),
+  };
+
+  let decl_type = match field("type") {
+    None => Err(GenError::config(
+      origin.location(),
+      "Missing field `type` (expected `generate` or `import`)",
+    )),
+    Some(Value::String(decl_type)) => Ok(decl_type.as_str()),
+    Some(_) => Err(GenError::config(
+      origin.field_location("type"),
+      "Expected `type` to be `generate` or `import`",
+    )),
+  }?;
+
+  match decl_type {
+    "generate" => {
+      // Ids become namespace components, which are separated by `/`
+      let id = string_field("id")?;
+      if id.is_empty() || id.contains('/') {
+        return Err(GenError::config(
+          origin.field_location("id"),
+          format!(
+            "Invalid id `{}` (ids must be non-empty and can't contain `/`)",
+            id
+          ),
+        ));
+      }
+
+      let using = string_field("using")?;
+      if let Err(err) = using.parse::<GeneratorSpec>() {
+        return Err(GenError::Config {
+          location: Box::new(origin.field_location("using")),
+          message: format!("Invalid generator `{}`", using),
+          cause: Some(Box::new(err)),
+        });
+      }
+
+      match field("with") {
+        None | Some(Value::Mapping(_)) => {}
+        Some(_) => {
+          return Err(GenError::config(
+            origin.field_location("with"),
+            "Expected `with` to be a mapping",
+          ))
+        }
+      }
+
+      // Overrides of the generated root page
+      let expect = |name: &str, valid: bool, expected: &str| {
+        if valid {
+          Ok(())
+        } else {
+          Err(GenError::config(
+            origin.field_location(name),
+            format!("Expected `{}` to be {}", name, expected),
+          ))
+        }
+      };
+      if let Some(order) = field("order") {
+        expect("order", matches!(order, Value::Integer(_)), "an integer")?;
+      }
+      for name in &["title", "icon"] {
+        if field(name).is_some() {
+          string_field(name)?;
+        }
+      }
+      if let Some(hidden) = field("hidden") {
+        expect(
+          "hidden",
+          matches!(hidden, Value::Bool(_)),
+          "`true` or `false`",
+        )?;
+      }
+      if let Some(metadata) = field("metadata") {
+        let valid = match metadata {
+          Value::Mapping(metadata) => metadata
+            .values()
+            .all(|value| matches!(value, Value::String(_))),
+          _ => false,
+        };
+        expect("metadata", valid, "a mapping of strings")?;
+      }
+
+      match field("children") {
+        None => {}
+        Some(Value::Sequence(children)) => {
+          for (i, child) in children.iter().enumerate() {
+            validate(&origin.child(i), child)?;
+          }
+        }
+        Some(_) => {
+          return Err(GenError::config(
+            origin.field_location("children"),
+            "Expected `children` to be a list of decls",
+          ))
+        }
+      }
+    }
+    "import" => {
+      string_field("path")?;
+
+      if field("integrity").is_some() {
+        string_field("integrity")?;
+      }
+
+      match field("optional") {
+        None | Some(Value::Bool(_)) => {}
+        Some(_) => {
+          return Err(GenError::config(
+            origin.field_location("optional"),
+            "Expected `optional` to be `true` or `false`",
+          ))
+        }
+      }
+    }
+    decl_type => {
+      return Err(GenError::config(
+        origin.field_location("type"),
+        format!(
+          "Unknown decl type `{}` (expected `generate` or `import`)",
+          decl_type
+        ),
+      ))
+    }
+  }
+
+  Ok(())
+}
+
+/// Preprocess `value`, the parsed contents of `origin`'s file, recording the
+/// scope at its imports in `origin`. Returns `None` if the root decl is
+/// excluded by its `when` condition.
+async fn preprocess(
+  origin: &mut Origin,
+  value: Value,
+  scope: Scope,
+) -> Result<Option<Value>, GenError> {
+  let preprocessed = preprocessor::preprocess(value, origin.options.clone(), scope)
+    .await
+    .map_err(|err| GenError::Config {
+      location: Box::new(origin.locate(origin.source.mark(err.pointer.as_str()))),
+      message: "Failed to preprocess configuration".to_string(),
+      cause: Some(Box::new(err.source)),
+    })?;
+
+  origin.imports = Arc::new(preprocessed.imports);
+  origin.pointers = Arc::new(preprocessed.pointers);
+  Ok(preprocessed.value)
+}
+
+fn deserialize(origin: &Origin, value: Value) -> Result<Decl, GenError> {
+  validate(origin, &value)?;
+
+  // Decls are deserialized through serde_yaml's value tree, which, unlike
+  // JSON's, can represent every value (e.g., NaN)
+  let value = serde_yaml::to_value(&value).map_err(|err| GenError::Config {
+    location: Box::new(origin.location()),
+    message: "Invalid decl".to_string(),
+    cause: Some(Box::new(err)),
+  })?;
+  serde_yaml::from_value(value).map_err(|err| GenError::Config {
+    location: Box::new(origin.location()),
+    message: "Invalid decl".to_string(),
+    cause: Some(Box::new(err)),
+  })
+}
+
+/// Load and preprocess the root configuration file at `path`.
+async fn load_root<P: AsRef<Path>>(
+  path: P,
+  options: &Arc<preprocessor::Options>,
+  resources: &UriCache,
+) -> Result<(Decl, Origin), GenError> {
+  let path = path.as_ref();
+  let contents = tokio::fs::read_to_string(path)
+    .await
+    .map_err(|err| GenError::io(path, err))?;
+
+  let format = Format::from_path(path);
+  let mut origin = Origin::root(
+    path.to_path_buf(),
+    format.source_map(contents.as_str()),
+    Vec::new(),
+    options.clone(),
+    resources.clone(),
+  );
+
+  let raw_config = parse(&origin, format, contents.as_str())?;
+  let config = preprocess(&mut origin, raw_config, Scope::default())
+    .await?
+    .ok_or_else(|| {
+      GenError::config(
+        origin.field_location("when"),
+        "The root decl is excluded by its `when` condition",
+      )
+    })?;
+
+  Ok((deserialize(&origin, config)?, origin))
+}
+
+/// The names of the configuration file looked for when no file is named,
+/// in order of preference.
+static DEFAULT_FILES: &[&str] = &["drydoc.yaml", "drydoc.toml", "drydoc.json"];
+
+/// The configuration file in `dir`: the first of `DEFAULT_FILES` that
+/// exists, or `drydoc.yaml` if none do.
+fn default_file(dir: &Path) -> PathBuf {
+  DEFAULT_FILES
+    .iter()
+    .map(|name| dir.join(name))
+    .find(|path| path.is_file())
+    .unwrap_or_else(|| dir.join(DEFAULT_FILES[0]))
+}
+
+/// The root configuration file to load when `--config` is `path`. If
+/// `path` is the default `drydoc.yaml` and doesn't exist, `drydoc.toml` or
+/// `drydoc.json` is used instead.
+pub fn find_root(path: &str) -> String {
+  if path != DEFAULT_FILES[0] {
+    return path.to_string();
+  }
+  default_file(Path::new("")).display().to_string()
+}
+
+/// Whether the import path `path` contains wildcards.
+pub fn is_glob(path: &str) -> bool {
+  path.contains(['*', '?', '['].as_ref())
+}
+
+/// The configuration files `import`, declared at `origin`, refers to, in
+/// the order they should be imported.
+fn import_paths(origin: &Origin, import: &Import) -> Result<Vec<PathBuf>, GenError> {
+  if !is_glob(import.path.as_str()) {
+    let mut path = origin.dir().join(&import.path);
+    if path.is_dir() {
+      path = default_file(&path);
+    }
+
+    if import.optional && !path.exists() {
+      return Ok(Vec::new());
+    }
+    return Ok(vec![path]);
+  }
+
+  // Only the imported path is a pattern, not the directory it's relative to
+  let dir = glob::Pattern::escape(origin.dir().to_string_lossy().as_ref());
+  let pattern = Path::new(dir.as_str()).join(&import.path);
+  let entries = glob::glob(pattern.to_string_lossy().as_ref()).map_err(|err| GenError::Config {
+    location: Box::new(origin.field_location("path")),
+    message: format!("Invalid pattern `{}`", import.path),
+    cause: Some(Box::new(err)),
+  })?;
+
+  let mut paths = Vec::new();
+  for entry in entries {
+    let mut path = entry.map_err(|err| {
+      let path = err.path().to_path_buf();
+      GenError::io(path, err)
+    })?;
+    // Matched directories without a configuration file aren't imported
+    if path.is_dir() {
+      path = default_file(&path);
+    }
+    if path.is_file() {
+      paths.push(path);
+    }
+  }
+
+  if paths.is_empty() && !import.optional {
+    return Err(GenError::config(
+      origin.field_location("path"),
+      format!(
+        "No configuration files match `{}` (use `optional: true` to allow this)",
+        import.path
+      ),
+    ));
+  }
+
+  paths.sort();
+  Ok(paths)
+}
+
+/// A configuration file referenced by an `import` decl.
+enum ImportFile {
+  Local(PathBuf),
+  Remote(Remote),
+}
+
+impl ImportFile {
+  /// The path the file is identified by in diagnostics.
+  fn path(&self) -> PathBuf {
+    match self {
+      Self::Local(path) => path.clone(),
+      Self::Remote(remote) => remote.to_path(),
+    }
+  }
+}
+
+/// The configuration files `import`, declared at `origin`, refers to. Paths
+/// in a file imported by URI are relative to that URI.
+fn import_files(origin: &Origin, import: &Import) -> Result<Vec<ImportFile>, GenError> {
+  let invalid = |message: String| GenError::config(origin.field_location("path"), message);
+  let remote = match Remote::parse(import.path.as_str(), origin.dir()).map_err(invalid)? {
+    Some(remote) => Some(remote),
+    None => match &origin.remote {
+      Some(base) => Some(base.join(import.path.as_str()).map_err(invalid)?),
+      None => None,
+    },
+  };
+
+  match remote {
+    Some(remote) => Ok(vec![ImportFile::Remote(remote)]),
+    None => Ok(
+      import_paths(origin, import)?
+        .into_iter()
+        .map(ImportFile::Local)
+        .collect(),
+    ),
+  }
+}
+
+/// The contents of `file`, imported by `import` declared at `origin`, or
+/// `None` if it's missing and the import is optional.
+async fn read_import_file(
+  origin: &Origin,
+  import: &Import,
+  file: &ImportFile,
+) -> Result<Option<String>, GenError> {
+  let unable = |err: Box<dyn std::error::Error + Send + Sync>| GenError::Config {
+    location: Box::new(origin.field_location("path")),
+    message: format!("Unable to import {}", file.path().display()),
+    cause: Some(err),
+  };
+  let check_integrity = |contents: &[u8]| match &import.integrity {
+    Some(integrity) => remote::check_integrity(contents, integrity.as_str())
+      .map_err(|message| GenError::config(origin.field_location("integrity"), message)),
+    None => Ok(()),
+  };
+
+  let remote = match file {
+    ImportFile::Local(path) => {
+      let contents = tokio::fs::read(path)
+        .await
+        .map_err(|err| unable(Box::new(err)))?;
+      check_integrity(&contents)?;
+      return String::from_utf8(contents)
+        .map(Some)
+        .map_err(|err| unable(Box::new(err)));
+    }
+    ImportFile::Remote(remote) => remote,
+  };
+
+  // Other files in an archive that was already fetched were checked along
+  // with it
+  let same_archive = origin
+    .remote
+    .as_ref()
+    .is_some_and(|base| base.member.is_some() && base.uri == remote.uri);
+  if remote.is_http() && import.integrity.is_none() && !same_archive {
+    return Err(GenError::config(
+      origin.field_location("path"),
+      format!(
+        "Importing {} requires an `integrity` hash (e.g., `integrity: sha256-...`)",
+        remote
+      ),
+    ));
+  }
+
+  let contents = match origin.resources.get(&remote.uri).await {
+    Ok(contents) => contents,
+    Err(GetError::Io(err)) if import.optional && err.kind() == std::io::ErrorKind::NotFound => {
+      return Ok(None)
+    }
+    Err(err) => return Err(unable(Box::new(err))),
+  };
+  check_integrity(&contents)?;
+
+  match remote.extract(&contents) {
+    Ok(Some(contents)) => Ok(Some(contents)),
+    Ok(None) if import.optional => Ok(None),
+    Ok(None) => Err(GenError::config(
+      origin.field_location("path"),
+      format!("{} doesn't contain `{}`", remote.uri, import.path),
+    )),
+    Err(message) => Err(GenError::config(origin.field_location("path"), message)),
+  }
+}
+
+/// Load and preprocess the configuration file `file`, imported by the
+/// `import` decl declared at `origin`.
+async fn load_import_file(
+  origin: &Origin,
+  import: &Import,
+  file: ImportFile,
+) -> Result<Option<(Decl, Origin)>, GenError> {
+  let contents = match read_import_file(origin, import, &file).await? {
+    Some(contents) => contents,
+    None => return Ok(None),
+  };
+
+  let mut site = origin.location();
+  let mut included_from = std::mem::take(&mut site.included_from);
+  included_from.insert(0, site);
+
+  // Configs imported by URI may come from anywhere, so only run the
+  // commands the user allowed
+  let options = match &file {
+    ImportFile::Remote(_) => origin.options.untrusted(),
+    ImportFile::Local(_) => origin.options.clone(),
+  };
+
+  let path = file.path();
+  let format = Format::from_path(&path);
+  let mut import_origin = Origin::root(
+    path,
+    format.source_map(contents.as_str()),
+    included_from,
+    options,
+    origin.resources.clone(),
+  );
+  if let ImportFile::Remote(remote) = file {
+    import_origin.remote = Some(Arc::new(remote));
+  }
+
+  // The imported file inherits the variables and templates in scope at the
+  // import
+  let scope = origin
+    .imports
+    .get(origin.source_pointer())
+    .cloned()
+    .unwrap_or_default();
+  let config = parse(&import_origin, format, contents.as_str())?;
+  match preprocess(&mut import_origin, config, scope).await? {
+    Some(config) => Ok(Some((deserialize(&import_origin, config)?, import_origin))),
+    None => Ok(None),
+  }
+}
+
+/// Fail if importing `path` from the `import` decl declared at `origin`
+/// would import a file that is already being imported.
+fn check_cycle(origin: &Origin, path: &Path) -> Result<(), GenError> {
+  let canonical = |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  let target = canonical(path);
+
+  // The files through which `origin` was reached, outermost first
+  let mut chain: Vec<&Path> = origin
+    .included_from
+    .iter()
+    .rev()
+    .map(|site| site.file.as_path())
+    .collect();
+  chain.push(origin.file.as_path());
+
+  let start = match chain.iter().position(|file| canonical(file) == target) {
+    Some(start) => start,
+    None => return Ok(()),
+  };
+
+  let cycle: Vec<String> = chain[start..]
+    .iter()
+    .copied()
+    .chain(std::iter::once(path))
+    .map(|file| file.display().to_string())
+    .collect();
+  Err(GenError::config(
+    origin.field_location("path"),
+    format!("Import cycle: {}", cycle.join(" -> ")),
+  ))
+}
+
+/// Load and preprocess the configuration files referenced by `import`,
+/// which was declared at `origin`. Files matched by a glob are loaded in
+/// order of their paths.
+async fn load_import(origin: &Origin, import: &Import) -> Result<Vec<(Decl, Origin)>, GenError> {
+  let mut imported = Vec::new();
+  for file in import_files(origin, import)? {
+    check_cycle(origin, &file.path())?;
+    match load_import_file(origin, import, file).await? {
+      Some(decl) => imported.push(decl),
+      // Missing and optional, or excluded by its `when` condition
+      None => continue,
+    }
+  }
+
+  Ok(imported)
+}
+
+/// Rename the repeated ids in `ids`, the ids of the decls imported by a
+/// single import: the second `id` becomes `id_2`, the third `id_3` and so on,
+/// skipping names in `taken` (the ids of every sibling). The new names are
+/// added to `taken`.
+///
+/// The first of each id is kept even if a sibling declared elsewhere uses it,
+/// so that the conflict is reported rather than hidden.
+fn dedup_ids(ids: Vec<String>, taken: &mut HashSet<String>) -> Vec<String> {
+  let mut counts: HashMap<String, usize> = HashMap::new();
+  ids
+    .into_iter()
+    .map(|id| {
+      let count = counts.entry(id.clone()).or_insert(0);
+      *count += 1;
+      if *count == 1 {
+        return id;
+      }
+
+      let mut renamed = format!("{}_{}", id, count);
+      while taken.contains(&renamed) {
+        *count += 1;
+        renamed = format!("{}_{}", id, count);
+      }
+      taken.insert(renamed.clone());
+      renamed
+    })
+    .collect()
+}
+
+/// The `generate` decls `nodes` resolve to (following imports).
+fn generated_mut<'a>(nodes: &'a mut [Node], decls: &mut Vec<&'a mut Generate>) {
+  for node in nodes {
+    match node {
+      Node::Generate { generate, .. } => decls.push(generate),
+      Node::Import { imported, .. } => generated_mut(imported, decls),
+    }
+  }
+}
+
+/// Rename the decls that an import among `children` imported more than once
+/// under the same id (see `dedup_ids`).
+fn rename_duplicates(children: &mut [Node]) {
+  let mut decls = Vec::new();
+  generated_mut(children, &mut decls);
+  let mut taken: HashSet<String> = decls.iter().map(|decl| decl.id.clone()).collect();
+
+  for child in children {
+    if let Node::Import { imported, .. } = child {
+      let mut decls = Vec::new();
+      generated_mut(imported, &mut decls);
+
+      let ids = dedup_ids(
+        decls.iter().map(|decl| decl.id.clone()).collect(),
+        &mut taken,
+      );
+      for (decl, id) in decls.into_iter().zip(ids) {
+        decl.id = id;
+      }
+    }
+  }
+}
+
+/// A decl whose imports have been loaded, so that the tree can be validated,
+/// generated and watched without loading (and preprocessing) any file
+/// twice.
+pub enum Node {
+  Generate {
+    /// The decl, without its children
+    generate: Box<Generate>,
+    origin: Origin,
+    children: Vec<Node>,
+  },
+  Import {
+    import: Import,
+    origin: Origin,
+    /// The root decls of the imported files
+    imported: Vec<Node>,
+  },
+}
+
+impl Node {
+  /// Where the decl was declared.
+  pub fn origin(&self) -> &Origin {
+    match self {
+      Self::Generate { origin, .. } | Self::Import { origin, .. } => origin,
+    }
+  }
+}
+
+/// Load every import in the tree rooted at `decl`, declared at `origin`.
+/// Imports that fail to load are left empty, and their errors returned
+/// alongside the tree so that all of them can be reported at once.
+fn load_node(decl: Decl, origin: Origin) -> Pin<Box<dyn Future<Output = (Node, Vec<GenError>)>>> {
+  Box::pin(async move {
+    let mut errors = Vec::new();
+    let node = match decl {
+      Decl::Import(import) => {
+        let imported = match load_import(&origin, &import).await {
+          Ok(imported) => imported,
+          Err(err) => {
+            errors.push(err);
+            Vec::new()
+          }
+        };
+
+        let loaded = join_all(
+          imported
+            .into_iter()
+            .map(|(decl, import_origin)| load_node(decl, import_origin)),
+        )
+        .await;
+        let mut imported = Vec::new();
+        for (node, node_errors) in loaded {
+          imported.push(node);
+          errors.extend(node_errors);
+        }
+
+        Node::Import {
+          import,
+          origin,
+          imported,
+        }
+      }
+      Decl::Generate(mut generate) => {
+        let children = generate.children.take().unwrap_or_default();
+        let loaded = join_all(
+          children
+            .into_iter()
+            .enumerate()
+            .map(|(i, child)| load_node(child, origin.child(i))),
+        )
+        .await;
+        let mut children = Vec::new();
+        for (node, node_errors) in loaded {
+          children.push(node);
+          errors.extend(node_errors);
+        }
+        rename_duplicates(&mut children);
+
+        Node::Generate {
+          generate,
+          origin,
+          children,
+        }
+      }
+    };
+
+    (node, errors)
+  })
+}
+
+/// Load and preprocess the root configuration file at `path` and every file
+/// it imports. Failures to load imported files are returned alongside the
+/// tree, rather than failing the whole load.
+pub async fn load_tree<P: AsRef<Path>>(
+  path: P,
+  options: &Arc<preprocessor::Options>,
+  resources: &UriCache,
+) -> Result<(Node, Vec<GenError>), GenError> {
+  let (decl, origin) = load_root(path, options, resources).await?;
+  Ok(load_node(decl, origin).await)
+}
+
+/// The directory in which new files matching `import`, declared at
+/// `origin`, may appear.
+pub fn import_dir(origin: &Origin, import: &Import) -> PathBuf {
+  // Everything up to the first component containing a wildcard
+  let literal = Path::new(import.path.as_str())
+    .components()
+    .take_while(|component| !is_glob(component.as_os_str().to_string_lossy().as_ref()))
+    .collect::<PathBuf>();
+
+  let dir = origin.dir().join(literal);
+  if is_glob(import.path.as_str()) || dir.is_dir() {
+    dir
+  } else {
+    dir.parent().map(Path::to_path_buf).unwrap_or(dir)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ids(ids: &[&str]) -> Vec<String> {
+    ids.iter().map(|id| id.to_string()).collect()
+  }
+
+  fn dedup(imported: &[&str], siblings: &[&str]) -> Vec<String> {
+    let mut taken: HashSet<String> = ids(siblings).into_iter().collect();
+    taken.extend(ids(imported));
+    dedup_ids(ids(imported), &mut taken)
+  }
+
+  #[test]
+  fn keeps_unique_ids() {
+    assert_eq!(dedup(&["a", "b"], &[]), ids(&["a", "b"]));
+    assert_eq!(dedup(&[], &["a"]), ids(&[]));
+  }
+
+  #[test]
+  fn renames_repeated_ids() {
+    assert_eq!(
+      dedup(&["a", "b", "a", "a", "b"], &[]),
+      ids(&["a", "b", "a_2", "a_3", "b_2"])
+    );
+  }
+
+  #[test]
+  fn skips_ids_used_by_siblings() {
+    assert_eq!(dedup(&["a", "a"], &["a_2", "a_3"]), ids(&["a", "a_4"]));
+    // Including those imported by the same import
+    assert_eq!(dedup(&["a", "a_2", "a"], &[]), ids(&["a", "a_2", "a_3"]));
+    assert_eq!(dedup(&["a", "a", "a_2"], &[]), ids(&["a", "a_3", "a_2"]));
+    // And those renamed before them
+    assert_eq!(
+      dedup(&["a", "a", "a_2", "a_2"], &[]),
+      ids(&["a", "a_3", "a_2", "a_2_2"])
+    );
+  }
+
+  #[test]
+  fn keeps_ids_declared_elsewhere() {
+    // Reported as duplicates when validating
+    assert_eq!(dedup(&["a"], &["a"]), ids(&["a"]));
+  }
+
+  /// Options that allow running any program.
+  fn options() -> Arc<preprocessor::Options> {
+    Arc::new(preprocessor::Options {
+      conditions: Default::default(),
+      working_dir: std::env::temp_dir(),
+      timeout: std::time::Duration::from_secs(1),
+      allowed: None,
+    })
+  }
+
+  /// The origin of the root decl of `file`, reached through the files
+  /// `included_from`, outermost first.
+  fn origin(file: &Path, included_from: &[&Path]) -> Origin {
+    Origin::root(
+      file.to_path_buf(),
+      SourceMap::default(),
+      included_from.iter().rev().map(Location::file).collect(),
+      options(),
+      UriCache::default(),
+    )
+  }
+
+  fn cycle(origin: &Origin, path: &Path) -> Option<String> {
+    match check_cycle(origin, path) {
+      Ok(()) => None,
+      Err(GenError::Config { message, .. }) => Some(message),
+      Err(err) => panic!("Unexpected error {}", err),
+    }
+  }
+
+  #[test]
+  fn allows_new_files() {
+    let origin = origin(Path::new("b.yaml"), &[Path::new("a.yaml")]);
+    assert_eq!(cycle(&origin, Path::new("c.yaml")), None);
+    // The same file may be imported along different paths
+    assert_eq!(cycle(&origin.child(0), Path::new("c.yaml")), None);
+  }
+
+  #[test]
+  fn reports_cycles() {
+    let origin = origin(
+      Path::new("c.yaml"),
+      &[Path::new("a.yaml"), Path::new("b.yaml")],
+    );
+
+    assert_eq!(
+      cycle(&origin, Path::new("a.yaml")).as_deref(),
+      Some("Import cycle: a.yaml -> b.yaml -> c.yaml -> a.yaml")
+    );
+    assert_eq!(
+      cycle(&origin, Path::new("b.yaml")).as_deref(),
+      Some("Import cycle: b.yaml -> c.yaml -> b.yaml")
+    );
+    assert_eq!(
+      cycle(&origin.child(1), Path::new("c.yaml")).as_deref(),
+      Some("Import cycle: c.yaml -> c.yaml")
+    );
+  }
+
+  #[test]
+  fn compares_canonical_paths() {
+    let dir = std::env::temp_dir().join(format!("drydoc-cycle-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("drydoc.yaml"), "").unwrap();
+
+    let origin = origin(&dir.join("sub/api.yaml"), &[&dir.join("drydoc.yaml")]);
+    let message = cycle(&origin, &dir.join("sub/../drydoc.yaml"));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(message.unwrap().ends_with("sub/../drydoc.yaml"));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn denies_commands_in_remote_imports() {
+    let dir = std::env::temp_dir().join(format!("drydoc-untrusted-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+      dir.join("drydoc.yaml"),
+      "type: generate\nid: root\nusing: copy\nwith: {}\nchildren:\n  - type: import\n    path: local.yaml\n  - type: import\n    path: file://remote.yaml\n",
+    )
+    .unwrap();
+    let imported = "type: generate\nid: $(echo imported)\nusing: copy\nwith: {}\n";
+    std::fs::write(dir.join("local.yaml"), imported).unwrap();
+    std::fs::write(dir.join("remote.yaml"), imported).unwrap();
+
+    let loaded = load_tree(dir.join("drydoc.yaml"), &options(), &UriCache::default()).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (root, errors) = loaded.unwrap();
+    let mut ids = Vec::new();
+    if let Node::Generate { children, .. } = &root {
+      generated(children, &mut ids);
+    }
+    assert_eq!(ids, vec!["imported"]);
+
+    assert_eq!(errors.len(), 1);
+    let cause = std::error::Error::source(&errors[0]).unwrap();
+    assert_eq!(
+      cause.to_string(),
+      "`echo` is not an allowed command (see --allow-command)"
+    );
+  }
+
+  fn generated(nodes: &[Node], ids: &mut Vec<String>) {
+    for node in nodes {
+      match node {
+        Node::Import { imported, .. } => generated(imported, ids),
+        Node::Generate { generate, .. } => ids.push(generate.id.clone()),
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_ids() {
+    let origin = origin(Path::new("drydoc.yaml"), &[]);
+    let validate_id = |id: &str| {
+      let text = format!("type: generate\nid: '{}'\nusing: copy\nwith: {{}}\n", id);
+      validate(&origin, &Format::Yaml.parse(text.as_str()).unwrap()).map_err(|err| err.to_string())
+    };
+
+    assert!(validate_id("api").is_ok());
+    assert!(validate_id("api.v2").is_ok());
+    assert!(validate_id("").unwrap_err().starts_with("Invalid id ``"));
+    assert!(validate_id("api/v2")
+      .unwrap_err()
+      .starts_with("Invalid id `api/v2`"));
+  }
+
+  #[test]
+  fn defaults_with_to_no_parameters() {
+    let origin = origin(Path::new("drydoc.yaml"), &[]);
+    let value = Format::Yaml
+      .parse("type: generate\nid: api\nusing: copy\n")
+      .unwrap();
+
+    match deserialize(&origin, value) {
+      Ok(Decl::Generate(generate)) => assert!(generate.with.is_empty()),
+      _ => panic!("Expected a generate decl"),
+    }
+  }
+}
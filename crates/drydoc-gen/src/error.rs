@@ -0,0 +1,160 @@
+//! Errors reported by `drydoc gen`.
+
+use std::{
+  error::Error,
+  fmt::{Display, Formatter},
+  path::{Path, PathBuf},
+};
+
+use crate::source::Mark;
+
+/// A position within a configuration file.
+#[derive(Debug, Clone)]
+pub struct Location {
+  pub file: PathBuf,
+  /// The position within `file`, if known
+  pub mark: Option<Mark>,
+  /// The line of `file` that `mark` points into
+  pub excerpt: Option<String>,
+  /// The `import` decls through which `file` was reached, innermost first
+  pub included_from: Vec<Location>,
+}
+
+impl Location {
+  pub fn file<P: AsRef<Path>>(file: P) -> Self {
+    Self {
+      file: file.as_ref().to_path_buf(),
+      mark: None,
+      excerpt: None,
+      included_from: Vec::new(),
+    }
+  }
+}
+
+impl Display for Location {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self.mark {
+      Some(Mark { line, col }) => write!(f, "{}:{}:{}", self.file.display(), line, col),
+      None => write!(f, "{}", self.file.display()),
+    }
+  }
+}
+
+/// Identifies the decl an error occurred in.
+#[derive(Debug, Clone)]
+pub struct DeclInfo {
+  /// The decl's `id`
+  pub id: String,
+  /// The path of the decl in the decl tree (e.g., `root/readme/api_docs`)
+  pub path: String,
+  /// Where the decl was declared
+  pub location: Location,
+}
+
+#[derive(Debug)]
+pub enum GenError {
+  /// The configuration is invalid.
+  Config {
+    location: Box<Location>,
+    message: String,
+    cause: Option<Box<dyn Error>>,
+  },
+  /// A generator couldn't be started, or failed to generate a decl.
+  Generator {
+    decl: Box<DeclInfo>,
+    generator: String,
+    cause: Box<dyn Error>,
+  },
+  /// Reading an input or writing the output failed.
+  Io {
+    path: Option<PathBuf>,
+    cause: Box<dyn Error>,
+  },
+  /// Several problems were found at once.
+  Many(Vec<GenError>),
+}
+
+impl GenError {
+  pub fn config<M: Into<String>>(location: Location, message: M) -> Self {
+    Self::Config {
+      location: Box::new(location),
+      message: message.into(),
+      cause: None,
+    }
+  }
+
+  pub fn io<P: AsRef<Path>, E: Into<Box<dyn Error>>>(path: P, cause: E) -> Self {
+    Self::Io {
+      path: Some(path.as_ref().to_path_buf()),
+      cause: cause.into(),
+    }
+  }
+
+  /// Fail with `errors`, if there are any.
+  pub fn all(mut errors: Vec<GenError>) -> Result<(), GenError> {
+    match errors.len() {
+      0 => Ok(()),
+      1 => Err(errors.remove(0)),
+      _ => Err(Self::Many(errors)),
+    }
+  }
+
+  /// Where in the configuration the error occurred, if applicable.
+  pub fn location(&self) -> Option<&Location> {
+    match self {
+      Self::Config { location, .. } => Some(location),
+      Self::Generator { decl, .. } => Some(&decl.location),
+      Self::Io { .. } | Self::Many(_) => None,
+    }
+  }
+
+  /// The process exit code to report this error with.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Self::Config { .. } => 2,
+      Self::Generator { .. } => 3,
+      Self::Io { .. } => 4,
+      Self::Many(errors) => errors.first().map_or(2, GenError::exit_code),
+    }
+  }
+}
+
+impl Display for GenError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Config { message, .. } => write!(f, "{}", message),
+      Self::Generator {
+        decl, generator, ..
+      } => write!(
+        f,
+        "Generator `{}` failed to generate decl `{}` ({})",
+        generator, decl.id, decl.path
+      ),
+      Self::Io {
+        path: Some(path), ..
+      } => write!(f, "Unable to access {}", path.display()),
+      Self::Io { path: None, .. } => write!(f, "I/O error"),
+      Self::Many(errors) => write!(f, "Found {} problems", errors.len()),
+    }
+  }
+}
+
+impl Error for GenError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      Self::Config { cause, .. } => cause.as_deref(),
+      Self::Generator { cause, .. } => Some(cause.as_ref()),
+      Self::Io { cause, .. } => Some(cause.as_ref()),
+      Self::Many(_) => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for GenError {
+  fn from(value: std::io::Error) -> Self {
+    Self::Io {
+      path: None,
+      cause: Box::new(value),
+    }
+  }
+}
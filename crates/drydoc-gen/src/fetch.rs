@@ -1,19 +1,40 @@
 use url::Url;
 
+use std::io::{Error, ErrorKind};
 use tokio::io::AsyncReadExt;
 
+/// Convert an HTTP client error into an I/O error.
+fn http_error(err: reqwest::Error) -> Error {
+  Error::other(err)
+}
+
 pub async fn fetch(uri: &Url) -> Result<Box<[u8]>, tokio::io::Error> {
   match uri.scheme() {
     "" | "file" => {
-      let mut path_str = uri.path().to_string();
-      if cfg!(windows) {
-        path_str = path_str.replace('/', "\\").split_off(1);
-      }
-      let mut file = tokio::fs::File::open(path_str.as_str()).await?;
+      let path = uri
+        .to_file_path()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid file URI"))?;
+      let mut file = tokio::fs::File::open(path).await?;
       let mut contents = Vec::new();
       file.read_to_end(&mut contents).await?;
       Ok(contents.into_boxed_slice())
     }
+    "http" | "https" => {
+      let res = reqwest::get(uri.clone()).await.map_err(http_error)?;
+      let status = res.status();
+      if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(Error::new(
+          ErrorKind::NotFound,
+          format!("{} not found", uri),
+        ));
+      }
+      if !status.is_success() {
+        return Err(Error::other(format!("{} responded with {}", uri, status)));
+      }
+
+      let contents = res.bytes().await.map_err(http_error)?;
+      Ok(contents.to_vec().into_boxed_slice())
+    }
     _ => Err(tokio::io::Error::new(
       tokio::io::ErrorKind::InvalidInput,
       "URI scheme not recognized",
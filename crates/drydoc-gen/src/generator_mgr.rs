@@ -1,69 +1,207 @@
-use drydoc_pkg_manager::{Manager as PkgMgr, UrlFetcher, VersionReq};
+use drydoc_model::decl::GeneratorSpec;
+use drydoc_pkg_manager::{Artifact, Manager as PkgMgr, Parameters, UrlFetcher, Version};
 
 use tokio::sync::oneshot::{channel, Sender};
 
-use std::{collections::HashMap, error::Error, path::PathBuf};
+use std::{collections::HashMap, path::PathBuf};
+
+use derive_more::{Display, Error};
 
 use crate::{
   actor::{Actor, Addr, Receiver},
   ipc::IpcMsg,
 };
 
+#[derive(Display, Debug, Error)]
+pub enum StartError {
+  /// The spec names a repository that wasn't configured.
+  #[display(
+    fmt = "Unknown repository `{}` (add it with --repository {}=<url>)",
+    name,
+    name
+  )]
+  UnknownRepository { name: String },
+  /// The generator package couldn't be resolved or downloaded.
+  #[display(fmt = "Unable to fetch generator: {}", message)]
+  Resolve { message: String },
+  /// The package exists, but isn't a generator.
+  #[display(fmt = "{} is not a generator", "path.display()")]
+  NotAGenerator { path: PathBuf },
+  /// The generator's process couldn't be started.
+  #[display(fmt = "Unable to start generator: {}", source)]
+  Spawn { source: std::io::Error },
+  /// The generator started, but the handshake with it failed (e.g., it
+  /// speaks an incompatible version of the IPC protocol).
+  #[display(fmt = "Unable to initialize generator: {}", source)]
+  Initialize { source: crate::ipc::Error },
+  /// The generator manager has shut down.
+  #[display(fmt = "The generator manager has stopped")]
+  Stopped,
+}
+
 pub enum GeneratorMgrMsg {
   GetOrStart {
-    name: String,
-    version_req: VersionReq,
-    res: Sender<Result<Addr<IpcMsg>, ()>>,
+    spec: GeneratorSpec,
+    res: Sender<Result<Addr<IpcMsg>, StartError>>,
+  },
+  /// Look up the parameters a generator accepts without starting it.
+  GetParameters {
+    spec: GeneratorSpec,
+    res: Sender<Result<Option<Parameters>, StartError>>,
   },
+  /// Identify the exact generator a spec resolves to without starting it.
+  Identify {
+    spec: GeneratorSpec,
+    res: Sender<Result<String, StartError>>,
+  },
+}
+
+/// A generator a spec was resolved to.
+struct Resolved {
+  path: PathBuf,
+  /// The version of the package, if the generator isn't local
+  version: Option<Version>,
+  artifact: Artifact,
 }
 
 pub struct GeneratorMgr {
+  /// The repository used for specs without a repository name
   pkg_mgr: PkgMgr<UrlFetcher>,
+  /// Additional repositories, by name
+  repositories: HashMap<String, PkgMgr<UrlFetcher>>,
   generators: HashMap<PathBuf, Addr<IpcMsg>>,
+  /// The directory generators may open files in
+  root: PathBuf,
 }
 
 impl GeneratorMgr {
-  pub fn new(pkg_mgr: PkgMgr<UrlFetcher>) -> Self {
+  pub fn new(
+    pkg_mgr: PkgMgr<UrlFetcher>,
+    repositories: HashMap<String, PkgMgr<UrlFetcher>>,
+    root: PathBuf,
+  ) -> Self {
     Self {
       pkg_mgr,
+      repositories,
       generators: HashMap::new(),
+      root,
+    }
+  }
+
+  /// Find the generator `spec` refers to, downloading it if necessary.
+  async fn resolve(&mut self, spec: &GeneratorSpec) -> Result<Resolved, StartError> {
+    match spec {
+      GeneratorSpec::Package {
+        repository,
+        name,
+        version_req,
+      } => {
+        let pkg_mgr =
+          match repository {
+            None => &mut self.pkg_mgr,
+            Some(repository) => self.repositories.get_mut(repository).ok_or_else(|| {
+              StartError::UnknownRepository {
+                name: repository.clone(),
+              }
+            })?,
+          };
+
+        let (path, version, artifact) =
+          pkg_mgr
+            .get(name, version_req)
+            .await
+            .map_err(|err| StartError::Resolve {
+              message: err.to_string(),
+            })?;
+
+        Ok(Resolved {
+          path,
+          version: Some(version),
+          artifact,
+        })
+      }
+      GeneratorSpec::Path(path) => {
+        let artifact_path = path.join("artifact.json");
+        let artifact = tokio::fs::read_to_string(&artifact_path)
+          .await
+          .map_err(|err| StartError::Resolve {
+            message: format!("Unable to read {} ({})", artifact_path.display(), err),
+          })?;
+
+        let artifact =
+          serde_json::from_str(artifact.as_str()).map_err(|err| StartError::Resolve {
+            message: format!("Invalid {} ({})", artifact_path.display(), err),
+          })?;
+
+        Ok(Resolved {
+          path: path.clone(),
+          version: None,
+          artifact,
+        })
+      }
     }
   }
 
-  async fn get_or_start(
+  async fn get_parameters(
     &mut self,
-    name: &str,
-    version_req: &VersionReq,
-  ) -> Result<Addr<IpcMsg>, Box<dyn Error>> {
-    let (path, _, artifact) = self.pkg_mgr.get(name, &version_req).await?;
+    spec: &GeneratorSpec,
+  ) -> Result<Option<Parameters>, StartError> {
+    let Resolved { path, artifact, .. } = self.resolve(spec).await?;
+    match artifact.as_generator() {
+      Some(gen) => Ok(gen.parameters.clone()),
+      None => Err(StartError::NotAGenerator { path }),
+    }
+  }
+
+  /// The package name and version `spec` resolves to (e.g.,
+  /// `acme/clang@1.2.3`), or the path of a local generator.
+  async fn identify(&mut self, spec: &GeneratorSpec) -> Result<String, StartError> {
+    match (spec, self.resolve(spec).await?.version) {
+      (
+        GeneratorSpec::Package {
+          repository, name, ..
+        },
+        Some(version),
+      ) => Ok(match repository {
+        Some(repository) => format!("{}/{}@{}", repository, name, version),
+        None => format!("{}@{}", name, version),
+      }),
+      _ => Ok(spec.to_string()),
+    }
+  }
+
+  async fn get_or_start(&mut self, spec: &GeneratorSpec) -> Result<Addr<IpcMsg>, StartError> {
+    let Resolved { path, artifact, .. } = self.resolve(spec).await?;
 
     if let Some(addr) = self.generators.get(&path) {
       Ok(addr.clone())
+    } else if let Some(gen) = artifact.as_generator() {
+      let addr = crate::ipc::start_generator(&path, gen, self.root.clone())
+        .await
+        .map_err(|source| StartError::Spawn { source })?;
+      addr
+        .init()
+        .await
+        .map_err(|source| StartError::Initialize { source })?;
+      self.generators.insert(path, addr.clone());
+      Ok(addr)
     } else {
-      if let Some(gen) = artifact.as_generator() {
-        let addr = crate::ipc::start_generator(&path, gen).await?;
-        self.generators.insert(path, addr.clone());
-        Ok(addr)
-      } else {
-        panic!("{:?} is not a generator", path);
-      }
+      Err(StartError::NotAGenerator { path })
     }
   }
 
   async fn run(mut self, mut rx: Receiver<GeneratorMgrMsg>) {
     while let Some(msg) = rx.recv().await {
       match msg {
-        GeneratorMgrMsg::GetOrStart {
-          name,
-          version_req,
-          res,
-        } => {
-          res.send(
-            self
-              .get_or_start(name.as_str(), &version_req)
-              .await
-              .map_err(|_| ()),
-          );
+        GeneratorMgrMsg::GetOrStart { spec, res } => {
+          // The requester may have given up waiting, which is fine
+          let _ = res.send(self.get_or_start(&spec).await);
+        }
+        GeneratorMgrMsg::GetParameters { spec, res } => {
+          let _ = res.send(self.get_parameters(&spec).await);
+        }
+        GeneratorMgrMsg::Identify { spec, res } => {
+          let _ = res.send(self.identify(&spec).await);
         }
       }
     }
@@ -81,18 +219,32 @@ impl Actor for GeneratorMgr {
 }
 
 impl Addr<GeneratorMgrMsg> {
-  pub async fn get_or_start<N: Into<String>>(
+  pub async fn get_or_start(&self, spec: GeneratorSpec) -> Result<Addr<IpcMsg>, StartError> {
+    let (tx, rx) = channel();
+    self
+      .send(GeneratorMgrMsg::GetOrStart { spec, res: tx })
+      .map_err(|_| StartError::Stopped)?;
+
+    rx.await.map_err(|_| StartError::Stopped)?
+  }
+  pub async fn get_parameters(
     &self,
-    name: N,
-    version_req: VersionReq,
-  ) -> Result<Addr<IpcMsg>, ()> {
+    spec: GeneratorSpec,
+  ) -> Result<Option<Parameters>, StartError> {
+    let (tx, rx) = channel();
+    self
+      .send(GeneratorMgrMsg::GetParameters { spec, res: tx })
+      .map_err(|_| StartError::Stopped)?;
+
+    rx.await.map_err(|_| StartError::Stopped)?
+  }
+
+  pub async fn identify(&self, spec: GeneratorSpec) -> Result<String, StartError> {
     let (tx, rx) = channel();
-    self.send(GeneratorMgrMsg::GetOrStart {
-      name: name.into(),
-      version_req,
-      res: tx,
-    });
+    self
+      .send(GeneratorMgrMsg::Identify { spec, res: tx })
+      .map_err(|_| StartError::Stopped)?;
 
-    rx.await.unwrap()
+    rx.await.map_err(|_| StartError::Stopped)?
   }
 }
@@ -0,0 +1,191 @@
+//! The graph of decls and the configuration files they're declared in,
+//! printed by `--print-graph` for debugging large hierarchies.
+
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+
+use drydoc_model::ns::Namespace;
+use serde::Serialize;
+
+use crate::{
+  config::{self, Node},
+  error::GenError,
+  GenCtx, GenOpts,
+};
+
+/// How to print the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+  /// Graphviz DOT
+  Dot,
+  Json,
+}
+
+impl FromStr for GraphFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "dot" => Ok(Self::Dot),
+      "json" => Ok(Self::Json),
+      _ => Err(format!(
+        "Unknown graph format `{}` (expected `dot` or `json`)",
+        s
+      )),
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum GraphNode {
+  /// A configuration file
+  File { path: String },
+  /// A `generate` decl
+  Decl {
+    id: String,
+    /// The decl's path in the decl tree
+    path: String,
+    generator: String,
+    location: String,
+  },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EdgeKind {
+  /// From a decl to one of its children, or from a file to its root decl
+  Child,
+  /// From a decl to a file imported by one of its children
+  Import,
+}
+
+#[derive(Debug, Serialize)]
+struct Edge {
+  /// Index of the source node
+  from: usize,
+  /// Index of the target node
+  to: usize,
+  kind: EdgeKind,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Graph {
+  nodes: Vec<GraphNode>,
+  edges: Vec<Edge>,
+  #[serde(skip)]
+  files: HashMap<PathBuf, usize>,
+}
+
+impl Graph {
+  /// The node of the file `path`, added if necessary.
+  fn file(&mut self, path: &PathBuf) -> usize {
+    if let Some(node) = self.files.get(path) {
+      return *node;
+    }
+
+    self.nodes.push(GraphNode::File {
+      path: path.display().to_string(),
+    });
+    self.files.insert(path.clone(), self.nodes.len() - 1);
+    self.nodes.len() - 1
+  }
+
+  /// Add `node` and its descendants, with an edge of kind `kind` from
+  /// `parent`.
+  fn visit(&mut self, node: &Node, namespace: Arc<Namespace>, parent: usize, kind: EdgeKind) {
+    match node {
+      Node::Import { imported, .. } => {
+        for node in imported {
+          let file = self.file(&node.origin().file);
+          self.edges.push(Edge {
+            from: parent,
+            to: file,
+            kind: EdgeKind::Import,
+          });
+          self.visit(node, namespace.clone(), file, EdgeKind::Child);
+        }
+      }
+      Node::Generate {
+        generate,
+        origin,
+        children,
+      } => {
+        let child_ns = namespace.child(generate.id.as_str());
+        self.nodes.push(GraphNode::Decl {
+          id: generate.id.clone(),
+          path: child_ns.to_string(),
+          generator: generate.using.to_string(),
+          location: origin.location().to_string(),
+        });
+        let node = self.nodes.len() - 1;
+        self.edges.push(Edge {
+          from: parent,
+          to: node,
+          kind,
+        });
+
+        for child in children {
+          self.visit(child, child_ns.clone(), node, EdgeKind::Child);
+        }
+      }
+    }
+  }
+
+  fn to_dot(&self) -> String {
+    let quote = |s: &str| {
+      let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+      format!("\"{}\"", escaped)
+    };
+
+    let mut dot = String::from("digraph drydoc {\n");
+    for (i, node) in self.nodes.iter().enumerate() {
+      let (shape, label) = match node {
+        GraphNode::File { path } => ("note", path.clone()),
+        GraphNode::Decl {
+          path, generator, ..
+        } => ("box", format!("{}\nusing {}", path, generator)),
+      };
+      dot.push_str(&format!(
+        "  n{} [shape={}, label={}];\n",
+        i,
+        shape,
+        quote(label.as_str())
+      ));
+    }
+
+    for edge in &self.edges {
+      let style = match edge.kind {
+        EdgeKind::Child => "",
+        EdgeKind::Import => " [style=dashed, label=\"import\"]",
+      };
+      dot.push_str(&format!("  n{} -> n{}{};\n", edge.from, edge.to, style));
+    }
+
+    dot.push_str("}\n");
+    dot
+  }
+}
+
+/// Print the graph of the configuration at `opts.config` to stdout in
+/// `format`, without generating anything.
+pub async fn print(opts: &GenOpts, format: GraphFormat, ctx: &GenCtx) -> Result<(), GenError> {
+  let (root, errors) = config::load_tree(&opts.config, &ctx.preprocess, &ctx.resources).await?;
+  GenError::all(errors)?;
+
+  let mut graph = Graph::default();
+  let file = graph.file(&root.origin().file);
+  graph.visit(&root, Namespace::new("root"), file, EdgeKind::Child);
+
+  match format {
+    GraphFormat::Dot => print!("{}", graph.to_dot()),
+    GraphFormat::Json => println!(
+      "{}",
+      serde_json::to_string_pretty(&graph).expect("Graphs are valid JSON")
+    ),
+  }
+
+  Ok(())
+}
@@ -1,6 +1,7 @@
 /// IPC communication with external documentation generators
 use std::{
   collections::HashMap,
+  io::SeekFrom,
   iter::FromIterator,
   net::{Ipv4Addr, SocketAddrV4},
   path::PathBuf,
@@ -12,16 +13,21 @@ use crate::actor::{
   Actor, Addr, Receiver, WeakAddr,
 };
 use client::RequestData;
-use drydoc_model::{client, ns::Namespace, server, Encoding, Message};
-use log::error;
+use drydoc_model::{
+  client, decl::Params, fs::LinkedFileHandle, ns::Namespace, server, Encoding, Message,
+};
+use log::{debug, error};
 use tokio::{
-  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  fs::File,
+  io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite},
   net::TcpSocket,
 };
 
 use tokio::sync::oneshot::{channel, Sender};
 
-use drydoc_ipc::MessageProcessor;
+use drydoc_ipc::MessageCodec;
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 use derive_more::{Display, Error};
 
@@ -29,10 +35,83 @@ use std::sync::Arc;
 
 type ResponseSender<T> = Sender<Result<T, Error>>;
 
+/// Fail a request the generator sent the wrong kind of response to.
+fn unexpected<T>(request: &str, data: client::ResponseData) -> Option<Result<T, Error>> {
+  Some(Err(Error::Protocol {
+    message: format!("Expected a response to {}, got {:?}", request, data),
+  }))
+}
+
+/// Reject a request made before the handshake completed.
+fn not_initialized<T>(res: ResponseSender<T>) {
+  let _ = res.send(Err(Error::NotInitialized));
+}
+
 static IPC_VERSION: u32 = 1;
 
+/// The encodings generators may choose from during the handshake.
+static SUPPORTED_ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::Bincode, Encoding::Pickle];
+
+/// How long a generator has to respond to the handshake once started.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most bytes returned by a single `Read` request.
+const MAX_READ_SIZE: u32 = 1 << 20;
+
+#[derive(Display, Debug, Error)]
+pub enum Error {
+  /// The generator reported that it failed to handle a request.
+  #[display(fmt = "{}", message)]
+  Generator { message: String },
+  /// The connection to the generator was lost.
+  #[display(fmt = "The generator disconnected")]
+  Disconnected,
+  /// The generator speaks a different version of the IPC protocol.
+  #[display(
+    fmt = "The generator speaks IPC version {}, but version {} is required",
+    version,
+    expected
+  )]
+  Incompatible { version: u32, expected: u32 },
+  /// The generator chose an encoding that wasn't offered to it.
+  #[display(fmt = "The generator chose an unsupported encoding ({:?})", encoding)]
+  UnsupportedEncoding { encoding: Encoding },
+  /// The generator sent a message the protocol doesn't allow.
+  #[display(fmt = "The generator broke the IPC protocol ({})", message)]
+  Protocol { message: String },
+  /// The generator didn't respond to the handshake in time.
+  #[display(
+    fmt = "The generator didn't respond to the handshake within {} seconds",
+    "HANDSHAKE_TIMEOUT.as_secs()"
+  )]
+  Timeout,
+  /// A request was made before the handshake completed.
+  #[display(fmt = "The generator hasn't been initialized")]
+  NotInitialized,
+}
+
+/// Why a generator's request to access a file failed, which is sent back to
+/// it.
 #[derive(Display, Debug, Error)]
-pub enum Error {}
+enum FileError {
+  /// The file isn't within the project's root directory.
+  #[display(fmt = "{} is outside of the project", path)]
+  OutsideRoot { path: String },
+  #[display(fmt = "{} is not a file", path)]
+  NotAFile { path: String },
+  #[display(fmt = "Unable to open {}: {}", path, source)]
+  Open {
+    path: String,
+    source: std::io::Error,
+  },
+  #[display(fmt = "Unable to read file: {}", source)]
+  Read { source: std::io::Error },
+  /// The request the file is opened for isn't a `Generate` request.
+  #[display(fmt = "Unknown generate request {}", id)]
+  UnknownRequest { id: u64 },
+  #[display(fmt = "Unknown file handle {}", "handle.id()")]
+  UnknownHandle { handle: LinkedFileHandle },
+}
 
 pub struct Init {
   pub res: Sender<Result<(), Error>>,
@@ -54,16 +133,32 @@ pub struct CloseContext {
 pub struct Generate {
   context_id: u32,
   namespace: String,
-  params: HashMap<String, String>,
+  params: Params,
   path: String,
   pub res: ResponseSender<client::GenerateResponse>,
 }
 
+/// A request to read part of a `LinkedFile` the generator returned.
+pub struct ReadLinked {
+  pub handle: LinkedFileHandle,
+  pub offset: u64,
+  pub len: u32,
+  pub res: ResponseSender<Vec<u8>>,
+}
+
+/// A request to let the generator discard a `LinkedFile`.
+pub struct ReleaseLinked {
+  pub handle: LinkedFileHandle,
+  pub res: ResponseSender<()>,
+}
+
 pub enum IpcMsg {
   Generate(Generate),
   Init(Init),
   OpenContext(OpenContext),
   CloseContext(CloseContext),
+  ReadLinked(ReadLinked),
+  ReleaseLinked(ReleaseLinked),
 }
 
 impl From<Generate> for IpcMsg {
@@ -90,10 +185,27 @@ impl From<CloseContext> for IpcMsg {
   }
 }
 
+impl From<ReadLinked> for IpcMsg {
+  fn from(value: ReadLinked) -> Self {
+    Self::ReadLinked(value)
+  }
+}
+
+impl From<ReleaseLinked> for IpcMsg {
+  fn from(value: ReleaseLinked) -> Self {
+    Self::ReleaseLinked(value)
+  }
+}
+
 enum IpcInternalMsg {
   Ipc(IpcMsg),
-  Init(client::InitializeResponse),
   Send(Message),
+  /// A request from the generator, to be responded to.
+  Request(client::Request),
+  /// The generator closed its end of the connection.
+  Disconnected,
+  /// The generator responded to the `CloseContext` request for a context.
+  ContextClosed(u32),
 }
 
 impl From<IpcMsg> for IpcInternalMsg {
@@ -128,9 +240,9 @@ where
   F: FnOnce(client::ResponseData) -> Option<T>,
 {
   fn resolve(self: Box<Self>, data: client::ResponseData) -> Result<(), ()> {
-    // If this fails to unwrap it means a programming
-    // mistake was made. Hard error.
-    let res = (self.f)(data).unwrap();
+    // Every mapper handles unexpected responses, so this is a programming
+    // mistake
+    let res = (self.f)(data).expect("Unmapped response");
     self.sender.send(res).map_err(|_| ())
   }
 }
@@ -140,12 +252,30 @@ where
   R: 'static + AsyncRead + Send + Unpin,
   W: 'static + AsyncWrite + Send + Unpin,
 {
-  write: W,
+  write: FramedWrite<W, MessageCodec>,
   read: Option<R>,
   outstanding_requests: Addr<StoreMsg<u64, Box<dyn Responder + Send + Sync>>>,
   request_id_iter: u64,
   encoding: Encoding,
-  drops: Vec<Box<dyn 'static + Drop + Send>>,
+  /// Whether the generator reads its inputs itself, as negotiated by the
+  /// handshake
+  requires_direct_fs_access: bool,
+  /// Dropped along with the connection
+  drops: Vec<Box<dyn 'static + Send>>,
+  /// The directory generators may open files in
+  root: PathBuf,
+  /// The context of each `Generate` request, and the directory of the
+  /// configuration file its decl was declared in, by request id. Kept until
+  /// the context is closed, as the generator's requests may arrive after its
+  /// response.
+  decl_dirs: HashMap<u64, (u32, PathBuf)>,
+  /// The files the generator opened, along with the `Generate` request each
+  /// was opened for (if any)
+  files: HashMap<LinkedFileHandle, (Option<u64>, File)>,
+  handle_iter: u32,
+  /// Where the generator's responses to `CloseContext` are reported, once
+  /// spawned
+  addr: Option<WeakAddr<IpcInternalMsg>>,
 }
 
 struct IpcReader<R>
@@ -153,7 +283,7 @@ where
   R: 'static + AsyncRead + Send + Unpin,
 {
   addr: WeakAddr<IpcInternalMsg>,
-  read: R,
+  read: FramedRead<R, MessageCodec>,
   outstanding_requests: Addr<StoreMsg<u64, Box<dyn Responder + Send + Sync>>>,
 }
 
@@ -162,31 +292,37 @@ where
   R: 'static + AsyncRead + Send + Unpin,
   W: 'static + AsyncWrite + Send + Unpin,
 {
-  pub fn new(read: R, write: W) -> Self {
+  /// Communicate with a generator over `read` and `write`, which may open
+  /// files in `root`.
+  pub fn new(read: R, write: W, root: PathBuf) -> Self {
     Self {
       read: Some(read),
-      write,
+      write: FramedWrite::new(write, MessageCodec::new()),
       outstanding_requests: Store::new().spawn(),
       request_id_iter: 0,
       encoding: Encoding::Json,
+      requires_direct_fs_access: true,
       drops: Vec::new(),
+      root,
+      decl_dirs: HashMap::new(),
+      files: HashMap::new(),
+      handle_iter: 0,
+      addr: None,
     }
   }
 
-  pub fn add_drop(&mut self, drop: impl Drop + Send + 'static) {
+  /// Keep `drop` (e.g., the generator's process) alive until the connection
+  /// is dropped.
+  pub fn add_drop(&mut self, drop: impl Send + 'static) {
     self.drops.push(Box::new(drop));
   }
 
   async fn on_event(this: &mut IpcReader<R>, event: client::Event) {}
 
   async fn on_request(this: &mut IpcReader<R>, request: client::Request) {
-    use client::{OpenRequest, ReleaseRequest, Request};
-
-    let Request { id, data } = request;
-
-    match data {
-      RequestData::Open(OpenRequest { path }) => {}
-      RequestData::Release(ReleaseRequest { handle }) => {}
+    // Responding needs the connection's state, which the actor owns
+    if let Some(addr) = this.addr.upgrade() {
+      let _ = addr.send(IpcInternalMsg::Request(request));
     }
   }
 
@@ -199,13 +335,13 @@ where
     }
   }
 
-  async fn process_message(this: &mut IpcReader<R>, message: Message) {
+  async fn process_message(
+    this: &mut IpcReader<R>,
+    message: Message,
+  ) -> Result<(), Box<dyn std::error::Error>> {
+    // The codec only decodes messages with a valid encoding
     let encoding = message.encoding().expect("Invalid encoding");
-    let message: client::MessageData = match encoding {
-      Encoding::Json => serde_json::from_slice(message.data()).expect("Invalid JSON"),
-      Encoding::Pickle => serde_pickle::from_slice(message.data()).expect("Invalid Pickle"),
-      Encoding::Bincode => bincode::deserialize(message.data()).expect("Invalid Bincode"),
-    };
+    let message: client::MessageData = encoding.deserialize(message.data())?;
 
     use client::MessageData;
 
@@ -214,87 +350,281 @@ where
       MessageData::Request(req) => Self::on_request(this, req).await,
       MessageData::Response(res) => Self::on_response(this, res).await,
     }
+    Ok(())
   }
 
   async fn read(mut this: IpcReader<R>) {
-    let mut processor = MessageProcessor::new();
-    let mut buf = [0u8; 512];
-    while let Ok(size) = this.read.read(&mut buf).await {
-      if size == 0 {
-        // FIXME: There's probably some way to not poll
-        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-        continue;
+    // Ends once the generator closes its end of the connection (or exits)
+    while let Some(message) = this.read.next().await {
+      let result = match message {
+        Ok(message) => Self::process_message(&mut this, message).await,
+        Err(err) => Err(err.into()),
+      };
+
+      // The rest of the stream can't be trusted after an invalid message
+      if let Err(err) = result {
+        error!("Invalid message from generator: {}", err);
+        break;
       }
+    }
+
+    // Outstanding requests will never be responded to. Dropping their
+    // responders fails them with `Error::Disconnected`.
+    let _ = this.outstanding_requests.clear().await;
+    if let Some(addr) = this.addr.upgrade() {
+      let _ = addr.send(IpcInternalMsg::Disconnected);
+    }
+  }
+
+  /// Send a request, resolving `responder` with the response. If the request
+  /// can't be sent, `responder` is dropped.
+  async fn request<T: Into<server::RequestData>>(
+    &mut self,
+    data: T,
+    responder: Box<dyn Responder + Send + Sync>,
+  ) -> u64 {
+    self.request_id_iter += 1;
+    let id = self.request_id_iter;
+    self
+      .outstanding_requests
+      .insert(id, responder)
+      .await
+      .unwrap();
 
-      processor.submit(&buf[..size]);
-      while let Some(message) = processor.next() {
-        Self::process_message(&mut this, message).await
+    let request = server::Request {
+      id,
+      data: data.into(),
+    };
+    if self.write_message(request).await.is_err() {
+      self.outstanding_requests.remove(id).await.unwrap();
+    }
+    id
+  }
+
+  /// Perform the handshake, after which messages are sent with the encoding
+  /// the generator chose.
+  async fn init(&mut self) -> Result<(), Error> {
+    let req = server::InitializeRequest {
+      version: IPC_VERSION,
+      supported_encodings: SUPPORTED_ENCODINGS.iter().copied().collect(),
+    };
+
+    let (tx, rx) = channel();
+    let responder = ResponderMapper::new(tx, |data| match data {
+      client::ResponseData::Initialize(res) => Some(Ok(res)),
+      client::ResponseData::Error(err) => Some(Err(Error::Generator {
+        message: err.message,
+      })),
+      data => unexpected("the handshake", data),
+    });
+
+    let id = self.request(req, Box::new(responder)).await;
+    let res = match tokio::time::timeout(HANDSHAKE_TIMEOUT, rx).await {
+      Ok(res) => res.map_err(|_| Error::Disconnected)??,
+      Err(_) => {
+        self.outstanding_requests.remove(id).await.unwrap();
+        return Err(Error::Timeout);
       }
+    };
+
+    if res.version != IPC_VERSION {
+      return Err(Error::Incompatible {
+        version: res.version,
+        expected: IPC_VERSION,
+      });
+    }
+    if !SUPPORTED_ENCODINGS.contains(&res.encoding) {
+      return Err(Error::UnsupportedEncoding {
+        encoding: res.encoding,
+      });
+    }
+
+    self.encoding = res.encoding;
+    self.requires_direct_fs_access = res.requires_direct_fs_access;
+    debug!(
+      "Initialized generator (encoding: {:?}, requires direct file system access: {})",
+      self.encoding, self.requires_direct_fs_access
+    );
+    Ok(())
+  }
+
+  /// Open the file at `path`, relative to the decl of the `Generate` request
+  /// `request` (or the root), provided it's within the root.
+  async fn open(&mut self, open: client::OpenRequest) -> Result<server::OpenResponse, FileError> {
+    let client::OpenRequest { path, request } = open;
+
+    let dir = match request {
+      Some(id) => self
+        .decl_dirs
+        .get(&id)
+        .map(|(_, dir)| dir)
+        .ok_or(FileError::UnknownRequest { id })?,
+      None => &self.root,
+    };
+
+    // Canonicalized so that neither `..` nor symbolic links can escape the
+    // root
+    let full_path = tokio::fs::canonicalize(dir.join(&path))
+      .await
+      .map_err(|source| FileError::Open {
+        path: path.clone(),
+        source,
+      })?;
+    if !full_path.starts_with(&self.root) {
+      return Err(FileError::OutsideRoot { path });
+    }
+
+    let file = File::open(&full_path)
+      .await
+      .map_err(|source| FileError::Open {
+        path: path.clone(),
+        source,
+      })?;
+    let metadata = file.metadata().await.map_err(|source| FileError::Open {
+      path: path.clone(),
+      source,
+    })?;
+    if !metadata.is_file() {
+      return Err(FileError::NotAFile { path });
+    }
+
+    self.handle_iter += 1;
+    let handle = LinkedFileHandle::new(self.handle_iter);
+    self.files.insert(handle, (request, file));
+    Ok(server::OpenResponse {
+      handle,
+      size: metadata.len(),
+    })
+  }
+
+  async fn read_file(
+    &mut self,
+    read: client::ReadRequest,
+  ) -> Result<server::ReadResponse, FileError> {
+    let client::ReadRequest {
+      handle,
+      offset,
+      len,
+    } = read;
+
+    let (_, file) = self
+      .files
+      .get_mut(&handle)
+      .ok_or(FileError::UnknownHandle { handle })?;
+    file
+      .seek(SeekFrom::Start(offset))
+      .await
+      .map_err(|source| FileError::Read { source })?;
+
+    let len = std::cmp::min(len, MAX_READ_SIZE);
+    let mut data = Vec::with_capacity(len as usize);
+    file
+      .take(len as u64)
+      .read_to_end(&mut data)
+      .await
+      .map_err(|source| FileError::Read { source })?;
+    Ok(server::ReadResponse { data })
+  }
+
+  fn release(
+    &mut self,
+    release: client::ReleaseRequest,
+  ) -> Result<server::ReleaseResponse, FileError> {
+    let client::ReleaseRequest { handle } = release;
+    self
+      .files
+      .remove(&handle)
+      .ok_or(FileError::UnknownHandle { handle })?;
+    Ok(server::ReleaseResponse {})
+  }
+
+  /// Forget the `Generate` requests made in the closed context `context`,
+  /// closing the files the generator didn't release.
+  fn forget_context(&mut self, context: u32) {
+    let requests: Vec<u64> = self
+      .decl_dirs
+      .iter()
+      .filter(|(_, (request_context, _))| *request_context == context)
+      .map(|(id, _)| *id)
+      .collect();
+
+    for id in &requests {
+      self.decl_dirs.remove(id);
+    }
+    self.files.retain(|_, (request, _)| match request {
+      Some(request) => !requests.contains(request),
+      None => true,
+    });
+  }
+
+  /// Respond to a request from the generator.
+  async fn on_generator_request(&mut self, request: client::Request) {
+    let client::Request { id, data } = request;
+
+    let result = match data {
+      RequestData::Open(open) => self.open(open).await.map(Into::into),
+      RequestData::Read(read) => self.read_file(read).await.map(Into::into),
+      RequestData::Release(release) => self.release(release).map(Into::into),
+    };
+    let data = result.unwrap_or_else(|err| {
+      server::ErrorResponse {
+        message: err.to_string(),
+      }
+      .into()
+    });
+
+    if let Err(err) = self.write_message(server::Response { id, data }).await {
+      error!("Unable to respond to generator: {}", err);
     }
   }
 
   async fn open_context(&mut self, open: OpenContext) {
-    use server::{OpenContextRequest, Request};
+    use server::OpenContextRequest;
 
     let OpenContext { id, res } = open;
 
     let req = OpenContextRequest { id };
 
-    let responder = ResponderMapper::new(res, |data| {
-      if let client::ResponseData::OpenContext(ctx) = data {
-        Some(Ok(ctx))
-      } else {
-        None
-      }
+    let responder = ResponderMapper::new(res, |data| match data {
+      client::ResponseData::OpenContext(ctx) => Some(Ok(ctx)),
+      client::ResponseData::Error(err) => Some(Err(Error::Generator {
+        message: err.message,
+      })),
+      data => unexpected("the `OpenContext` request", data),
     });
 
-    self.request_id_iter += 1;
-    self
-      .outstanding_requests
-      .insert(self.request_id_iter, Box::new(responder))
-      .await
-      .unwrap();
-
-    self
-      .write_message(Request {
-        id: self.request_id_iter,
-        data: req.into(),
-      })
-      .await;
+    self.request(req, Box::new(responder)).await;
   }
 
   async fn close_context(&mut self, close: CloseContext) {
-    use server::{CloseContextRequest, Request};
+    use server::CloseContextRequest;
 
     let CloseContext { id, res } = close;
 
     let req = CloseContextRequest { id };
 
-    let responder = ResponderMapper::new(res, |data| {
-      if let client::ResponseData::CloseContext(ctx) = data {
-        Some(Ok(ctx))
-      } else {
-        None
+    // Reported by the reader, after the requests the generator made before
+    // responding
+    let addr = self.addr.clone();
+    let responder = ResponderMapper::new(res, move |data| {
+      if let Some(addr) = addr.and_then(|addr| addr.upgrade()) {
+        let _ = addr.send(IpcInternalMsg::ContextClosed(id));
       }
-    });
 
-    self.request_id_iter += 1;
-    self
-      .outstanding_requests
-      .insert(self.request_id_iter, Box::new(responder))
-      .await
-      .unwrap();
+      match data {
+        client::ResponseData::CloseContext(ctx) => Some(Ok(ctx)),
+        client::ResponseData::Error(err) => Some(Err(Error::Generator {
+          message: err.message,
+        })),
+        data => unexpected("the `CloseContext` request", data),
+      }
+    });
 
-    self
-      .write_message(Request {
-        id: self.request_id_iter,
-        data: req.into(),
-      })
-      .await;
+    self.request(req, Box::new(responder)).await;
   }
 
   async fn generate(&mut self, generate: Generate) {
-    use server::{GenerateRequest, Request};
+    use server::GenerateRequest;
 
     let Generate {
       context_id,
@@ -304,39 +634,74 @@ where
       res,
     } = generate;
 
-    let req = GenerateRequest {
-      context_id,
-      params,
-      path,
+    let dir = Path::new(path.as_str())
+      .parent()
+      .unwrap_or_else(|| Path::new(""))
+      .to_path_buf();
+    let req = GenerateRequest::new(context_id, params, path);
+
+    let responder = ResponderMapper::new(res, |data| match data {
+      client::ResponseData::Generate(ctx) => Some(Ok(ctx)),
+      client::ResponseData::Error(err) => Some(Err(Error::Generator {
+        message: err.message,
+      })),
+      data => unexpected("the `Generate` request", data),
+    });
+
+    let id = self.request(req, Box::new(responder)).await;
+    // The generator's requests are handled after this returns, so none can
+    // arrive before its decl's directory is known
+    self.decl_dirs.insert(id, (context_id, dir));
+  }
+
+  async fn read_linked(&mut self, read: ReadLinked) {
+    let ReadLinked {
+      handle,
+      offset,
+      len,
+      res,
+    } = read;
+
+    let req = server::ReadRequest {
+      handle,
+      offset,
+      len,
     };
 
-    let responder = ResponderMapper::new(res, |data| {
-      if let client::ResponseData::Generate(ctx) = data {
-        Some(Ok(ctx))
-      } else {
-        None
-      }
+    let responder = ResponderMapper::new(res, |data| match data {
+      client::ResponseData::Read(read) => Some(Ok(read.data)),
+      client::ResponseData::Error(err) => Some(Err(Error::Generator {
+        message: err.message,
+      })),
+      data => unexpected("the `Read` request", data),
     });
 
-    self.request_id_iter += 1;
-    self
-      .outstanding_requests
-      .insert(self.request_id_iter, Box::new(responder))
-      .await
-      .unwrap();
+    self.request(req, Box::new(responder)).await;
+  }
 
-    self
-      .write_message(Request {
-        id: self.request_id_iter,
-        data: req.into(),
-      })
-      .await;
+  async fn release_linked(&mut self, release: ReleaseLinked) {
+    let ReleaseLinked { handle, res } = release;
+
+    let req = server::ReleaseRequest { handle };
+
+    let responder = ResponderMapper::new(res, |data| match data {
+      client::ResponseData::Release(_) => Some(Ok(())),
+      client::ResponseData::Error(err) => Some(Err(Error::Generator {
+        message: err.message,
+      })),
+      data => unexpected("the `Release` request", data),
+    });
+
+    self.request(req, Box::new(responder)).await;
   }
 
-  async fn write_message<T: Into<server::MessageData>>(&mut self, msg: T) {
+  async fn write_message<T: Into<server::MessageData>>(
+    &mut self,
+    msg: T,
+  ) -> Result<(), drydoc_ipc::CodecError> {
     let msg = Message::encode(self.encoding, &msg.into()).unwrap();
 
-    self.write.write_all(msg.raw()).await.unwrap();
+    self.write.send(msg).await
   }
 
   async fn run(mut self, mut rx: Receiver<IpcInternalMsg>) {
@@ -345,24 +710,33 @@ where
     while let Some(msg) = rx.recv().await {
       match msg {
         IpcInternalMsg::Send(msg) => {
-          self.write.write_all(msg.raw()).await.unwrap();
+          self.write.send(msg).await.unwrap();
         }
-        IpcInternalMsg::Init(res) => {
-          inited = true;
-          self.encoding = res.encoding;
-        }
-        IpcInternalMsg::Ipc(ipc) => {
-          if !inited {
-            panic!("Not inited");
-          }
-
-          match ipc {
-            IpcMsg::OpenContext(open) => self.open_context(open).await,
-            IpcMsg::CloseContext(close) => self.close_context(close).await,
-            IpcMsg::Generate(close) => self.generate(close).await,
-            _ => {}
-          }
+        IpcInternalMsg::Request(request) => self.on_generator_request(request).await,
+        IpcInternalMsg::Disconnected => break,
+        IpcInternalMsg::ContextClosed(context) => self.forget_context(context),
+        IpcInternalMsg::Ipc(IpcMsg::Init(Init { res })) => {
+          let result = self.init().await;
+          inited = result.is_ok();
+          let _ = res.send(result);
         }
+        IpcInternalMsg::Ipc(ipc) if !inited => match ipc {
+          IpcMsg::OpenContext(open) => not_initialized(open.res),
+          IpcMsg::CloseContext(close) => not_initialized(close.res),
+          IpcMsg::Generate(generate) => not_initialized(generate.res),
+          IpcMsg::ReadLinked(read) => not_initialized(read.res),
+          IpcMsg::ReleaseLinked(release) => not_initialized(release.res),
+          // Handled above
+          IpcMsg::Init(_) => {}
+        },
+        IpcInternalMsg::Ipc(ipc) => match ipc {
+          IpcMsg::OpenContext(open) => self.open_context(open).await,
+          IpcMsg::CloseContext(close) => self.close_context(close).await,
+          IpcMsg::Generate(close) => self.generate(close).await,
+          IpcMsg::ReadLinked(read) => self.read_linked(read).await,
+          IpcMsg::ReleaseLinked(release) => self.release_linked(release).await,
+          _ => {}
+        },
       }
     }
   }
@@ -377,11 +751,12 @@ where
 
   fn spawn(mut self) -> Addr<Self::Msg> {
     let (addr, rx) = Addr::new();
+    self.addr = Some(addr.downgrade());
     let read = self.read.take().unwrap();
     let outstanding_requests = self.outstanding_requests.clone();
     let map = tokio::spawn(self.run(rx));
     tokio::spawn(Self::read(IpcReader {
-      read,
+      read: FramedRead::new(read, MessageCodec::new()),
       addr: addr.downgrade(),
       outstanding_requests,
     }));
@@ -392,27 +767,61 @@ where
 impl Addr<IpcMsg> {
   pub async fn open_context(&self, id: u32) -> Result<client::OpenContextResponse, Error> {
     let (tx, rx) = channel();
-    self.send(OpenContext { id, res: tx }).unwrap();
-    rx.await.unwrap()
+    self
+      .send(OpenContext { id, res: tx })
+      .map_err(|_| Error::Disconnected)?;
+    rx.await.map_err(|_| Error::Disconnected)?
   }
 
   pub async fn close_context(&self, id: u32) -> Result<client::CloseContextResponse, Error> {
     let (tx, rx) = channel();
-    self.send(CloseContext { id, res: tx }).unwrap();
-    rx.await.unwrap()
+    self
+      .send(CloseContext { id, res: tx })
+      .map_err(|_| Error::Disconnected)?;
+    rx.await.map_err(|_| Error::Disconnected)?
+  }
+
+  /// Read up to `len` bytes of the generator's `LinkedFile` `handle`,
+  /// starting at `offset`. No bytes are returned at the end of the file.
+  pub async fn read_linked(
+    &self,
+    handle: LinkedFileHandle,
+    offset: u64,
+    len: u32,
+  ) -> Result<Vec<u8>, Error> {
+    let (tx, rx) = channel();
+    self
+      .send(ReadLinked {
+        handle,
+        offset,
+        len,
+        res: tx,
+      })
+      .map_err(|_| Error::Disconnected)?;
+    rx.await.map_err(|_| Error::Disconnected)?
+  }
+
+  pub async fn release_linked(&self, handle: LinkedFileHandle) -> Result<(), Error> {
+    let (tx, rx) = channel();
+    self
+      .send(ReleaseLinked { handle, res: tx })
+      .map_err(|_| Error::Disconnected)?;
+    rx.await.map_err(|_| Error::Disconnected)?
   }
 
   pub async fn init(&self) -> Result<(), Error> {
     let (tx, rx) = channel();
-    self.send(Init { res: tx }).unwrap();
-    rx.await.unwrap()
+    self
+      .send(Init { res: tx })
+      .map_err(|_| Error::Disconnected)?;
+    rx.await.map_err(|_| Error::Disconnected)?
   }
 
   pub async fn generate(
     &self,
     context_id: u32,
     namespace: Arc<Namespace>,
-    params: HashMap<String, String>,
+    params: Params,
     path: String,
   ) -> Result<client::GenerateResponse, Error> {
     let (tx, rx) = channel();
@@ -424,32 +833,43 @@ impl Addr<IpcMsg> {
         path,
         res: tx,
       })
-      .unwrap();
-    rx.await.unwrap()
+      .map_err(|_| Error::Disconnected)?;
+    rx.await.map_err(|_| Error::Disconnected)?
   }
 }
 
 use tokio::{
   net::TcpStream,
-  process::{ChildStdin, ChildStdout, Command},
+  process::{Child, Command},
 };
 
 use std::{path::Path, process::Stdio, time::Duration};
 
 use drydoc_pkg_manager::{GeneratorArtifact, IpcChannel};
 
-pub async fn pipe(stdout: ChildStdout, stdin: ChildStdin) -> Addr<IpcMsg> {
-  Ipc::new(stdout, stdin).spawn()
+/// Communicate with `child` over its stdin and stdout.
+pub async fn pipe(mut child: Child, root: PathBuf) -> Addr<IpcMsg> {
+  let stdout = child.stdout.take().unwrap();
+  let stdin = child.stdin.take().unwrap();
+  let mut ipc = Ipc::new(stdout, stdin, root);
+  ipc.add_drop(child);
+  ipc.spawn()
 }
 
-pub async fn tcp(stream: TcpStream) -> Addr<IpcMsg> {
+/// Communicate with `child` over `stream`.
+pub async fn tcp(stream: TcpStream, child: Child, root: PathBuf) -> Addr<IpcMsg> {
   let (rx, tx) = stream.into_split();
-  Ipc::new(rx, tx).spawn()
+  let mut ipc = Ipc::new(rx, tx, root);
+  ipc.add_drop(child);
+  ipc.spawn()
 }
 
+/// Start the generator `artifact`, installed at `path`, which may open files
+/// in `root`.
 pub async fn start_generator<P: AsRef<Path>>(
   path: P,
   artifact: &GeneratorArtifact,
+  root: PathBuf,
 ) -> std::io::Result<Addr<IpcMsg>> {
   let mut program_path = path.as_ref().to_path_buf();
   program_path.push(&artifact.entrypoint);
@@ -466,10 +886,11 @@ pub async fn start_generator<P: AsRef<Path>>(
     }
   }
 
+  // The generator is killed once the connection to it is dropped
   let child = cmd.spawn()?;
 
   match artifact.ipc_channel {
-    IpcChannel::Stdio => Ok(pipe(child.stdout.unwrap(), child.stdin.unwrap()).await),
+    IpcChannel::Stdio => Ok(pipe(child, root).await),
     IpcChannel::Tcp { port } => {
       lazy_static! {
         static ref LOCAL_HOST: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
@@ -480,7 +901,233 @@ pub async fn start_generator<P: AsRef<Path>>(
       tokio::time::sleep(Duration::from_millis(500)).await;
       let socket = TcpSocket::new_v4()?;
       let addr = SocketAddrV4::new(*LOCAL_HOST, port);
-      Ok(tcp(socket.connect(addr.into()).await?).await)
+      Ok(tcp(socket.connect(addr.into()).await?, child, root).await)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use drydoc_model::{
+    bundle::{Bundle, Manifest},
+    fs::VirtualFolder,
+  };
+  use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+  /// The generator's end of a connection.
+  struct FakeGenerator {
+    read: FramedRead<ReadHalf<DuplexStream>, MessageCodec>,
+    write: FramedWrite<WriteHalf<DuplexStream>, MessageCodec>,
+    request_id_iter: u64,
+  }
+
+  impl FakeGenerator {
+    async fn recv(&mut self) -> server::MessageData {
+      let message = self.read.next().await.unwrap().unwrap();
+      Encoding::Json.deserialize(message.data()).unwrap()
     }
+
+    async fn send(&mut self, message: client::MessageData) {
+      let message = Message::encode(Encoding::Json, &message).unwrap();
+      self.write.send(message).await.unwrap();
+    }
+
+    async fn recv_request(&mut self) -> server::Request {
+      match self.recv().await {
+        server::MessageData::Request(request) => request,
+        message => panic!("Unexpected message {:?}", message),
+      }
+    }
+
+    async fn respond(&mut self, id: u64, data: client::ResponseData) {
+      self
+        .send(client::MessageData::Response(client::Response { id, data }))
+        .await;
+    }
+
+    /// Make a request of the host, returning its response.
+    async fn request(&mut self, data: client::RequestData) -> server::ResponseData {
+      self.request_id_iter += 1;
+      let id = self.request_id_iter;
+      self
+        .send(client::MessageData::Request(client::Request { id, data }))
+        .await;
+
+      match self.recv().await {
+        server::MessageData::Response(response) if response.id == id => response.data,
+        message => panic!("Unexpected message {:?}", message),
+      }
+    }
+
+    /// Complete the handshake, choosing JSON.
+    async fn init(&mut self) {
+      let request = self.recv_request().await;
+      self.respond(request.id, initialize_response()).await;
+    }
+  }
+
+  fn initialize_response() -> client::ResponseData {
+    client::ResponseData::Initialize(client::InitializeResponse {
+      version: IPC_VERSION,
+      encoding: Encoding::Json,
+      requires_direct_fs_access: false,
+    })
+  }
+
+  /// A connection to a generator that may open files in `root`.
+  fn connect(root: PathBuf) -> (Addr<IpcMsg>, FakeGenerator) {
+    let (ours, theirs) = tokio::io::duplex(1 << 16);
+    let (read, write) = tokio::io::split(ours);
+    let ipc = Ipc::new(read, write, root).spawn();
+
+    let (read, write) = tokio::io::split(theirs);
+    let generator = FakeGenerator {
+      read: FramedRead::new(read, MessageCodec::new()),
+      write: FramedWrite::new(write, MessageCodec::new()),
+      request_id_iter: 0,
+    };
+    (ipc, generator)
+  }
+
+  #[tokio::test]
+  async fn rejects_requests_before_init() {
+    // Keep the generator's end open, so the connection isn't lost
+    let (ours, _theirs) = tokio::io::duplex(1024);
+    let (read, write) = tokio::io::split(ours);
+    let ipc = Ipc::new(read, write, PathBuf::new()).spawn();
+
+    assert!(matches!(
+      ipc.open_context(0).await,
+      Err(Error::NotInitialized)
+    ));
+    assert!(matches!(
+      ipc.release_linked(LinkedFileHandle::new(0)).await,
+      Err(Error::NotInitialized)
+    ));
+  }
+
+  #[tokio::test]
+  async fn rejects_unexpected_responses() {
+    let (ipc, mut generator) = connect(PathBuf::new());
+
+    // Responds to every request as if it were another handshake
+    let host = async {
+      ipc.init().await.unwrap();
+      assert!(matches!(
+        ipc.open_context(0).await,
+        Err(Error::Protocol { .. })
+      ));
+      assert!(matches!(
+        ipc.read_linked(LinkedFileHandle::new(0), 0, 1).await,
+        Err(Error::Protocol { .. })
+      ));
+      // The connection outlives the broken responses
+      assert!(matches!(
+        ipc.release_linked(LinkedFileHandle::new(0)).await,
+        Err(Error::Protocol { .. })
+      ));
+    };
+    let generator = async {
+      generator.init().await;
+      for _ in 0..3 {
+        let request = generator.recv_request().await;
+        generator.respond(request.id, initialize_response()).await;
+      }
+    };
+    tokio::join!(host, generator);
+  }
+
+  #[tokio::test]
+  async fn forgets_closed_contexts() {
+    let root = std::env::temp_dir().join(format!("drydoc-ipc-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("input.txt"), "input").unwrap();
+    let root = std::fs::canonicalize(root).unwrap();
+
+    let (ipc, mut generator) = connect(root.clone());
+    let decl_path = root.join("drydoc.yaml").to_string_lossy().to_string();
+    let host = async {
+      ipc.init().await.unwrap();
+      ipc.open_context(0).await.unwrap();
+      ipc
+        .generate(0, Namespace::new("root"), Params::new(), decl_path)
+        .await
+        .unwrap();
+      ipc.close_context(0).await.unwrap();
+      // Gives the generator a chance to use what it opened in context 0
+      ipc.open_context(1).await.unwrap();
+    };
+    let generator = async {
+      generator.init().await;
+      let open = generator.recv_request().await;
+      generator
+        .respond(
+          open.id,
+          client::ResponseData::OpenContext(client::OpenContextResponse {}),
+        )
+        .await;
+
+      // Opens a file without releasing it
+      let generate = generator.recv_request().await;
+      let open_input = || {
+        client::RequestData::Open(client::OpenRequest {
+          path: "input.txt".to_string(),
+          request: Some(generate.id),
+        })
+      };
+      let handle = match generator.request(open_input()).await {
+        server::ResponseData::Open(open) => open.handle,
+        data => panic!("Unexpected response {:?}", data),
+      };
+      let read_input = || {
+        client::RequestData::Read(client::ReadRequest {
+          handle,
+          offset: 0,
+          len: 5,
+        })
+      };
+      let before = generator.request(read_input()).await;
+      let bundle = Bundle {
+        manifest: Manifest {
+          root: "root".into(),
+          symbols: HashMap::new(),
+          pages: HashMap::new(),
+        },
+        resources: VirtualFolder::new().into(),
+      };
+      generator
+        .respond(
+          generate.id,
+          client::ResponseData::Generate(client::GenerateResponse { bundle }),
+        )
+        .await;
+
+      let close = generator.recv_request().await;
+      generator
+        .respond(
+          close.id,
+          client::ResponseData::CloseContext(client::CloseContextResponse { bundle: None }),
+        )
+        .await;
+
+      let open = generator.recv_request().await;
+      let after = generator.request(read_input()).await;
+      let reopened = generator.request(open_input()).await;
+      generator
+        .respond(
+          open.id,
+          client::ResponseData::OpenContext(client::OpenContextResponse {}),
+        )
+        .await;
+      (before, after, reopened)
+    };
+    let ((), (before, after, reopened)) = tokio::join!(host, generator);
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert!(matches!(before, server::ResponseData::Read(read) if read.data == b"input"));
+    assert!(matches!(after, server::ResponseData::Error(_)));
+    assert!(matches!(reopened, server::ResponseData::Error(_)));
   }
 }
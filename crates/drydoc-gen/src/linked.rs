@@ -0,0 +1,383 @@
+//! Generators may return large files (e.g., videos) as `LinkedFile`s, which
+//! the host reads from them over IPC in chunks rather than as part of their
+//! response. Each is stored in a directory named after the hash of its
+//! contents, so that cached bundles can keep referring to it. Files the last
+//! successful build no longer refers to are removed after it.
+
+use std::{
+  collections::{HashMap, HashSet},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+use derive_more::{Display, Error};
+use drydoc_model::fs::{Entry, File, Folder, LinkedFileHandle, LocalFile};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::{actor::Addr, ipc::IpcMsg};
+
+/// How many bytes are requested from the generator at once.
+const CHUNK_SIZE: u32 = 1 << 20;
+
+/// Distinguishes the temporary files of concurrent reads.
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Display, Debug, Error)]
+pub enum Error {
+  /// The generator failed to provide the file.
+  #[display(fmt = "Unable to read linked file {}: {}", "handle.id()", source)]
+  Ipc {
+    handle: LinkedFileHandle,
+    source: crate::ipc::Error,
+  },
+  #[display(
+    fmt = "Unable to store linked file at {}: {}",
+    "path.display()",
+    source
+  )]
+  Io {
+    path: PathBuf,
+    source: std::io::Error,
+  },
+}
+
+/// Every linked file in `folder`, and its subfolders.
+fn linked_entries<'a>(folder: &'a mut Folder, entries: &mut Vec<&'a mut Entry>) {
+  // Local folders are read from disk, so can't contain linked files
+  if let Folder::Virtual(folder) = folder {
+    for (_, entry) in folder.iter_mut() {
+      if let Entry::Folder(folder) = entry {
+        linked_entries(folder, entries);
+      } else if let Entry::File(File::Linked(_)) = entry {
+        entries.push(entry);
+      }
+    }
+  }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> Error {
+  let path = path.to_path_buf();
+  move |source| Error::Io { path, source }
+}
+
+/// Copy the linked file `handle` from `ipc` into the new file at `path`,
+/// returning the hash of its contents.
+async fn copy(ipc: &Addr<IpcMsg>, handle: LinkedFileHandle, path: &Path) -> Result<String, Error> {
+  let mut file = tokio::fs::File::create(path)
+    .await
+    .map_err(io_error(path))?;
+
+  // Written as it arrives, so that the file is never held in memory
+  let mut hasher = Sha256::default();
+  let mut offset = 0;
+  loop {
+    let data = ipc
+      .read_linked(handle, offset, CHUNK_SIZE)
+      .await
+      .map_err(|source| Error::Ipc { handle, source })?;
+    if data.is_empty() {
+      break;
+    }
+
+    hasher.update(&data);
+    file.write_all(&data).await.map_err(io_error(path))?;
+    offset += data.len() as u64;
+  }
+  file.flush().await.map_err(io_error(path))?;
+
+  Ok(hex::encode(hasher.finalize()))
+}
+
+/// Read the linked file `handle` from `ipc` into `dir`, returning its path.
+async fn read(ipc: &Addr<IpcMsg>, handle: LinkedFileHandle, dir: &Path) -> Result<PathBuf, Error> {
+  tokio::fs::create_dir_all(dir)
+    .await
+    .map_err(io_error(dir))?;
+
+  let tmp_path = dir.join(format!(
+    "{}-{}.tmp",
+    std::process::id(),
+    NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+  ));
+  let hash = match copy(ipc, handle, &tmp_path).await {
+    Ok(hash) => hash,
+    Err(err) => {
+      let _ = tokio::fs::remove_file(&tmp_path).await;
+      return Err(err);
+    }
+  };
+
+  let path = dir.join(hash);
+  tokio::fs::rename(&tmp_path, &path)
+    .await
+    .map_err(io_error(&path))?;
+  Ok(path)
+}
+
+/// Replace every linked file in `folder` with a local file in `dir`, read
+/// from the generator `ipc`, which is then told to release it. If any file
+/// can't be read, the generator is still told to release the rest.
+pub async fn resolve(ipc: &Addr<IpcMsg>, folder: &mut Folder, dir: &Path) -> Result<(), Error> {
+  let mut entries = Vec::new();
+  linked_entries(folder, &mut entries);
+
+  let entries: Vec<(LinkedFileHandle, &mut Entry)> = entries
+    .into_iter()
+    .map(|entry| match entry {
+      Entry::File(File::Linked(linked)) => (linked.handle(), entry),
+      _ => unreachable!("Only linked files are collected"),
+    })
+    .collect();
+
+  // A file may be linked more than once, but can only be released once
+  let mut handles: Vec<LinkedFileHandle> = Vec::new();
+  for (handle, _) in &entries {
+    if !handles.contains(handle) {
+      handles.push(*handle);
+    }
+  }
+
+  let mut paths: HashMap<LinkedFileHandle, PathBuf> = HashMap::new();
+  let mut handles = handles.into_iter();
+  while let Some(handle) = handles.next() {
+    let result = read(ipc, handle, dir).await;
+    let released = ipc
+      .release_linked(handle)
+      .await
+      .map_err(|source| Error::Ipc { handle, source });
+    match result.and_then(|path| released.map(|()| path)) {
+      Ok(path) => {
+        paths.insert(handle, path);
+      }
+      Err(err) => {
+        for handle in handles {
+          let _ = ipc.release_linked(handle).await;
+        }
+        return Err(err);
+      }
+    }
+  }
+
+  for (handle, entry) in entries {
+    *entry = LocalFile::new(&paths[&handle]).into();
+  }
+
+  Ok(())
+}
+
+/// The paths of the local files and folders `folder` refers to.
+fn local_paths<'a>(folder: &'a Folder, paths: &mut Vec<&'a Path>) {
+  let folder = match folder {
+    Folder::Virtual(folder) => folder,
+    Folder::Local(folder) => return paths.push(folder.path()),
+  };
+
+  for (_, entry) in folder.iter() {
+    match entry {
+      Entry::Folder(folder) => local_paths(folder, paths),
+      Entry::File(File::Local(file)) => paths.push(file.path()),
+      Entry::File(_) => {}
+    }
+  }
+}
+
+/// Whether every local file and folder `folder` refers to still exists
+/// (e.g., linked files that were since removed from the cache).
+pub fn is_complete(folder: &Folder) -> bool {
+  let mut paths = Vec::new();
+  local_paths(folder, &mut paths);
+  paths.iter().all(|path| path.exists())
+}
+
+/// Remove the linked files in `dir` that `folder` doesn't refer to, along
+/// with those left behind by interrupted reads.
+pub async fn remove_unreferenced(dir: &Path, folder: &Folder) -> Result<(), Error> {
+  let mut paths = Vec::new();
+  local_paths(folder, &mut paths);
+  let referenced: HashSet<&Path> = paths.into_iter().collect();
+
+  let mut entries = match tokio::fs::read_dir(dir).await {
+    Ok(entries) => entries,
+    // Nothing was ever linked
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(source) => return Err(io_error(dir)(source)),
+  };
+  while let Some(entry) = entries.next_entry().await.map_err(io_error(dir))? {
+    let path = entry.path();
+    if !referenced.contains(path.as_path()) {
+      tokio::fs::remove_file(&path)
+        .await
+        .map_err(io_error(&path))?;
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::{Arc, Mutex};
+
+  use drydoc_model::fs::{LinkedFile, VirtualFolder};
+
+  use crate::ipc::{ReadLinked, ReleaseLinked};
+
+  /// An empty directory for the test `name`.
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("drydoc-linked-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  /// A generator linking to `files`, by handle. Returns the handles it was
+  /// told to release, in order.
+  fn generator(files: Vec<(u32, &'static [u8])>) -> (Addr<IpcMsg>, Arc<Mutex<Vec<u32>>>) {
+    let files: HashMap<u32, &[u8]> = files.into_iter().collect();
+    let released = Arc::new(Mutex::new(Vec::new()));
+
+    let (addr, mut rx) = Addr::new();
+    let released_by_task = released.clone();
+    tokio::spawn(async move {
+      while let Some(msg) = rx.recv().await {
+        match msg {
+          IpcMsg::ReadLinked(ReadLinked {
+            handle,
+            offset,
+            len,
+            res,
+          }) => {
+            let _ = res.send(match files.get(&handle.id()) {
+              Some(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + len as usize).min(data.len());
+                Ok(data[start..end].to_vec())
+              }
+              None => Err(crate::ipc::Error::Generator {
+                message: format!("Unknown file {}", handle.id()),
+              }),
+            });
+          }
+          IpcMsg::ReleaseLinked(ReleaseLinked { handle, res }) => {
+            released_by_task.lock().unwrap().push(handle.id());
+            let _ = res.send(Ok(()));
+          }
+          _ => panic!("Unexpected request"),
+        }
+      }
+    });
+
+    (addr, released)
+  }
+
+  fn linked(id: u32) -> File {
+    LinkedFile::new(LinkedFileHandle::new(id)).into()
+  }
+
+  /// The path of the local file `name` in `folder`.
+  fn local_path(folder: &mut Folder, name: &str) -> PathBuf {
+    match folder {
+      Folder::Virtual(folder) => match folder.get(name) {
+        Some(Entry::File(File::Local(file))) => file.path().to_path_buf(),
+        entry => panic!("Unexpected entry {:?}", entry),
+      },
+      Folder::Local(_) => panic!("Unexpected local folder"),
+    }
+  }
+
+  #[tokio::test]
+  async fn reads_each_file_once() {
+    let dir = temp_dir("dedup");
+    let (ipc, released) = generator(vec![(1, b"video"), (2, b"audio")]);
+
+    let mut folder = VirtualFolder::new();
+    folder.insert("a.mp4", linked(1));
+    folder.insert("b.mp4", linked(1));
+    folder.insert("c.ogg", linked(2));
+    let mut folder: Folder = folder.into();
+
+    resolve(&ipc, &mut folder, &dir).await.unwrap();
+
+    let a = local_path(&mut folder, "a.mp4");
+    assert_eq!(a, local_path(&mut folder, "b.mp4"));
+    assert_eq!(std::fs::read(&a).unwrap(), b"video");
+    let c = local_path(&mut folder, "c.ogg");
+    assert_eq!(std::fs::read(&c).unwrap(), b"audio");
+
+    let mut released = released.lock().unwrap().clone();
+    released.sort_unstable();
+    assert_eq!(released, vec![1, 2]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn releases_every_file_on_error() {
+    let dir = temp_dir("error");
+    // Whichever file is read first fails, so the others are never read
+    let (ipc, released) = generator(vec![]);
+
+    let mut folder = VirtualFolder::new();
+    folder.insert("a.mp4", linked(1));
+    folder.insert("b.mp4", linked(1));
+    folder.insert("c.ogg", linked(2));
+    folder.insert("d.ogg", linked(3));
+    let mut folder: Folder = folder.into();
+
+    assert!(matches!(
+      resolve(&ipc, &mut folder, &dir).await,
+      Err(Error::Ipc { .. })
+    ));
+
+    let mut released = released.lock().unwrap().clone();
+    released.sort_unstable();
+    assert_eq!(released, vec![1, 2, 3]);
+
+    // The partially read file is removed
+    let tmp_files = std::fs::read_dir(&dir)
+      .unwrap()
+      .filter(|entry| {
+        let path = entry.as_ref().unwrap().path();
+        path.extension() == Some("tmp".as_ref())
+      })
+      .count();
+    assert_eq!(tmp_files, 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn removes_unreferenced_files() {
+    let dir = temp_dir("unreferenced");
+    std::fs::write(dir.join("kept"), b"kept").unwrap();
+    std::fs::write(dir.join("removed"), b"removed").unwrap();
+
+    let mut folder = VirtualFolder::new();
+    folder.insert("kept.mp4", LocalFile::new(dir.join("kept")));
+    let folder: Folder = folder.into();
+
+    let mut stale = VirtualFolder::new();
+    stale.insert("removed.mp4", LocalFile::new(dir.join("removed")));
+    let stale: Folder = stale.into();
+    assert!(is_complete(&stale));
+
+    remove_unreferenced(&dir, &folder).await.unwrap();
+    assert!(dir.join("kept").exists());
+    assert!(!dir.join("removed").exists());
+    assert!(is_complete(&folder));
+    assert!(!is_complete(&stale));
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn ignores_missing_directories() {
+    let dir = temp_dir("missing").join("linked");
+    let folder: Folder = VirtualFolder::new().into();
+    remove_unreferenced(&dir, &folder).await.unwrap();
+    std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+  }
+}
@@ -1,31 +1,48 @@
 //! Given a `drydoc.yaml` file, generate a website.
 
+mod cache;
+mod check;
+mod condition;
+mod config;
+mod error;
 mod fetch;
 mod resource;
 
-use std::path::PathBuf;
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  time::Duration,
+};
 
 use clap::Clap;
-use drydoc_model::{
-  bundle::Bundle,
-  decl::{Decl, Generate, Import},
-  ns::Namespace,
-};
+use drydoc_model::{bundle::Bundle, decl::Generate, ns::Namespace};
 
-use drydoc_pkg_manager::{Manager as PkgMgr, UrlFetcher, VersionReq};
+use drydoc_pkg_manager::{Manager as PkgMgr, UrlFetcher};
 mod actor;
 mod uri;
 
-use tokio::fs::File;
+use tokio::sync::Semaphore;
+
+use futures::future::{try_join, try_join_all};
 
 use actor::{Actor, Addr};
 use std::future::Future;
 use std::pin::Pin;
 mod emitter;
 mod generator_mgr;
+mod graph;
 mod ipc;
+mod linked;
+mod merge;
 mod preprocessor;
 mod progress;
+mod remote;
+mod schema;
+mod source;
+mod template;
+mod validate;
+mod value;
+mod watch;
 
 use generator_mgr::{GeneratorMgr, GeneratorMgrMsg};
 
@@ -33,98 +50,374 @@ use std::error::Error;
 
 use emitter::Emitter;
 
+use cache::{BuildCache, CacheKey, Memo};
+use config::{Node, Origin};
+use error::{DeclInfo, GenError, Location};
+use merge::{Generated, MergePolicy, Source};
+use resource::UriCache;
+
 #[macro_use]
 extern crate lazy_static;
 
-use std::sync::Arc;
+use std::sync::{
+  atomic::{AtomicU32, Ordering},
+  Arc,
+};
 
-/// Generate documentation from a drydoc.yaml file.
+/// Generate documentation from a drydoc.yaml, drydoc.toml or drydoc.json file.
 #[derive(Clap, Debug)]
 pub struct GenOpts {
   /// The configuration file to generate from
-  #[clap(short, long, default_value = "drydoc.yaml")]
+  #[clap(short, long, default_value = "drydoc.yaml", global = true)]
   config: String,
 
   /// Output directory
-  #[clap(short, long, default_value = "html")]
+  #[clap(short, long, default_value = "html", global = true)]
   output: String,
 
-  #[clap(long, default_value = "https://semio-ai.github.io/drydoc-packages")]
+  #[clap(
+    long,
+    default_value = "https://semio-ai.github.io/drydoc-packages",
+    global = true
+  )]
   repository_url: String,
 
-  #[clap(long)]
+  /// Additional repository that generators can be qualified with, as
+  /// `name=url` (e.g., `--repository acme=https://example.com/packages`
+  /// allows `using: acme/clang@^1.0`)
+  #[clap(
+    long = "repository",
+    value_name = "name=url",
+    number_of_values = 1,
+    parse(try_from_str = parse_repository),
+    global = true
+  )]
+  repositories: Vec<(String, String)>,
+
+  #[clap(long, global = true)]
   repository_dir: Option<String>,
+
+  /// Directory in which previously generated bundles are cached
+  #[clap(long, default_value = ".drydoc-cache", global = true)]
+  cache_dir: String,
+
+  /// Regenerate every decl, ignoring and not updating the cache
+  #[clap(long, global = true)]
+  no_cache: bool,
+
+  /// Maximum number of decls to generate concurrently (default: number of CPUs)
+  #[clap(short, long, parse(try_from_str = parse_jobs), global = true)]
+  jobs: Option<usize>,
+
+  /// What to do when decls generate pages or resources with the same name:
+  /// `error`, `warn` (keep the last) or `prefer-first`
+  #[clap(long, default_value = "warn", global = true)]
+  on_conflict: MergePolicy,
+
+  /// Kill `$(...)` substitutions in the configuration that take longer than
+  /// the given number of seconds
+  #[clap(long, value_name = "seconds", default_value = "30", global = true)]
+  command_timeout: u64,
+
+  /// Only allow `$(...)` substitutions to run the given program. May be given
+  /// multiple times. If not given, any program may be run, except by configs
+  /// imported by URI, which may run none.
+  #[clap(
+    long = "allow-command",
+    value_name = "program",
+    number_of_values = 1,
+    global = true
+  )]
+  allowed_commands: Vec<String>,
+
+  /// Select a profile, including the decls whose `when` condition requires
+  /// it (e.g., `--profile public`). May be given multiple times.
+  #[clap(
+    long = "profile",
+    value_name = "name",
+    number_of_values = 1,
+    global = true
+  )]
+  profiles: Vec<String>,
+
+  /// Print the graph of decls and the configuration files they're declared
+  /// in as `dot` (the default) or `json`, instead of generating anything
+  #[clap(
+    long,
+    value_name = "format",
+    min_values = 0,
+    require_equals = true,
+    default_missing_value = "dot",
+    global = true
+  )]
+  print_graph: Option<graph::GraphFormat>,
+
+  #[clap(subcommand)]
+  command: Option<Command>,
+}
+
+fn parse_repository(s: &str) -> Result<(String, String), String> {
+  match s.split_once('=') {
+    Some((name, url)) if !url.is_empty() => {
+      // Names are used in specs and as directory names
+      drydoc_model::decl::validate_name(name).map_err(|err| err.to_string())?;
+      Ok((name.to_string(), url.to_string()))
+    }
+    _ => Err(format!("Expected `name=url`, found `{}`", s)),
+  }
+}
+
+fn parse_jobs(s: &str) -> Result<usize, String> {
+  match s.parse::<usize>() {
+    Ok(0) => Err("Expected at least 1 job".to_string()),
+    Ok(jobs) => Ok(jobs),
+    Err(err) => Err(err.to_string()),
+  }
+}
+
+#[derive(Clap, Debug)]
+pub enum Command {
+  Check(check::CheckOpts),
+  Schema(schema::SchemaOpts),
+  Watch(watch::WatchOpts),
+}
+
+/// State shared by every decl being generated.
+#[derive(Clone)]
+struct GenCtx {
+  mgr: Addr<GeneratorMgrMsg>,
+  cache: Option<BuildCache>,
+  /// Limits how many generator requests may be outstanding at once.
+  jobs: Arc<Semaphore>,
+  merge_policy: MergePolicy,
+  preprocess: Arc<preprocessor::Options>,
+  /// Configuration files, and archives of them, imported by URI
+  resources: UriCache,
+  /// Where files generators link to are stored
+  linked_dir: PathBuf,
+  /// The id of the next generator context to open. Each decl is generated
+  /// in a context of its own, since decls are generated concurrently.
+  next_context: Arc<AtomicU32>,
+  /// The bundles of decls that haven't changed since they were last
+  /// generated, when watching
+  memo: Option<Memo>,
 }
 
 async fn gen_unit(
   config: Generate,
-  mgr: Addr<GeneratorMgrMsg>,
+  children: Vec<Node>,
+  ctx: GenCtx,
   namespace: Arc<Namespace>,
-  path: PathBuf,
-) -> Result<Bundle, Box<dyn Error>> {
-  let mut sub_bundles = Vec::new();
-
-  let child_ns = namespace.child(config.id);
-  if let Some(children) = config.children {
-    for child in children {
-      let path = path.clone();
-      sub_bundles.push(gen_decl(child, mgr.clone(), child_ns.clone(), path).await?);
-    }
-  }
+  origin: Origin,
+) -> Result<Generated, GenError> {
+  let child_ns = namespace.child(config.id.as_str());
+  let overrides = config.page.clone();
+
+  // Children are generated concurrently with each other and with this decl.
+  // `try_join_all` preserves the order of its inputs, so the merge below is
+  // deterministic regardless of which generator finishes first.
+  let sub_bundles = try_join_all(
+    children
+      .into_iter()
+      .map(|child| gen_decl(child, ctx.clone(), child_ns.clone())),
+  );
+
+  let decl = DeclInfo {
+    id: config.id.clone(),
+    path: child_ns.to_string(),
+    location: origin.location(),
+  };
 
-  lazy_static! {
-    static ref WILDCARD: VersionReq = VersionReq::parse("*").unwrap();
+  let source = Source {
+    decl: decl.clone(),
+    generator: config.using.to_string(),
+  };
+
+  let (mut bundle, sub_bundles) = try_join(
+    gen_cached(config, &ctx, namespace, &origin, &decl),
+    sub_bundles,
+  )
+  .await?;
+
+  // Overrides aren't part of the cache key, so they're applied to cached
+  // bundles too
+  let root = bundle.manifest.root.clone();
+  if let Some(page) = bundle.manifest.pages.get_mut(&root) {
+    overrides.apply(page);
   }
 
-  let parts = config.using.split("@").collect::<Vec<&str>>();
+  let mut generated = Generated::new(bundle, source);
+  for sub_bundle in sub_bundles.into_iter().flatten() {
+    generated = generated.merge(sub_bundle, ctx.merge_policy)?;
+  }
+  generated.bundle.manifest.sort_children(&root);
+  Ok(generated)
+}
 
-  let (name, version_req) = if parts.len() == 1 {
-    (parts[0], WILDCARD.clone())
-  } else if parts.len() == 2 {
-    (parts[1], VersionReq::parse(parts[2]).unwrap())
-  } else {
-    panic!("Invalid generator string")
+/// Generate the bundle for `config` alone (excluding its children), reusing
+/// the bundle last generated for it while watching, or a cached bundle, if
+/// one is available.
+async fn gen_cached(
+  config: Generate,
+  ctx: &GenCtx,
+  namespace: Arc<Namespace>,
+  origin: &Origin,
+  decl: &DeclInfo,
+) -> Result<Bundle, GenError> {
+  let memo = match &ctx.memo {
+    Some(memo) => memo,
+    None => return gen_stored(config, ctx, namespace, origin, decl).await,
   };
 
-  let ipc = mgr.get_or_start(name, version_req).await.unwrap();
+  // Forgotten by `watch` when the decl's inputs change
+  if let Some(bundle) = memo
+    .get(&decl.path)
+    .filter(|bundle| linked::is_complete(&bundle.resources))
+  {
+    return Ok(bundle);
+  }
+
+  let bundle = gen_stored(config, ctx, namespace, origin, decl).await?;
+  memo.put(decl.path.clone(), bundle.clone());
+  Ok(bundle)
+}
+
+/// Generate the bundle for `config` alone (excluding its children), reusing
+/// a bundle from the build cache if one is available.
+async fn gen_stored(
+  config: Generate,
+  ctx: &GenCtx,
+  namespace: Arc<Namespace>,
+  origin: &Origin,
+  decl: &DeclInfo,
+) -> Result<Bundle, GenError> {
+  let cache = match &ctx.cache {
+    Some(cache) => cache,
+    None => return gen_generator(config, ctx, namespace, origin, decl).await,
+  };
 
-  let path = path.to_str().unwrap().to_string();
-  let mut res = ipc
-    .generate(0, namespace.clone(), config.with, path)
-    .await?;
-  for sub_bundle in sub_bundles {
-    res.bundle = res.bundle.merge(sub_bundle).unwrap();
+  // Package specs match a range of versions, so bundles are cached under
+  // the version actually used
+  let generator = ctx
+    .mgr
+    .identify(origin.resolve_generator(&config.using))
+    .await
+    .map_err(|err| GenError::Generator {
+      decl: Box::new(decl.clone()),
+      generator: config.using.to_string(),
+      cause: Box::new(err),
+    })?;
+  let key = CacheKey::compute(
+    &config,
+    &generator,
+    &namespace,
+    &origin.file,
+    &cache.ignored(),
+  )
+  .await?;
+  if let Some(bundle) = cache.get(&key).await {
+    info!("{} is up to date", decl.path);
+    return Ok(bundle);
   }
-  Ok(res.bundle)
+
+  let bundle = gen_generator(config, ctx, namespace, origin, decl).await?;
+  cache.put(&key, &bundle).await?;
+  Ok(bundle)
 }
 
-use tokio::io::AsyncReadExt;
+/// Invoke the generator named by `config.using`.
+async fn gen_generator(
+  config: Generate,
+  ctx: &GenCtx,
+  namespace: Arc<Namespace>,
+  origin: &Origin,
+  decl: &DeclInfo,
+) -> Result<Bundle, GenError> {
+  let spec = origin.resolve_generator(&config.using);
+
+  let generator_error = |cause: Box<dyn Error>| GenError::Generator {
+    decl: Box::new(decl.clone()),
+    generator: config.using.to_string(),
+    cause,
+  };
+
+  let mut with = config.with.clone();
+  if let Some(parameters) = ctx
+    .mgr
+    .get_parameters(spec.clone())
+    .await
+    .map_err(|err| generator_error(Box::new(err)))?
+  {
+    validate::apply_defaults(&parameters, &mut with);
+  }
+
+  let ipc = ctx
+    .mgr
+    .get_or_start(spec)
+    .await
+    .map_err(|err| generator_error(Box::new(err)))?;
+
+  let _permit = ctx
+    .jobs
+    .acquire()
+    .await
+    .expect("The job semaphore is never closed");
+
+  let context = ctx.next_context.fetch_add(1, Ordering::Relaxed);
+  ipc
+    .open_context(context)
+    .await
+    .map_err(|err| generator_error(Box::new(err)))?;
+  let path = origin.file.to_string_lossy().to_string();
+  let generated = ipc.generate(context, namespace, with, path).await;
+  // Closed even if generation failed, so the generator can clean up
+  let closed = ipc.close_context(context).await;
+  let mut bundle = generated
+    .map_err(|err| generator_error(Box::new(err)))?
+    .bundle;
+  // Generators may return what they deferred until the context closed
+  if let Some(rest) = closed.map_err(|err| generator_error(Box::new(err)))?.bundle {
+    bundle = bundle
+      .merge(rest)
+      .map_err(|err| generator_error(Box::new(err)))?;
+  }
+  linked::resolve(&ipc, &mut bundle.resources, &ctx.linked_dir)
+    .await
+    .map_err(|err| generator_error(Box::new(err)))?;
+  Ok(bundle)
+}
 
+/// Generate `node`, returning a bundle for each decl it resolves to (an
+/// import may import any number of files).
 fn gen_decl(
-  decl: Decl,
-  mgr: Addr<GeneratorMgrMsg>,
+  node: Node,
+  ctx: GenCtx,
   namespace: Arc<Namespace>,
-  decl_path: PathBuf,
-) -> Pin<Box<dyn Future<Output = Result<Bundle, Box<dyn Error>>>>> {
+) -> Pin<Box<dyn Future<Output = Result<Vec<Generated>, GenError>>>> {
   Box::pin(async move {
-    match decl {
-      Decl::Import(Import { path }) => {
-        let mut abs_path = PathBuf::new();
-        abs_path.push(decl_path.parent().unwrap());
-        abs_path.push(&path);
-        let mut file = File::open(&abs_path).await?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).await?;
-        let config: Decl = serde_yaml::from_str(contents.as_str()).unwrap();
-        Ok(gen_decl(config, mgr.clone(), namespace, abs_path).await?)
+    match node {
+      Node::Import { imported, .. } => {
+        let generated = try_join_all(
+          imported
+            .into_iter()
+            .map(|node| gen_decl(node, ctx.clone(), namespace.clone())),
+        )
+        .await?;
+        Ok(generated.into_iter().flatten().collect())
       }
-      Decl::Generate(generate) => Ok(gen_unit(generate, mgr.clone(), namespace, decl_path).await?),
+      Node::Generate {
+        generate,
+        origin,
+        children,
+      } => Ok(vec![
+        gen_unit(*generate, children, ctx, namespace, origin).await?,
+      ]),
     }
   })
 }
 
 use colored::*;
-use log::info;
+use log::{info, warn};
 
 struct Logger {
   level: log::Level,
@@ -148,14 +441,56 @@ impl log::Log for Logger {
   fn flush(&self) {}
 }
 
-async fn gen() -> Result<(), Box<dyn std::error::Error>> {
-  let opts = GenOpts::parse();
-  let contents = tokio::fs::read_to_string(&opts.config).await?;
+/// Generate the website described by `opts.config` and write it to
+/// `opts.output`.
+async fn build(opts: &GenOpts, ctx: &GenCtx) -> Result<(), GenError> {
+  let (root, errors) = config::load_tree(&opts.config, &ctx.preprocess, &ctx.resources).await?;
+  build_tree(opts, ctx, root, errors).await
+}
 
-  let raw_config: serde_yaml::Value = serde_yaml::from_str(contents.as_str())?;
-  let decl: Decl = serde_yaml::from_value(
-    preprocessor::preprocess(raw_config, Arc::new(std::env::current_dir()?)).await?,
-  )?;
+/// Generate the website from the decl tree `root`, loaded from
+/// `opts.config` with `errors`, and write it to `opts.output`.
+async fn build_tree(
+  opts: &GenOpts,
+  ctx: &GenCtx,
+  root: Node,
+  errors: Vec<GenError>,
+) -> Result<(), GenError> {
+  // Find every problem with the configuration before generating anything
+  validate::validate(&root, errors, &ctx.mgr).await?;
+
+  // The root is only generated into several bundles if it's a glob import
+  let location = root.origin().location();
+  let mut generated = gen_decl(root, ctx.clone(), Namespace::new("root"))
+    .await?
+    .into_iter();
+  let mut merged = generated
+    .next()
+    .ok_or_else(|| GenError::config(location, "The root import didn't import anything"))?;
+  for next in generated {
+    merged = merged.merge(next, ctx.merge_policy)?;
+  }
+  let mut bundle = merged.bundle;
+  let root = bundle.manifest.root.clone();
+  bundle.manifest.sort_children(&root);
+
+  // Cached bundles referring to removed files are regenerated if needed
+  if let Err(err) = linked::remove_unreferenced(&ctx.linked_dir, &bundle.resources).await {
+    warn!("Unable to clean up linked files: {}", err);
+  }
+
+  let emitter = emitter::html::Html::new(&opts.output);
+  emitter
+    .emit(bundle)
+    .await
+    .map_err(|err| GenError::io(&opts.output, err))?;
+
+  Ok(())
+}
+
+async fn gen() -> Result<(), GenError> {
+  let mut opts = GenOpts::parse();
+  opts.config = config::find_root(opts.config.as_str());
 
   log::set_logger(&Logger {
     level: log::Level::Debug,
@@ -164,32 +499,159 @@ async fn gen() -> Result<(), Box<dyn std::error::Error>> {
 
   log::set_max_level(log::LevelFilter::Debug);
 
+  let repository_dir = match &opts.repository_dir {
+    Some(repository_dir) => PathBuf::from(repository_dir),
+    None => {
+      let mut repository_dir = dirs::home_dir().ok_or_else(|| {
+        GenError::config(
+          Location::file(&opts.config),
+          "Unable to determine the home directory. Please specify --repository-dir.",
+        )
+      })?;
+      repository_dir.push(".drydoc");
+      repository_dir.push("repository");
+      repository_dir
+    }
+  };
+
   let pkg_mgr = PkgMgr::new(
-    UrlFetcher::new(opts.repository_url),
-    &opts.repository_dir.unwrap(),
+    UrlFetcher::new(opts.repository_url.clone()),
+    &repository_dir,
   );
-  let gen_mgr = GeneratorMgr::new(pkg_mgr).spawn();
 
-  let bundle = gen_decl(
-    decl,
-    gen_mgr,
-    Namespace::new("root"),
-    PathBuf::from(opts.config.as_str()),
-  )
-  .await?;
+  // Each additional repository gets its own directory, apart from the
+  // packages of the default repository, so packages of the same name from
+  // different repositories don't collide.
+  let repositories: HashMap<String, PkgMgr<UrlFetcher>> = opts
+    .repositories
+    .iter()
+    .map(|(name, url)| {
+      let pkg_mgr = PkgMgr::new(
+        UrlFetcher::new(url.clone()),
+        repository_dir.join("repositories").join(name),
+      );
+      (name.clone(), pkg_mgr)
+    })
+    .collect();
 
-  let emitter = emitter::html::Html::new(opts.output);
-  emitter.emit(bundle).await?;
+  if let Some(Command::Schema(_)) = &opts.command {
+    return schema::print(&pkg_mgr, &repositories);
+  }
 
-  Ok(())
+  // Generators may only open files below the root configuration file.
+  // Canonicalized, as the paths they open are.
+  let root = std::env::current_dir()?.join(
+    Path::new(opts.config.as_str())
+      .parent()
+      .unwrap_or_else(|| Path::new("")),
+  );
+  let root = std::fs::canonicalize(&root).unwrap_or(root);
+  let gen_mgr = GeneratorMgr::new(pkg_mgr, repositories, root).spawn();
+
+  let cache = if opts.no_cache {
+    None
+  } else {
+    Some(BuildCache::new(&opts.cache_dir, &opts.output))
+  };
+
+  let jobs = match opts.jobs {
+    Some(jobs) => jobs,
+    None => std::thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+  };
+
+  let preprocess = preprocessor::Options {
+    conditions: condition::Context {
+      profiles: opts.profiles.iter().cloned().collect(),
+    },
+    working_dir: std::env::current_dir()?,
+    timeout: Duration::from_secs(opts.command_timeout),
+    allowed: if opts.allowed_commands.is_empty() {
+      None
+    } else {
+      Some(opts.allowed_commands.iter().cloned().collect())
+    },
+  };
+
+  let ctx = GenCtx {
+    mgr: gen_mgr,
+    cache,
+    jobs: Arc::new(Semaphore::new(jobs)),
+    merge_policy: opts.on_conflict,
+    preprocess: Arc::new(preprocess),
+    resources: UriCache::default(),
+    // Kept with the cache, as cached bundles refer to them
+    linked_dir: Path::new(opts.cache_dir.as_str()).join("linked"),
+    next_context: Arc::new(AtomicU32::new(0)),
+    memo: None,
+  };
+
+  if let Some(format) = opts.print_graph {
+    return graph::print(&opts, format, &ctx).await;
+  }
+
+  match &opts.command {
+    None => build(&opts, &ctx).await,
+    Some(Command::Check(check_opts)) => check::check(&opts, check_opts, &ctx).await,
+    // Printed before the package managers are handed to the generator
+    // manager
+    Some(Command::Schema(_)) => unreachable!(),
+    Some(Command::Watch(watch_opts)) => watch::watch(&opts, watch_opts, &ctx).await,
+  }
+}
+
+/// Print `err` and its causes as a diagnostic.
+fn report(err: &GenError) {
+  if let GenError::Many(errors) = err {
+    for err in errors {
+      report(err);
+      eprintln!();
+    }
+    eprintln!("{}: {}", "error".red().bold(), err);
+    return;
+  }
+
+  eprintln!("{}: {}", "error".red().bold(), err);
+  if let Some(location) = err.location() {
+    eprintln!("  {} {}", "-->".blue(), location);
+
+    if let (Some(mark), Some(excerpt)) = (location.mark, &location.excerpt) {
+      let gutter = mark.line.to_string();
+      let padding = " ".repeat(gutter.len());
+      eprintln!("  {} {}", padding, "|".blue());
+      eprintln!("  {} {} {}", gutter.blue(), "|".blue(), excerpt);
+      eprintln!(
+        "  {} {} {}{}",
+        padding,
+        "|".blue(),
+        " ".repeat(mark.col.saturating_sub(1)),
+        "^".red().bold()
+      );
+    }
+
+    for include in &location.included_from {
+      eprintln!("  {} included from {}", "=".blue(), include);
+    }
+  }
+
+  // Some errors (e.g., serde_yaml's) repeat themselves as their source
+  let mut last = err.to_string();
+  let mut cause = err.source();
+  while let Some(err) = cause {
+    let message = err.to_string();
+    if message != last {
+      eprintln!("  {} {}", "caused by:".blue(), message);
+    }
+    last = message;
+    cause = err.source();
+  }
 }
 
 #[tokio::main]
 async fn main() {
   match gen().await {
     Err(err) => {
-      eprintln!("ERROR: {:?}", err.source());
-      std::process::exit(1);
+      report(&err);
+      std::process::exit(err.exit_code());
     }
     _ => {
       std::process::exit(0);
@@ -0,0 +1,350 @@
+//! Merging of the bundles generated by different decls.
+
+use std::{
+  collections::HashMap,
+  fmt::{Display, Formatter},
+  str::FromStr,
+  sync::Arc,
+};
+
+use drydoc_model::{
+  bundle::{Bundle, Keep},
+  fs::{Entry, Folder},
+  page::Id,
+};
+use log::warn;
+
+use crate::error::{DeclInfo, GenError};
+
+/// What to do when two decls generate a page or resource with the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+  /// Fail the build.
+  Error,
+  /// Keep the one generated last, with a warning.
+  Warn,
+  /// Silently keep the one generated first.
+  PreferFirst,
+}
+
+impl FromStr for MergePolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "error" => Ok(Self::Error),
+      "warn" => Ok(Self::Warn),
+      "prefer-first" => Ok(Self::PreferFirst),
+      _ => Err(format!(
+        "Unknown policy `{}` (expected `error`, `warn` or `prefer-first`)",
+        s
+      )),
+    }
+  }
+}
+
+/// The decl and generator that generated part of a bundle.
+#[derive(Debug)]
+pub struct Source {
+  pub decl: DeclInfo,
+  pub generator: String,
+}
+
+impl Display for Source {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "`{}` (using `{}`, at {})",
+      self.decl.path, self.generator, self.decl.location
+    )
+  }
+}
+
+/// Which decl generated each page and resource of a bundle.
+struct Provenance {
+  /// The source of the decl the bundle was generated for (excluding its
+  /// descendants)
+  root: Arc<Source>,
+  pages: HashMap<Id, Arc<Source>>,
+  /// Keyed by `/`-separated path. Entries within local folders aren't
+  /// listed, so look up a resource's ancestors as well.
+  resources: HashMap<String, Arc<Source>>,
+}
+
+fn collect_resources(
+  folder: &Folder,
+  prefix: &str,
+  source: &Arc<Source>,
+  resources: &mut HashMap<String, Arc<Source>>,
+) {
+  let folder = match folder {
+    Folder::Virtual(folder) => folder,
+    Folder::Local(_) => return,
+  };
+
+  for (name, entry) in folder.iter() {
+    let path = format!("{}{}", prefix, name);
+    if let Entry::Folder(folder) = entry {
+      collect_resources(folder, format!("{}/", path).as_str(), source, resources);
+    }
+    resources.insert(path, source.clone());
+  }
+}
+
+impl Provenance {
+  fn new(bundle: &Bundle, source: Source) -> Self {
+    let source = Arc::new(source);
+
+    let pages = bundle
+      .manifest
+      .pages
+      .keys()
+      .map(|id| (id.clone(), source.clone()))
+      .collect();
+
+    let mut resources = HashMap::new();
+    collect_resources(&bundle.resources, "", &source, &mut resources);
+
+    Self {
+      root: source,
+      pages,
+      resources,
+    }
+  }
+
+  fn page_source(&self, id: &Id) -> &Arc<Source> {
+    self.pages.get(id).unwrap_or(&self.root)
+  }
+
+  /// The source of the resource at `path`, or of its closest listed
+  /// ancestor.
+  fn resource_source(&self, path: &str) -> &Arc<Source> {
+    let mut path = path;
+    loop {
+      if let Some(source) = self.resources.get(path) {
+        return source;
+      }
+
+      path = match path.rfind('/') {
+        Some(end) => &path[..end],
+        None => return &self.root,
+      };
+    }
+  }
+
+  /// Record the sources of a bundle merged into this one.
+  fn absorb(&mut self, other: Provenance, keep: Keep) {
+    for (id, source) in other.pages {
+      if keep == Keep::Second || !self.pages.contains_key(&id) {
+        self.pages.insert(id, source);
+      }
+    }
+
+    for (path, source) in other.resources {
+      if keep == Keep::Second || !self.resources.contains_key(&path) {
+        self.resources.insert(path, source);
+      }
+    }
+  }
+}
+
+/// A bundle, along with the source of each of its pages and resources.
+pub struct Generated {
+  pub bundle: Bundle,
+  provenance: Provenance,
+}
+
+impl Generated {
+  /// `bundle`, generated entirely by `source`.
+  pub fn new(bundle: Bundle, source: Source) -> Self {
+    let provenance = Provenance::new(&bundle, source);
+    Self { bundle, provenance }
+  }
+
+  /// Merge `other` into this bundle, resolving conflicts according to
+  /// `policy`.
+  pub fn merge(self, other: Generated, policy: MergePolicy) -> Result<Self, GenError> {
+    let keep = match policy {
+      MergePolicy::PreferFirst => Keep::First,
+      MergePolicy::Error | MergePolicy::Warn => Keep::Second,
+    };
+
+    let (bundle, conflicts) = self
+      .bundle
+      .merge_reporting(other.bundle, keep)
+      .map_err(|err| GenError::Io {
+        path: None,
+        cause: Box::new(err),
+      })?;
+
+    let mut errors = Vec::new();
+    let mut conflict = |what: String, first: &Arc<Source>, second: &Arc<Source>| match policy {
+      MergePolicy::Error => errors.push(GenError::config(
+        second.decl.location.clone(),
+        format!("{} is generated by both {} and {}", what, first, second),
+      )),
+      MergePolicy::Warn => warn!(
+        "{} is generated by both {} and {}. Keeping the latter.",
+        what, first, second
+      ),
+      MergePolicy::PreferFirst => {}
+    };
+
+    let mut provenance = self.provenance;
+    for id in conflicts.pages.iter() {
+      conflict(
+        format!("Page `{}`", id),
+        provenance.page_source(id),
+        other.provenance.page_source(id),
+      );
+    }
+
+    for path in conflicts.resources.iter() {
+      conflict(
+        format!("Resource `{}`", path),
+        provenance.resource_source(path),
+        other.provenance.resource_source(path),
+      );
+    }
+
+    match errors.len() {
+      0 => {}
+      1 => return Err(errors.remove(0)),
+      _ => return Err(GenError::Many(errors)),
+    }
+
+    provenance.absorb(other.provenance, keep);
+    Ok(Self { bundle, provenance })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use drydoc_model::{
+    bundle::Manifest,
+    fs::{File, VirtualFile, VirtualFolder},
+    page::Page,
+  };
+
+  use crate::error::Location;
+
+  /// A bundle generated by the decl `decl`, with a root page named `root`
+  /// generated with `content_type`, and the virtual files `files`.
+  fn generated(decl: &str, root: &str, content_type: &str, files: &[(&str, &str)]) -> Generated {
+    let page = Page::builder()
+      .id(root)
+      .name(root)
+      .content_type(content_type)
+      .build()
+      .unwrap();
+
+    let mut resources = VirtualFolder::new();
+    for (name, contents) in files {
+      resources.insert(*name, VirtualFile::new(contents.as_bytes().to_vec()));
+    }
+
+    let bundle = Bundle {
+      manifest: Manifest {
+        root: page.id.clone(),
+        symbols: HashMap::new(),
+        pages: vec![(page.id.clone(), page)].into_iter().collect(),
+      },
+      resources: resources.into(),
+    };
+    let source = Source {
+      decl: DeclInfo {
+        id: decl.to_string(),
+        path: format!("root/{}", decl),
+        location: Location::file("drydoc.yaml"),
+      },
+      generator: "copy".to_string(),
+    };
+    Generated::new(bundle, source)
+  }
+
+  fn content_type(generated: &Generated, id: &str) -> String {
+    generated.bundle.manifest.pages[&Id::from(id)]
+      .content_type
+      .clone()
+  }
+
+  fn file(generated: &mut Generated, name: &str) -> Vec<u8> {
+    match &mut generated.bundle.resources {
+      Folder::Virtual(folder) => match folder.get(name) {
+        Some(Entry::File(File::Virtual(file))) => file.content().to_vec(),
+        entry => panic!("Unexpected entry {:?}", entry),
+      },
+      Folder::Local(_) => panic!("Unexpected local folder"),
+    }
+  }
+
+  #[test]
+  fn merges_without_conflicts() {
+    for policy in &[
+      MergePolicy::Error,
+      MergePolicy::Warn,
+      MergePolicy::PreferFirst,
+    ] {
+      let first = generated("a", "a", "text/a", &[("a.css", "a")]);
+      let second = generated("b", "b", "text/b", &[("b.css", "b")]);
+
+      let mut merged = first.merge(second, *policy).unwrap();
+      assert_eq!(content_type(&merged, "a"), "text/a");
+      assert_eq!(content_type(&merged, "b"), "text/b");
+      assert_eq!(file(&mut merged, "a.css"), b"a");
+      assert_eq!(file(&mut merged, "b.css"), b"b");
+    }
+  }
+
+  #[test]
+  fn warn_keeps_second() {
+    let first = generated("a", "page", "text/a", &[("style.css", "a")]);
+    let second = generated("b", "page", "text/b", &[("style.css", "b")]);
+
+    let mut merged = first.merge(second, MergePolicy::Warn).unwrap();
+    assert_eq!(content_type(&merged, "page"), "text/b");
+    assert_eq!(file(&mut merged, "style.css"), b"b");
+  }
+
+  #[test]
+  fn prefer_first_keeps_first() {
+    let first = generated("a", "page", "text/a", &[("style.css", "a")]);
+    let second = generated("b", "page", "text/b", &[("style.css", "b")]);
+
+    let mut merged = first.merge(second, MergePolicy::PreferFirst).unwrap();
+    assert_eq!(content_type(&merged, "page"), "text/a");
+    assert_eq!(file(&mut merged, "style.css"), b"a");
+  }
+
+  #[test]
+  fn error_reports_every_conflict() {
+    let first = generated("a", "page", "text/a", &[("style.css", "a")]);
+    let second = generated("b", "page", "text/b", &[("style.css", "b")]);
+
+    let errors = match first.merge(second, MergePolicy::Error) {
+      Err(GenError::Many(errors)) => errors,
+      _ => panic!("Expected several errors"),
+    };
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].starts_with("Page `page` is generated by both `root/a`"));
+    assert!(messages[1].starts_with("Resource `style.css` is generated by both `root/a`"));
+  }
+
+  #[test]
+  fn reports_source_of_merged_items() {
+    let first = generated("a", "a", "text/a", &[]);
+    let second = generated("b", "b", "text/b", &[("style.css", "b")]);
+    let third = generated("c", "c", "text/c", &[("style.css", "c")]);
+
+    let merged = first.merge(second, MergePolicy::Error).unwrap();
+    let err = match merged.merge(third, MergePolicy::Error) {
+      Err(err) => err.to_string(),
+      Ok(_) => panic!("Expected a conflict"),
+    };
+    assert!(err.starts_with("Resource `style.css` is generated by both `root/b`"));
+    assert!(err.contains("and `root/c`"));
+  }
+}
@@ -1,107 +1,1188 @@
-use serde_yaml::{Mapping, Sequence, Value};
+//! Substitution of `${variable}` and `$(command)` expressions in
+//! configuration strings.
+//!
+//! `${env:NAME}` is replaced with the environment variable `NAME`, and
+//! `${name}` with the variable `name` declared by the `vars` field of the
+//! decl or one of its ancestors (including the decls through which the file
+//! was imported). Either may be followed by `:-default`, which is used if the
+//! variable isn't set.
+//!
+//! Commands are run directly rather than through a shell, so they behave
+//! the same on every platform and can't be used to run arbitrary shell code.
+//! Arguments are split on whitespace, and may be quoted with `'...'` or
+//! `"..."` or escaped with `\`. Commands may be piped into each other with
+//! `|`. `$$` is replaced with a literal `$`.
+//!
+//! A command is split into arguments before the expressions within it are
+//! substituted, so a value becomes part of the argument it appears in, and is
+//! never split or interpreted itself (e.g., a `|` in a variable doesn't start
+//! another command). Expressions within `'...'` aren't substituted.
 
-use tokio::io::Result;
+use std::{
+  collections::{HashMap, HashSet},
+  future::Future,
+  iter::Peekable,
+  path::{Path, PathBuf},
+  pin::Pin,
+  process::Stdio,
+  sync::Arc,
+  time::Duration,
+};
 
-use std::future::Future;
-use std::pin::Pin;
+use derive_more::{Display, Error};
+use futures::future::{join, join_all};
+use tokio::process::Command;
 
-use io::ErrorKind;
-use std::io;
+use crate::{
+  condition,
+  template::{self, Templates},
+  value::{Mapping, Sequence, Value},
+};
 
-use std::process::{Command, Stdio};
+/// The variables in scope at a decl, by name.
+pub type Vars = HashMap<String, String>;
 
-use std::path::PathBuf;
+/// How configuration files are preprocessed.
+#[derive(Debug, Clone)]
+pub struct Options {
+  /// What `when` conditions are evaluated against
+  pub conditions: condition::Context,
+  /// The directory commands are run in
+  pub working_dir: PathBuf,
+  /// How long a single substitution (including every command in its
+  /// pipeline) may run before it's killed
+  pub timeout: Duration,
+  /// The only programs that may be run, if set. Programs named with a path
+  /// must be listed with the same path.
+  pub allowed: Option<HashSet<String>>,
+}
 
-use std::sync::Arc;
+impl Options {
+  /// The options for configs that aren't trusted to run any program, which
+  /// may only run those explicitly allowed.
+  pub fn untrusted(self: &Arc<Self>) -> Arc<Self> {
+    match self.allowed {
+      Some(_) => self.clone(),
+      None => Arc::new(Self {
+        allowed: Some(HashSet::new()),
+        ..Self::clone(self)
+      }),
+    }
+  }
+}
 
-lazy_static! {
-  pub static ref CMD_REGEX: regex::Regex = regex::Regex::new(r"\$\(.*\)").unwrap();
+#[derive(Debug, Display, Error)]
+pub enum SubstitutionError {
+  #[display(fmt = "Invalid substitution: {}", message)]
+  Syntax {
+    #[error(not(source))]
+    message: String,
+  },
+  #[display(fmt = "`{}` not found", program)]
+  NotFound {
+    #[error(not(source))]
+    program: String,
+  },
+  #[display(fmt = "`{}` is not an allowed command (see --allow-command)", program)]
+  NotAllowed {
+    #[error(not(source))]
+    program: String,
+  },
+  #[display(fmt = "Unable to run `{}`", program)]
+  Spawn {
+    program: String,
+    source: std::io::Error,
+  },
+  #[display(fmt = "`{}` exited with {}{}", command, status, stderr)]
+  Failed {
+    command: String,
+    status: String,
+    /// The command's standard error, prefixed with `: `, or empty
+    stderr: String,
+  },
+  #[display(fmt = "`{}` timed out after {} seconds", command, seconds)]
+  TimedOut { command: String, seconds: u64 },
+  #[display(fmt = "Undefined variable `{}`", name)]
+  Undefined {
+    #[error(not(source))]
+    name: String,
+  },
+  #[display(fmt = "Environment variable `{}` is not set", name)]
+  EnvNotSet {
+    #[error(not(source))]
+    name: String,
+  },
+  #[display(fmt = "Unknown template `{}`", name)]
+  UnknownTemplate {
+    #[error(not(source))]
+    name: String,
+  },
+  /// A `vars`, `templates` or `extends` field is invalid.
+  #[display(fmt = "{}", message)]
+  Invalid {
+    #[error(not(source))]
+    message: String,
+  },
+  #[display(fmt = "`{}` didn't output valid UTF-8", command)]
+  InvalidOutput {
+    #[error(not(source))]
+    command: String,
+  },
 }
 
-fn which(name: &str) -> Result<PathBuf> {
-  let sys_path = std::env::var("PATH").unwrap();
-  let sys_paths: Vec<&str> = sys_path
-    .split(|c| c == if cfg!(windows) { ';' } else { ':' })
-    .collect();
+/// A substitution that failed, and where in the document it was.
+#[derive(Debug, Display, Error)]
+#[display(fmt = "{}", source)]
+pub struct PreprocessError {
+  /// Path of the string within the document (e.g., `/children/0/with/path`)
+  pub pointer: String,
+  pub source: SubstitutionError,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+  Literal(String),
+  /// The contents of `${...}`
+  Variable(String),
+  /// The contents of `$(...)`
+  Command(String),
+}
+
+type Chars<'a> = Peekable<std::str::Chars<'a>>;
+
+fn syntax<M: Into<String>>(message: M) -> SubstitutionError {
+  SubstitutionError::Syntax {
+    message: message.into(),
+  }
+}
+
+/// Read the contents of `${...}`, after the `${`.
+fn read_variable(chars: &mut Chars) -> Result<String, SubstitutionError> {
+  let mut expr = String::new();
+  loop {
+    match chars.next() {
+      Some('}') => return Ok(expr),
+      Some(c) => expr.push(c),
+      None => return Err(syntax(format!("Unterminated `${{{}`", expr))),
+    }
+  }
+}
 
-  for sys_path in sys_paths {
-    for entry in std::fs::read_dir(sys_path)? {
-      let entry = entry?;
-      let path = entry.path();
-      let file_name = path.file_stem().unwrap().to_str().unwrap().to_string();
-      if file_name == name {
-        return Ok(path.to_path_buf());
+/// Read the contents of `$(...)`, after the `$(`. Parentheses within quotes
+/// don't need to be balanced.
+fn read_command(chars: &mut Chars) -> Result<String, SubstitutionError> {
+  let mut command = String::new();
+  let mut depth = 0;
+  let mut quote = None;
+  loop {
+    let c = chars
+      .next()
+      .ok_or_else(|| syntax(format!("Unterminated `$({}`", command)))?;
+    match (quote, c) {
+      (None, ')') if depth == 0 => return Ok(command),
+      (None, '(') => depth += 1,
+      (None, ')') => depth -= 1,
+      (None, '\'') | (None, '"') => quote = Some(c),
+      (Some(q), c) if q == c => quote = None,
+      (Some('\''), _) => {}
+      (_, '\\') => {
+        command.push(c);
+        if let Some(c) = chars.next() {
+          command.push(c);
+        }
+        continue;
       }
+      _ => {}
     }
+    command.push(c);
   }
+}
 
-  Err(io::Error::new(
-    ErrorKind::NotFound,
-    format!("{} not found in PATH", name),
-  ))
+/// Read the expression starting with the `$` just read, if any. `$$` is read
+/// as a literal `$`.
+fn read_expression(chars: &mut Chars) -> Result<Option<Segment>, SubstitutionError> {
+  match chars.peek() {
+    Some('$') => {
+      chars.next();
+      Ok(Some(Segment::Literal("$".to_string())))
+    }
+    Some('{') => {
+      chars.next();
+      Ok(Some(Segment::Variable(read_variable(chars)?)))
+    }
+    Some('(') => {
+      chars.next();
+      Ok(Some(Segment::Command(read_command(chars)?)))
+    }
+    _ => Ok(None),
+  }
 }
 
-// FIXME: This should be async, but the regex replace_all function isn't.
-// Write an async-compat replace_all.
-fn execute(cmd: &str, working_dir: Arc<PathBuf>) -> Result<String> {
-  let args: Vec<&str> = cmd.split_ascii_whitespace().collect();
+/// Literal text and expressions, merging adjacent literals.
+#[derive(Debug, Default)]
+struct Segments {
+  segments: Vec<Segment>,
+  literal: String,
+}
 
-  let (name, rest) = match args.split_first() {
-    None => {
-      return Err(io::Error::new(ErrorKind::InvalidInput, "Empty command"));
+impl Segments {
+  fn push(&mut self, segment: Segment) {
+    match segment {
+      Segment::Literal(literal) => self.literal.push_str(literal.as_str()),
+      segment => {
+        if !self.literal.is_empty() {
+          self
+            .segments
+            .push(Segment::Literal(std::mem::take(&mut self.literal)));
+        }
+        self.segments.push(segment);
+      }
     }
-    Some(x) => x,
+  }
+
+  fn push_char(&mut self, c: char) {
+    self.literal.push(c);
+  }
+
+  fn finish(mut self) -> Vec<Segment> {
+    if !self.literal.is_empty() {
+      self.segments.push(Segment::Literal(self.literal));
+    }
+    self.segments
+  }
+}
+
+/// Split `string` into literal text and the contents of `${...}` and
+/// `$(...)` expressions.
+fn scan(string: &str) -> Result<Vec<Segment>, SubstitutionError> {
+  let mut segments = Segments::default();
+  let mut chars = string.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '$' => match read_expression(&mut chars)? {
+        Some(segment) => segments.push(segment),
+        None => segments.push_char(c),
+      },
+      c => segments.push_char(c),
+    }
+  }
+
+  Ok(segments.finish())
+}
+
+/// Split `command` into a pipeline of argument lists. Expressions are kept
+/// within the argument they appear in, so that their values are never split
+/// or interpreted. Text quoted with `'...'` isn't substituted.
+fn tokenize(command: &str) -> Result<Vec<Vec<Vec<Segment>>>, SubstitutionError> {
+  let mut pipeline = Vec::new();
+  let mut args = Vec::new();
+  // `None` between arguments, so that `''` still produces an argument
+  let mut arg: Option<Segments> = None;
+  let mut chars = command.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '\'' => {
+        let arg = arg.get_or_insert_with(Segments::default);
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => arg.push_char(c),
+            None => return Err(syntax("Unterminated `'`")),
+          }
+        }
+      }
+      '"' => {
+        let arg = arg.get_or_insert_with(Segments::default);
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+              Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') => arg.push_char(c),
+              Some(c) => {
+                arg.push_char('\\');
+                arg.push_char(c);
+              }
+              None => return Err(syntax("Unterminated `\"`")),
+            },
+            Some('$') => match read_expression(&mut chars)? {
+              Some(segment) => arg.push(segment),
+              None => arg.push_char('$'),
+            },
+            Some(c) => arg.push_char(c),
+            None => return Err(syntax("Unterminated `\"`")),
+          }
+        }
+      }
+      '\\' => match chars.next() {
+        Some(c) => arg.get_or_insert_with(Segments::default).push_char(c),
+        None => return Err(syntax("Trailing `\\`")),
+      },
+      '$' => {
+        let arg = arg.get_or_insert_with(Segments::default);
+        match read_expression(&mut chars)? {
+          Some(segment) => arg.push(segment),
+          None => arg.push_char(c),
+        }
+      }
+      '|' => {
+        args.extend(arg.take().map(Segments::finish));
+        if args.is_empty() {
+          return Err(syntax("Empty command in pipeline"));
+        }
+        pipeline.push(std::mem::take(&mut args));
+      }
+      c if c.is_whitespace() => args.extend(arg.take().map(Segments::finish)),
+      c => arg.get_or_insert_with(Segments::default).push_char(c),
+    }
+  }
+
+  args.extend(arg.take().map(Segments::finish));
+  if args.is_empty() {
+    return Err(syntax(if pipeline.is_empty() {
+      "Empty command"
+    } else {
+      "Empty command in pipeline"
+    }));
+  }
+  pipeline.push(args);
+  Ok(pipeline)
+}
+
+/// Find the executable `name`, either in `PATH` or, if it contains a path
+/// separator, relative to `working_dir`.
+fn which(name: &str, working_dir: &Path) -> Option<PathBuf> {
+  let is_path = name.contains('/') || (cfg!(windows) && name.contains('\\'));
+  if is_path {
+    let path = working_dir.join(name);
+    return if path.is_file() { Some(path) } else { None };
+  }
+
+  let extensions: &[&str] = if cfg!(windows) {
+    &["exe", "cmd", "bat", ""]
+  } else {
+    &[""]
   };
 
-  let bin = which(name)?;
+  let sys_path = std::env::var_os("PATH")?;
+  for dir in std::env::split_paths(&sys_path) {
+    for extension in extensions {
+      let mut path = dir.join(name);
+      if !extension.is_empty() {
+        path.set_extension(extension);
+      }
+      if path.is_file() {
+        return Some(path);
+      }
+    }
+  }
+
+  None
+}
+
+/// Run the substituted `pipeline` of `command` and return its trimmed
+/// standard output.
+async fn execute(
+  command: &str,
+  pipeline: &[Vec<String>],
+  options: &Options,
+) -> Result<String, SubstitutionError> {
+  let last = pipeline.len() - 1;
+
+  let mut children = Vec::new();
+  let mut pipes = Vec::new();
+  let mut previous_stdout = None;
+  for (i, args) in pipeline.iter().enumerate() {
+    let program = &args[0];
+    if let Some(allowed) = &options.allowed {
+      if !allowed.contains(program) {
+        return Err(SubstitutionError::NotAllowed {
+          program: program.clone(),
+        });
+      }
+    }
+
+    let bin = which(program, &options.working_dir).ok_or_else(|| SubstitutionError::NotFound {
+      program: program.clone(),
+    })?;
+
+    let mut child = Command::new(bin)
+      .args(&args[1..])
+      .current_dir(&options.working_dir)
+      .stdin(if i == 0 {
+        Stdio::null()
+      } else {
+        Stdio::piped()
+      })
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()
+      .map_err(|source| SubstitutionError::Spawn {
+        program: program.clone(),
+        source,
+      })?;
+
+    if let (Some(mut from), Some(mut to)) = (previous_stdout.take(), child.stdin.take()) {
+      pipes.push(async move {
+        // The next command may exit without reading all of its input, which
+        // isn't an error
+        let _ = tokio::io::copy(&mut from, &mut to).await;
+      });
+    }
+
+    // The output of the last command is collected by `wait_with_output`
+    if i != last {
+      previous_stdout = child.stdout.take();
+    }
+    children.push(child);
+  }
+
+  // Dropping the children on timeout kills them
+  let outputs = join(
+    join_all(children.into_iter().map(|child| child.wait_with_output())),
+    join_all(pipes),
+  );
+  let (outputs, _) = tokio::time::timeout(options.timeout, outputs)
+    .await
+    .map_err(|_| SubstitutionError::TimedOut {
+      command: command.to_string(),
+      seconds: options.timeout.as_secs(),
+    })?;
+
+  let mut stdout = Vec::new();
+  for (args, output) in pipeline.iter().zip(outputs) {
+    let output = output.map_err(|source| SubstitutionError::Spawn {
+      program: args[0].clone(),
+      source,
+    })?;
+
+    if !output.status.success() {
+      let stderr = String::from_utf8_lossy(&output.stderr);
+      let stderr = stderr.trim();
+      return Err(SubstitutionError::Failed {
+        command: args.join(" "),
+        status: match output.status.code() {
+          Some(code) => format!("status {}", code),
+          None => "a signal".to_string(),
+        },
+        stderr: if stderr.is_empty() {
+          String::new()
+        } else {
+          format!(": {}", stderr)
+        },
+      });
+    }
+
+    stdout = output.stdout;
+  }
 
-  let cmd = Command::new(bin)
-    .args(rest)
-    .stdout(Stdio::piped())
-    .current_dir(working_dir.as_ref())
-    .spawn()?;
+  let stdout = String::from_utf8(stdout).map_err(|_| SubstitutionError::InvalidOutput {
+    command: command.to_string(),
+  })?;
+  Ok(stdout.trim().to_string())
+}
 
-  let out = cmd.wait_with_output()?;
+/// Look up the variable expression `expr` (the contents of `${...}`).
+fn lookup(expr: &str, vars: &Vars) -> Result<String, SubstitutionError> {
+  let (name, default) = match expr.split_once(":-") {
+    Some((name, default)) => (name, Some(default)),
+    None => (expr, None),
+  };
 
-  let ret = String::from_utf8(out.stdout).unwrap();
-  Ok(ret.trim().to_string())
+  let value = match name.strip_prefix("env:") {
+    Some(name) => std::env::var(name).ok(),
+    None => vars.get(name).cloned(),
+  };
+
+  match (value, default) {
+    (Some(value), _) => Ok(value),
+    (None, Some(default)) => Ok(default.to_string()),
+    (None, None) => Err(match name.strip_prefix("env:") {
+      Some(name) => SubstitutionError::EnvNotSet {
+        name: name.to_string(),
+      },
+      None => SubstitutionError::Undefined {
+        name: name.to_string(),
+      },
+    }),
+  }
 }
 
-pub fn preprocess(
-  yaml: Value,
-  working_dir: Arc<PathBuf>,
-) -> Pin<Box<dyn Future<Output = Result<Value>>>> {
+/// Concatenate `segments`, looking up variables and running commands.
+fn expand<'a>(
+  segments: Vec<Segment>,
+  vars: &'a Vars,
+  options: &'a Options,
+) -> Pin<Box<dyn Future<Output = Result<String, SubstitutionError>> + 'a>> {
   Box::pin(async move {
-    match yaml {
-      Value::Mapping(map) => {
-        let mut next = Mapping::new();
+    let mut result = String::new();
+    for segment in segments {
+      match segment {
+        Segment::Literal(literal) => result.push_str(literal.as_str()),
+        Segment::Variable(expr) => result.push_str(lookup(expr.as_str(), vars)?.as_str()),
+        Segment::Command(command) => {
+          result.push_str(run(command.as_str(), vars, options).await?.as_str())
+        }
+      }
+    }
+    Ok(result)
+  })
+}
 
-        for (key, value) in map.into_iter() {
-          let key = preprocess(key, working_dir.clone()).await?;
-          let value = preprocess(value, working_dir.clone()).await?;
+/// Run the command `command` (the contents of `$(...)`). Its arguments are
+/// substituted after it's split into them.
+async fn run(command: &str, vars: &Vars, options: &Options) -> Result<String, SubstitutionError> {
+  let mut pipeline = Vec::new();
+  for segments in tokenize(command)? {
+    let mut args = Vec::new();
+    for arg in segments {
+      args.push(expand(arg, vars, options).await?);
+    }
+    pipeline.push(args);
+  }
 
-          next.insert(key, value);
-        }
+  execute(command, &pipeline, options).await
+}
+
+/// Replace the `${...}` and `$(...)` expressions in `string`.
+async fn substitute(
+  string: &str,
+  vars: &Vars,
+  options: &Options,
+) -> Result<String, SubstitutionError> {
+  expand(scan(string)?, vars, options).await
+}
+
+/// The variables and templates in scope at a decl.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+  pub vars: Arc<Vars>,
+  pub templates: Arc<Templates>,
+}
+
+/// The result of preprocessing a configuration file.
+pub struct Preprocessed {
+  /// `None` if the root decl is excluded by its `when` condition
+  pub value: Option<Value>,
+  /// The scope at each `import` decl, keyed by the decl's path within the
+  /// document. This is inherited by the imported file.
+  pub imports: HashMap<String, Scope>,
+  /// The path within the document of each decl that moved because an
+  /// earlier sibling was excluded, keyed by its path within `value`
+  pub pointers: HashMap<String, String>,
+}
+
+fn invalid<M: Into<String>>(pointer: String, message: M) -> PreprocessError {
+  PreprocessError {
+    pointer,
+    source: SubstitutionError::Invalid {
+      message: message.into(),
+    },
+  }
+}
 
-        Ok(Value::Mapping(next))
-      }
-      Value::String(string) => Ok(Value::String(
-        CMD_REGEX
-          .replace_all(string.as_str(), |cap: &regex::Captures| {
-            let cap = &cap[0];
-            let cap = &cap[2..cap.len() - 1];
-            execute(cap, working_dir.clone()).unwrap()
-          })
-          .to_string(),
-      )),
-      Value::Sequence(seq) => {
-        let mut next = Sequence::new();
-        for value in seq {
-          next.push(preprocess(value, working_dir.clone()).await?);
+struct Preprocessor {
+  options: Arc<Options>,
+  imports: HashMap<String, Scope>,
+  pointers: HashMap<String, String>,
+}
+
+impl Preprocessor {
+  /// `vars`, extended with the variables declared by the `vars` field of
+  /// `map` (which is removed).
+  async fn declare_vars(
+    &self,
+    map: &mut Mapping,
+    vars: &Arc<Vars>,
+    pointer: &str,
+  ) -> Result<Arc<Vars>, PreprocessError> {
+    let declared = match map.shift_remove("vars") {
+      None => return Ok(vars.clone()),
+      Some(Value::Mapping(declared)) => declared,
+      Some(_) => {
+        return Err(invalid(
+          format!("{}/vars", pointer),
+          "Expected `vars` to be a mapping",
+        ))
+      }
+    };
+
+    let mut scope = vars.as_ref().clone();
+    for (name, value) in declared {
+      if !is_name(name.as_str()) {
+        return Err(invalid(
+          format!("{}/vars", pointer),
+          "Variable names may only contain letters, digits, `_` and `-`",
+        ));
+      }
+
+      let pointer = format!("{}/vars/{}", pointer, name);
+      let value = match value.to_scalar_string() {
+        Some(value) => value,
+        None => {
+          return Err(invalid(
+            pointer,
+            format!("Expected `{}` to be a string, number or boolean", name),
+          ))
         }
-        Ok(Value::Sequence(next))
+      };
+
+      // Variables may refer to those declared before them
+      let value = substitute(value.as_str(), &scope, &self.options)
+        .await
+        .map_err(|source| PreprocessError { pointer, source })?;
+      scope.insert(name, value);
+    }
+
+    Ok(Arc::new(scope))
+  }
+
+  /// `templates`, extended with the templates declared by the `templates`
+  /// field of `map` (which is removed). Templates aren't substituted until
+  /// they're used.
+  fn declare_templates(
+    &self,
+    map: &mut Mapping,
+    templates: &Arc<Templates>,
+    pointer: &str,
+  ) -> Result<Arc<Templates>, PreprocessError> {
+    let declared = match map.shift_remove("templates") {
+      None => return Ok(templates.clone()),
+      Some(Value::Mapping(declared)) => declared,
+      Some(_) => {
+        return Err(invalid(
+          format!("{}/templates", pointer),
+          "Expected `templates` to be a mapping",
+        ))
+      }
+    };
+
+    let mut scope = templates.as_ref().clone();
+    for (name, template) in declared {
+      if !is_name(name.as_str()) {
+        return Err(invalid(
+          format!("{}/templates", pointer),
+          "Template names may only contain letters, digits, `_` and `-`",
+        ));
       }
-      x => Ok(x.clone()),
+
+      let pointer = format!("{}/templates/{}", pointer, name);
+      let mut template = template::check(name.as_str(), template)
+        .map_err(|message| invalid(pointer.clone(), message))?;
+
+      // Templates may extend those declared before them
+      if let Some(base) = template.shift_remove("extends") {
+        let base = lookup_template(&scope, base, format!("{}/extends", pointer))?;
+        template = template::apply(base, template).0;
+      }
+
+      scope.insert(name, template);
     }
+
+    Ok(Arc::new(scope))
+  }
+
+  /// Preprocess the decl `decl` at `pointer`, whose parent has `scope` in
+  /// scope, returning `None` if it's excluded by its `when` condition.
+  /// `target` is where the decl will be in the preprocessed document.
+  fn decl(
+    &mut self,
+    decl: Value,
+    scope: Scope,
+    pointer: String,
+    target: String,
+  ) -> Pin<Box<dyn Future<Output = Result<Option<Value>, PreprocessError>> + '_>> {
+    Box::pin(async move {
+      let mut map = match decl {
+        Value::Mapping(map) => map,
+        // Reported when the decl is deserialized
+        decl => return Ok(Some(self.value(decl, scope.vars, pointer).await?)),
+      };
+
+      // Nothing in an excluded decl is substituted, so none of its commands
+      // are run
+      if let Some(condition) = map.shift_remove("when") {
+        let included = condition::evaluate(&condition, &self.options.conditions)
+          .map_err(|message| invalid(format!("{}/when", pointer), message))?;
+        if !included {
+          return Ok(None);
+        }
+      }
+
+      if target != pointer {
+        self.pointers.insert(target.clone(), pointer.clone());
+      }
+
+      let scope = Scope {
+        vars: self
+          .declare_vars(&mut map, &scope.vars, pointer.as_str())
+          .await?,
+        templates: self.declare_templates(&mut map, &scope.templates, pointer.as_str())?,
+      };
+
+      let is_type =
+        |map: &Mapping, decl_type: &str| map.get("type").and_then(Value::as_str) == Some(decl_type);
+
+      // The index among the decl's own children of each of its children, or
+      // `None` for those taken from a template
+      let mut child_sources = None;
+      if let Some(name) = map.shift_remove("extends") {
+        let extends_pointer = format!("{}/extends", pointer);
+        if !is_type(&map, "generate") {
+          return Err(invalid(
+            extends_pointer,
+            "Only `generate` decls can extend templates",
+          ));
+        }
+
+        let template = lookup_template(&scope.templates, name, extends_pointer)?;
+        let (applied, sources) = template::apply(template, map);
+        map = applied;
+        child_sources = Some(sources);
+      }
+
+      let mut next = Mapping::new();
+      for (key, value) in map {
+        let value = match (key.as_str(), value) {
+          ("children", Value::Sequence(children)) => {
+            let mut next = Sequence::new();
+            for (i, child) in children.into_iter().enumerate() {
+              let source = child_sources.as_ref().map_or(Some(i), |sources| sources[i]);
+              let child_pointer = match source {
+                Some(source) => format!("{}/children/{}", pointer, source),
+                // Located at the `extends` that brought it in
+                None => format!("{}/extends", pointer),
+              };
+              let child_target = format!("{}/children/{}", target, next.len());
+              if let Some(child) = self
+                .decl(child, scope.clone(), child_pointer, child_target)
+                .await?
+              {
+                next.push(child);
+              }
+            }
+            Value::Sequence(next)
+          }
+          (name, value) => {
+            let child_pointer = format!("{}/{}", pointer, name);
+            self.value(value, scope.vars.clone(), child_pointer).await?
+          }
+        };
+        next.insert(key, value);
+      }
+
+      if is_type(&next, "import") {
+        self.imports.insert(pointer, scope);
+      }
+
+      Ok(Some(Value::Mapping(next)))
+    })
+  }
+
+  /// Substitute every string within `value`.
+  fn value(
+    &self,
+    value: Value,
+    vars: Arc<Vars>,
+    pointer: String,
+  ) -> Pin<Box<dyn Future<Output = Result<Value, PreprocessError>> + '_>> {
+    Box::pin(async move {
+      match value {
+        Value::Mapping(map) => {
+          let mut next = Mapping::new();
+
+          for (key, value) in map.into_iter() {
+            let child_pointer = format!("{}/{}", pointer, key);
+
+            let key = substitute(key.as_str(), &vars, &self.options)
+              .await
+              .map_err(|source| PreprocessError {
+                pointer: pointer.clone(),
+                source,
+              })?;
+            let value = self.value(value, vars.clone(), child_pointer).await?;
+
+            next.insert(key, value);
+          }
+
+          Ok(Value::Mapping(next))
+        }
+        Value::String(string) => match substitute(string.as_str(), &vars, &self.options).await {
+          Ok(string) => Ok(Value::String(string)),
+          Err(source) => Err(PreprocessError { pointer, source }),
+        },
+        Value::Sequence(seq) => {
+          let mut next = Sequence::new();
+          for (i, value) in seq.into_iter().enumerate() {
+            let child_pointer = format!("{}/{}", pointer, i);
+            next.push(self.value(value, vars.clone(), child_pointer).await?);
+          }
+          Ok(Value::Sequence(next))
+        }
+        x => Ok(x),
+      }
+    })
+  }
+}
+
+/// Look up the template named by the `extends` field `name`.
+fn lookup_template(
+  templates: &Templates,
+  name: Value,
+  pointer: String,
+) -> Result<&Mapping, PreprocessError> {
+  let name = match name {
+    Value::String(name) => name,
+    _ => return Err(invalid(pointer, "Expected `extends` to be a template name")),
+  };
+
+  templates.get(&name).ok_or(PreprocessError {
+    pointer,
+    source: SubstitutionError::UnknownTemplate { name },
   })
 }
+
+/// Whether `name` is a valid variable or template name.
+fn is_name(name: &str) -> bool {
+  !name.is_empty()
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Preprocess the configuration file `config`, whose root decl inherits
+/// `scope`.
+///
+/// Decls excluded by their `when` condition are removed, templates are
+/// applied to the decls that extend them, every string is substituted, and
+/// `when`, `vars`, `templates` and `extends` fields are removed from decls.
+pub async fn preprocess(
+  config: Value,
+  options: Arc<Options>,
+  scope: Scope,
+) -> Result<Preprocessed, PreprocessError> {
+  let mut preprocessor = Preprocessor {
+    options,
+    imports: HashMap::new(),
+    pointers: HashMap::new(),
+  };
+
+  let value = preprocessor
+    .decl(config, scope, String::new(), String::new())
+    .await?;
+  Ok(Preprocessed {
+    value,
+    imports: preprocessor.imports,
+    pointers: preprocessor.pointers,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn literal(text: &str) -> Vec<Segment> {
+    vec![Segment::Literal(text.to_string())]
+  }
+
+  fn options(allowed: Option<&[&str]>) -> Options {
+    Options {
+      conditions: condition::Context::default(),
+      working_dir: std::env::temp_dir(),
+      timeout: Duration::from_secs(10),
+      allowed: allowed.map(|allowed| allowed.iter().map(|program| program.to_string()).collect()),
+    }
+  }
+
+  fn vars(vars: &[(&str, &str)]) -> Vars {
+    vars
+      .iter()
+      .map(|(name, value)| (name.to_string(), value.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn splits_arguments() {
+    assert_eq!(
+      tokenize(r#"echo  a 'b c' "d \"e\"" f\ g '' "h\n""#).unwrap(),
+      vec![vec![
+        literal("echo"),
+        literal("a"),
+        literal("b c"),
+        literal("d \"e\""),
+        literal("f g"),
+        Vec::new(),
+        literal("h\\n"),
+      ]]
+    );
+  }
+
+  #[test]
+  fn splits_pipelines() {
+    assert_eq!(
+      tokenize("git describe|tr -d 'v|'").unwrap(),
+      vec![
+        vec![literal("git"), literal("describe")],
+        vec![literal("tr"), literal("-d"), literal("v|")],
+      ]
+    );
+  }
+
+  #[test]
+  fn keeps_expressions_within_arguments() {
+    assert_eq!(
+      tokenize(r#"echo v${version}-$(date +%s) "${a} ${b}" '${c}' \$d $$e $"#).unwrap(),
+      vec![vec![
+        literal("echo"),
+        vec![
+          Segment::Literal("v".to_string()),
+          Segment::Variable("version".to_string()),
+          Segment::Literal("-".to_string()),
+          Segment::Command("date +%s".to_string()),
+        ],
+        vec![
+          Segment::Variable("a".to_string()),
+          Segment::Literal(" ".to_string()),
+          Segment::Variable("b".to_string()),
+        ],
+        literal("${c}"),
+        literal("$d"),
+        literal("$e"),
+        literal("$"),
+      ]]
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_commands() {
+    for command in &[
+      "", "  ", "a |", "| b", "a || b", "'a", "\"a", "a \\", "${a", "$(a",
+    ] {
+      assert!(
+        matches!(tokenize(command), Err(SubstitutionError::Syntax { .. })),
+        "{:?} should be rejected",
+        command
+      );
+    }
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn substitutes_values_into_single_arguments() {
+    let vars = vars(&[("value", "a  b | c"), ("program", "printf")]);
+    let options = options(None);
+
+    assert_eq!(
+      substitute("$(printf '<%s>' ${value})", &vars, &options)
+        .await
+        .unwrap(),
+      "<a  b | c>"
+    );
+    assert_eq!(
+      substitute("$(${program} '<%s>' x$(echo ${value})y)", &vars, &options)
+        .await
+        .unwrap(),
+      "<xa  b | cy>"
+    );
+    assert_eq!(
+      substitute("$(printf '<%s>' '${value}')", &vars, &options)
+        .await
+        .unwrap(),
+      "<${value}>"
+    );
+  }
+
+  #[tokio::test]
+  async fn checks_substituted_programs() {
+    let vars = vars(&[("program", "rm"), ("args", "x | rm y")]);
+    let options = options(Some(&["echo"]));
+
+    assert!(matches!(
+      substitute("$(${program} x)", &vars, &options).await,
+      Err(SubstitutionError::NotAllowed { program }) if program == "rm"
+    ));
+    // `rm` is never run, since it's part of `echo`'s argument
+    assert!(matches!(
+      substitute("$(echo ${args})", &vars, &options).await,
+      Ok(output) if output == "x | rm y"
+    ));
+  }
+
+  fn yaml(text: &str) -> Value {
+    crate::value::Format::Yaml.parse(text).unwrap()
+  }
+
+  async fn preprocess_yaml(text: &str, vars: Vars) -> Result<Preprocessed, PreprocessError> {
+    let scope = Scope {
+      vars: Arc::new(vars),
+      templates: Arc::default(),
+    };
+    preprocess(yaml(text), Arc::new(options(None)), scope).await
+  }
+
+  #[test]
+  fn looks_up_variables() {
+    let vars = vars(&[("name", "docs"), ("empty", "")]);
+
+    assert_eq!(lookup("name", &vars).unwrap(), "docs");
+    assert_eq!(lookup("name:-other", &vars).unwrap(), "docs");
+    assert_eq!(lookup("empty:-other", &vars).unwrap(), "");
+    assert_eq!(lookup("missing:-other", &vars).unwrap(), "other");
+    assert_eq!(lookup("missing:-", &vars).unwrap(), "");
+    assert_eq!(lookup("missing:-a:-b", &vars).unwrap(), "a:-b");
+    assert!(matches!(
+      lookup("missing", &vars),
+      Err(SubstitutionError::Undefined { name }) if name == "missing"
+    ));
+  }
+
+  #[test]
+  fn looks_up_environment_variables() {
+    let vars = vars(&[("DRYDOC_UNSET", "var")]);
+
+    std::env::set_var("DRYDOC_PREPROCESSOR_TEST", "env");
+    assert_eq!(
+      lookup("env:DRYDOC_PREPROCESSOR_TEST", &vars).unwrap(),
+      "env"
+    );
+    assert_eq!(
+      lookup("env:DRYDOC_PREPROCESSOR_TEST:-other", &vars).unwrap(),
+      "env"
+    );
+    // Declared variables aren't environment variables
+    assert_eq!(lookup("env:DRYDOC_UNSET:-other", &vars).unwrap(), "other");
+    assert!(matches!(
+      lookup("env:DRYDOC_UNSET", &vars),
+      Err(SubstitutionError::EnvNotSet { name }) if name == "DRYDOC_UNSET"
+    ));
+  }
+
+  #[tokio::test]
+  async fn declares_variables() {
+    let preprocessed = preprocess_yaml(
+      r#"
+type: generate
+id: root
+vars:
+  name: docs
+  title: "${name} v${version}"
+  count: 2
+with:
+  title: "${title} (${count})"
+  "${name}": $$name
+children:
+  - type: generate
+    id: child
+    vars:
+      name: child
+    with:
+      title: "${title}: ${name}"
+  - type: import
+    path: "${name}.yaml"
+"#,
+      vars(&[("version", "1.0")]),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+      preprocessed.value,
+      Some(yaml(
+        r#"
+type: generate
+id: root
+with:
+  title: "docs v1.0 (2)"
+  docs: $name
+children:
+  - type: generate
+    id: child
+    with:
+      title: "docs v1.0: child"
+  - type: import
+    path: docs.yaml
+"#
+      ))
+    );
+
+    // Imported files inherit the importing decl's scope
+    let scope = &preprocessed.imports["/children/1"];
+    assert_eq!(
+      scope.vars.as_ref(),
+      &vars(&[
+        ("version", "1.0"),
+        ("name", "docs"),
+        ("title", "docs v1.0"),
+        ("count", "2")
+      ])
+    );
+  }
+
+  #[tokio::test]
+  async fn scopes_variables_to_descendants() {
+    let err = preprocess_yaml(
+      r#"
+type: generate
+id: root
+children:
+  - type: generate
+    id: a
+    vars:
+      name: a
+  - type: generate
+    id: b
+    with:
+      name: "${name}"
+"#,
+      Vars::new(),
+    )
+    .await
+    .err()
+    .unwrap();
+
+    assert_eq!(err.pointer, "/children/1/with/name");
+    assert!(matches!(
+      err.source,
+      SubstitutionError::Undefined { name } if name == "name"
+    ));
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_variables() {
+    let error = |text: &'static str| async move {
+      preprocess_yaml(text, Vars::new())
+        .await
+        .err()
+        .map(|err| (err.pointer.clone(), err.to_string()))
+        .unwrap()
+    };
+
+    assert_eq!(
+      error("vars: [a]").await,
+      (
+        "/vars".to_string(),
+        "Expected `vars` to be a mapping".to_string()
+      )
+    );
+    assert_eq!(
+      error("vars: { a.b: x }").await,
+      (
+        "/vars".to_string(),
+        "Variable names may only contain letters, digits, `_` and `-`".to_string()
+      )
+    );
+    assert_eq!(
+      error("vars: { a: [x] }").await,
+      (
+        "/vars/a".to_string(),
+        "Expected `a` to be a string, number or boolean".to_string()
+      )
+    );
+    assert_eq!(
+      error("vars: { a: '${b}', b: x }").await,
+      ("/vars/a".to_string(), "Undefined variable `b`".to_string())
+    );
+  }
+}
@@ -0,0 +1,403 @@
+//! Configuration files imported from URIs, and from within tar archives.
+
+use std::{
+  fmt::{Display, Formatter},
+  io::Read,
+  path::{Component, Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::uri::Uri;
+
+/// A configuration file imported by URI, e.g. `https://example.com/drydoc.yaml`
+/// or `tar+file://docs.tar#drydoc.yaml`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remote {
+  /// The file, or archive, to fetch
+  pub uri: Uri,
+  /// The path of the file within the tar archive at `uri`, if it's an
+  /// archive
+  pub member: Option<String>,
+}
+
+/// `path`, with `.` and `..` components resolved, or `None` if it refers to
+/// something outside of its root.
+fn normalize(path: &Path) -> Option<String> {
+  let mut components: Vec<String> = Vec::new();
+  for component in path.components() {
+    match component {
+      Component::Normal(name) => components.push(name.to_string_lossy().to_string()),
+      Component::CurDir | Component::RootDir => {}
+      Component::ParentDir => {
+        components.pop()?;
+      }
+      Component::Prefix(_) => return None,
+    }
+  }
+  Some(components.join("/"))
+}
+
+impl Remote {
+  /// Parse the import path `path`, which is relative to `dir` if it's a
+  /// relative `file://` URI. Returns `None` if `path` isn't a URI.
+  pub fn parse(path: &str, dir: &Path) -> Result<Option<Self>, String> {
+    let (scheme, rest) = match path.split_once("://") {
+      Some(parts) => parts,
+      None => return Ok(None),
+    };
+
+    let (archive, scheme) = match scheme.strip_prefix("tar+") {
+      Some(scheme) => (true, scheme),
+      None => (false, scheme),
+    };
+
+    let (location, member) = match rest.split_once('#') {
+      Some((location, member)) => (location, Some(member)),
+      None => (rest, None),
+    };
+
+    let member = match (archive, member) {
+      (true, Some(member)) => Some(
+        normalize(Path::new(member))
+          .filter(|member| !member.is_empty())
+          .ok_or_else(|| format!("Invalid archive member `{}`", member))?,
+      ),
+      (true, None) => {
+        return Err(format!(
+          "Expected the file to import from the archive after `#` (e.g., `{}#drydoc.yaml`)",
+          path
+        ))
+      }
+      (false, Some(_)) => {
+        return Err(format!(
+          "Only archives (`tar+{}://...`) can select a file with `#`",
+          scheme
+        ))
+      }
+      (false, None) => None,
+    };
+
+    let uri = match scheme {
+      "file" => {
+        let mut file = dir.join(location);
+        if file.is_relative() {
+          let working_dir = std::env::current_dir()
+            .map_err(|err| format!("Unable to resolve `{}`: {}", location, err))?;
+          file = working_dir.join(file);
+        }
+        Uri::from_file_path(&file).map_err(|_| format!("Invalid path `{}`", location))?
+      }
+      "http" | "https" => Uri::parse(format!("{}://{}", scheme, location).as_str())
+        .map_err(|err| format!("Invalid URI `{}`: {}", path, err))?,
+      scheme => {
+        return Err(format!(
+          "Unsupported scheme `{}` (expected `file`, `http` or `https`, optionally prefixed with `tar+`)",
+          scheme
+        ))
+      }
+    };
+
+    Ok(Some(Self { uri, member }))
+  }
+
+  /// The file at the relative path `path`, relative to this one. Files
+  /// within an archive can only refer to other files in the same archive.
+  pub fn join(&self, path: &str) -> Result<Self, String> {
+    match &self.member {
+      Some(member) => {
+        let joined = Path::new(member)
+          .parent()
+          .unwrap_or_else(|| Path::new(""))
+          .join(path);
+        let member = normalize(&joined)
+          .filter(|member| !member.is_empty())
+          .ok_or_else(|| format!("`{}` is outside of the archive", path))?;
+        Ok(Self {
+          uri: self.uri.clone(),
+          member: Some(member),
+        })
+      }
+      None => Ok(Self {
+        uri: self
+          .uri
+          .join(path)
+          .map_err(|err| format!("Invalid path `{}`: {}", path, err))?,
+        member: None,
+      }),
+    }
+  }
+
+  /// Whether fetching this file requires the network.
+  pub fn is_http(&self) -> bool {
+    matches!(self.uri.scheme(), "http" | "https")
+  }
+
+  /// Extract this file from `contents`, the contents of `uri`. Returns
+  /// `None` if it's an archive that doesn't contain the file.
+  pub fn extract(&self, contents: &[u8]) -> Result<Option<String>, String> {
+    let member = match &self.member {
+      Some(member) => member,
+      None => {
+        return String::from_utf8(contents.to_vec())
+          .map(Some)
+          .map_err(|_| format!("{} isn't valid UTF-8", self))
+      }
+    };
+
+    // Gzip streams start with these magic bytes
+    let reader: Box<dyn Read + '_> = if contents.starts_with(&[0x1f, 0x8b]) {
+      Box::new(flate2::read::GzDecoder::new(contents))
+    } else {
+      Box::new(contents)
+    };
+
+    let invalid = |err: std::io::Error| format!("Unable to read the archive {}: {}", self.uri, err);
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(invalid)? {
+      let mut entry = entry.map_err(invalid)?;
+      let path = entry.path().map_err(invalid)?;
+      if normalize(&path).as_ref() != Some(member) {
+        continue;
+      }
+
+      let mut file = String::new();
+      entry
+        .read_to_string(&mut file)
+        .map_err(|_| format!("{} isn't valid UTF-8", self))?;
+      return Ok(Some(file));
+    }
+
+    Ok(None)
+  }
+
+  /// The local file (or archive) this file is read from, if it's fetched
+  /// from a `file://` URI.
+  pub fn local_path(&self) -> Option<PathBuf> {
+    match self.uri.scheme() {
+      "file" => self.uri.to_file_path().ok(),
+      _ => None,
+    }
+  }
+
+  /// How this file is displayed in diagnostics.
+  pub fn to_path(&self) -> PathBuf {
+    PathBuf::from(self.to_string())
+  }
+}
+
+impl Display for Remote {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match &self.member {
+      Some(member) => write!(f, "tar+{}#{}", self.uri, member),
+      None => write!(f, "{}", self.uri),
+    }
+  }
+}
+
+/// Check `contents` against the Subresource Integrity-style hash
+/// `integrity` (e.g., `sha256-<base64 digest>`).
+pub fn check_integrity(contents: &[u8], integrity: &str) -> Result<(), String> {
+  let (algorithm, expected) = integrity.split_once('-').ok_or_else(|| {
+    format!(
+      "Invalid integrity `{}` (expected `sha256-`, `sha384-` or `sha512-` followed by a base64 digest)",
+      integrity
+    )
+  })?;
+
+  let actual = match algorithm {
+    "sha256" => base64::encode(Sha256::digest(contents)),
+    "sha384" => base64::encode(Sha384::digest(contents)),
+    "sha512" => base64::encode(Sha512::digest(contents)),
+    algorithm => {
+      return Err(format!(
+        "Unsupported integrity algorithm `{}` (expected `sha256`, `sha384` or `sha512`)",
+        algorithm
+      ))
+    }
+  };
+
+  if actual != expected {
+    return Err(format!(
+      "Integrity check failed (expected `{}`, found `{}-{}`)",
+      integrity, algorithm, actual
+    ));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+  };
+
+  use crate::resource::UriCache;
+
+  /// Serve `body` to every request on a local port, returning the base URI.
+  async fn serve(body: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      loop {
+        let (mut stream, _) = match listener.accept().await {
+          Ok(connection) => connection,
+          Err(_) => return,
+        };
+
+        let mut request = [0; 4096];
+        let _ = stream.read(&mut request).await;
+        let header = format!(
+          "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+          body.len()
+        );
+        let _ = stream.write_all(header.as_bytes()).await;
+        let _ = stream.write_all(&body).await;
+      }
+    });
+    format!("http://{}", addr)
+  }
+
+  fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, contents) in files {
+      let mut header = tar::Header::new_gnu();
+      header.set_size(contents.len() as u64);
+      header.set_mode(0o644);
+      header.set_cksum();
+      builder
+        .append_data(&mut header, path, contents.as_bytes())
+        .unwrap();
+    }
+    builder.into_inner().unwrap()
+  }
+
+  fn integrity(contents: &[u8]) -> String {
+    format!("sha256-{}", base64::encode(Sha256::digest(contents)))
+  }
+
+  #[test]
+  fn parses_import_uris() {
+    let remote = Remote::parse(
+      "tar+file://vendor/docs.tar#./a/../drydoc.yaml",
+      Path::new("/root"),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(remote.uri.as_str(), "file:///root/vendor/docs.tar");
+    assert_eq!(remote.member.as_deref(), Some("drydoc.yaml"));
+
+    let remote = Remote::parse("https://example.com/docs/drydoc.yaml", Path::new("/root"))
+      .unwrap()
+      .unwrap();
+    assert_eq!(remote.member, None);
+    assert_eq!(
+      remote.join("../api/drydoc.yaml").unwrap().uri.as_str(),
+      "https://example.com/api/drydoc.yaml"
+    );
+
+    assert_eq!(
+      Remote::parse("docs/drydoc.yaml", Path::new("/root")),
+      Ok(None)
+    );
+    assert!(Remote::parse("tar+file://docs.tar", Path::new("/root")).is_err());
+    assert!(Remote::parse("ftp://example.com/drydoc.yaml", Path::new("/root")).is_err());
+  }
+
+  #[test]
+  fn joins_within_archives() {
+    let remote = Remote::parse(
+      "tar+https://example.com/docs.tar#docs/drydoc.yaml",
+      Path::new("/"),
+    )
+    .unwrap()
+    .unwrap();
+
+    let sibling = remote.join("api/drydoc.yaml").unwrap();
+    assert_eq!(sibling.uri, remote.uri);
+    assert_eq!(sibling.member.as_deref(), Some("docs/api/drydoc.yaml"));
+
+    assert!(remote.join("../../escape.yaml").is_err());
+  }
+
+  #[test]
+  fn checks_integrity() {
+    let contents = b"type: import\npath: x.yaml\n";
+    assert_eq!(
+      check_integrity(contents, integrity(contents).as_str()),
+      Ok(())
+    );
+    assert!(check_integrity(b"tampered", integrity(contents).as_str()).is_err());
+    assert!(check_integrity(contents, "md5-abc").is_err());
+    assert!(check_integrity(contents, "abc").is_err());
+  }
+
+  #[tokio::test]
+  async fn fetches_archives_over_http() {
+    let config = "type: generate\nid: vendored\nusing: copy\nwith: {}\n";
+    let tar = archive(&[("README.md", "# Docs"), ("docs/drydoc.yaml", config)]);
+    let base = serve(tar.clone()).await;
+
+    let path = format!("tar+{}/docs.tar#docs/drydoc.yaml", base);
+    let remote = Remote::parse(path.as_str(), Path::new("/"))
+      .unwrap()
+      .unwrap();
+    assert!(remote.is_http());
+
+    let cache = UriCache::default();
+    let contents = cache.get(&remote.uri).await.unwrap();
+    assert_eq!(check_integrity(&contents, integrity(&tar).as_str()), Ok(()));
+    assert_eq!(remote.extract(&contents), Ok(Some(config.to_string())));
+
+    let missing = remote.join("missing.yaml").unwrap();
+    assert_eq!(missing.extract(&contents), Ok(None));
+  }
+
+  #[tokio::test]
+  async fn extracts_gzipped_archives() {
+    use std::io::Write;
+
+    let config = "type: import\npath: other.yaml\n";
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder
+      .write_all(&archive(&[("drydoc.yaml", config)]))
+      .unwrap();
+    let base = serve(encoder.finish().unwrap()).await;
+
+    let path = format!("tar+{}/docs.tar.gz#drydoc.yaml", base);
+    let remote = Remote::parse(path.as_str(), Path::new("/"))
+      .unwrap()
+      .unwrap();
+    let contents = UriCache::default().get(&remote.uri).await.unwrap();
+    assert_eq!(remote.extract(&contents), Ok(Some(config.to_string())));
+  }
+
+  #[tokio::test]
+  async fn rereads_forgotten_local_files() {
+    let dir = std::env::temp_dir().join(format!("drydoc-remote-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("docs.tar");
+    std::fs::write(&file, archive(&[("drydoc.yaml", "id: v1\n")])).unwrap();
+
+    let remote = Remote::parse("tar+file://docs.tar#drydoc.yaml", &dir)
+      .unwrap()
+      .unwrap();
+    assert_eq!(remote.local_path(), Some(file.clone()));
+
+    let cache = UriCache::default();
+    let v1 = cache.get(&remote.uri).await.unwrap();
+    std::fs::write(&file, archive(&[("drydoc.yaml", "id: v2\n")])).unwrap();
+    let cached = cache.get(&remote.uri).await.unwrap();
+    cache.forget_local();
+    let v2 = cache.get(&remote.uri).await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(remote.extract(&v1), Ok(Some("id: v1\n".to_string())));
+    assert_eq!(remote.extract(&cached), Ok(Some("id: v1\n".to_string())));
+    assert_eq!(remote.extract(&v2), Ok(Some("id: v2\n".to_string())));
+  }
+}
@@ -1,14 +1,23 @@
 use super::uri::Uri;
 
 use super::actor::{Actor, Addr, SendError};
-use std::sync::Arc;
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use derive_more::{Display, Error};
 
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot::{channel, Receiver, Sender};
 
+#[derive(Debug, Display, Error)]
 pub enum GetError {
+  #[display(fmt = "{}", _0)]
   Io(tokio::io::Error),
+  #[display(fmt = "The resource stopped without responding")]
   Receive,
+  #[display(fmt = "Unable to reach the resource")]
   Send(SendError),
 }
 
@@ -99,6 +108,45 @@ impl Actor for UriResource {
   }
 }
 
+type UriResources = HashMap<Uri, Addr<ResourceMsg<Arc<[u8]>>>>;
+
+/// A `UriResource` for every URI requested so far, so that each is only
+/// fetched once.
+#[derive(Clone, Default)]
+pub struct UriCache {
+  resources: Arc<Mutex<UriResources>>,
+}
+
+impl std::fmt::Debug for UriCache {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("UriCache").finish_non_exhaustive()
+  }
+}
+
+impl UriCache {
+  /// The contents of `uri`, fetching it if it hasn't been already.
+  pub async fn get(&self, uri: &Uri) -> Result<Arc<[u8]>, GetError> {
+    let resource = self
+      .resources
+      .lock()
+      .expect("The URI cache lock is never poisoned")
+      .entry(uri.clone())
+      .or_insert_with(|| UriResource::new(uri.clone()).spawn())
+      .clone();
+    resource.get().await
+  }
+
+  /// Forget the contents of every local (`file://`) URI, so that they're
+  /// read again the next time they're requested, in case they changed.
+  pub fn forget_local(&self) {
+    self
+      .resources
+      .lock()
+      .expect("The URI cache lock is never poisoned")
+      .retain(|uri, _| uri.scheme() != "file");
+  }
+}
+
 pub struct TransformResource<T, U, F>
 where
   T: Send + Sync,
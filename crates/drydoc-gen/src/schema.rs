@@ -0,0 +1,165 @@
+//! A JSON Schema for configuration files, derived from the `Decl` model, for
+//! editors to autocomplete and validate configurations with.
+
+use std::collections::{BTreeMap, HashMap};
+
+use clap::Clap;
+use drydoc_model::decl::Decl;
+use drydoc_pkg_manager::{Artifact, Manager as PkgMgr, ParameterType, Parameters, UrlFetcher};
+use serde_json::{json, Map, Value};
+
+use crate::error::GenError;
+
+/// Print a JSON Schema for configuration files to stdout, including the
+/// parameters of every installed generator.
+#[derive(Clap, Debug)]
+pub struct SchemaOpts {}
+
+/// The schema of a `with` field accepted by a generator with `parameters`.
+fn parameters_schema(parameters: &Parameters) -> Value {
+  let mut properties = Map::new();
+  let mut required = Vec::new();
+  for (name, parameter) in parameters {
+    let mut schema = match parameter.ty {
+      ParameterType::String => json!({ "type": "string" }),
+      ParameterType::Number => json!({ "type": "number" }),
+      ParameterType::Integer => json!({ "type": "integer" }),
+      ParameterType::Boolean => json!({ "type": "boolean" }),
+      ParameterType::List => json!({ "type": "array" }),
+      ParameterType::Map => json!({ "type": "object" }),
+      ParameterType::Any => json!({}),
+    };
+    if let Some(doc) = &parameter.doc {
+      schema["description"] = json!(doc);
+    }
+    if let Some(default) = &parameter.default {
+      schema["default"] = default.clone();
+    }
+    if parameter.required && parameter.default.is_none() {
+      required.push(name.clone());
+    }
+    properties.insert(name.clone(), schema);
+  }
+
+  // Generators may accept parameters their schema doesn't mention
+  json!({
+    "type": "object",
+    "properties": properties,
+    "required": required,
+  })
+}
+
+/// A rule applying the parameters of each generator installed by `pkg_mgr`
+/// to the `with` of decls using it. Generators in the default repository
+/// have no `prefix`; others have `repository/`.
+fn generator_rules(
+  pkg_mgr: &PkgMgr<UrlFetcher>,
+  prefix: &str,
+  rules: &mut Vec<Value>,
+) -> Result<(), GenError> {
+  let installed = pkg_mgr
+    .installed_artifacts()
+    .map_err(|cause| GenError::Io { path: None, cause })?;
+
+  // Only the newest installed version of each generator is described
+  let mut newest = BTreeMap::new();
+  for installed in installed {
+    if let Artifact::Generator(generator) = installed.artifact {
+      if let Some(parameters) = generator.parameters {
+        match newest.get(&installed.name) {
+          Some((newest_version, _)) if *newest_version >= installed.version => {}
+          _ => {
+            newest.insert(installed.name, (installed.version, parameters));
+          }
+        }
+      }
+    }
+  }
+
+  for (name, (_, parameters)) in newest {
+    // Package names may only contain letters, digits, `-`, `_` and `.`
+    let pattern = format!(
+      r"^\s*{}{}(@.*)?\s*$",
+      prefix.replace('.', r"\."),
+      name.replace('.', r"\.")
+    );
+    rules.push(json!({
+      "if": {
+        "properties": { "using": { "pattern": pattern } },
+        "required": ["using"],
+      },
+      "then": {
+        "properties": { "with": parameters_schema(&parameters) },
+      },
+    }));
+  }
+
+  Ok(())
+}
+
+/// The schema of configuration files, describing the parameters of the
+/// generators installed by `pkg_mgr` and `repositories`.
+fn schema(
+  pkg_mgr: &PkgMgr<UrlFetcher>,
+  repositories: &HashMap<String, PkgMgr<UrlFetcher>>,
+) -> Result<Value, GenError> {
+  let root = serde_json::to_value(schemars::schema_for!(Decl)).expect("Schemas are valid JSON");
+
+  let mut definitions = match root.get("definitions") {
+    Some(Value::Object(definitions)) => definitions.clone(),
+    _ => Map::new(),
+  };
+
+  let mut rules = Vec::new();
+  generator_rules(pkg_mgr, "", &mut rules)?;
+  let mut names: Vec<&String> = repositories.keys().collect();
+  names.sort();
+  for name in names {
+    generator_rules(
+      &repositories[name],
+      format!("{}/", name).as_str(),
+      &mut rules,
+    )?;
+  }
+
+  let variants = definitions
+    .get_mut("Decl")
+    .and_then(|decl| decl.get_mut("oneOf"))
+    .and_then(Value::as_array_mut)
+    .expect("Decl is an enum");
+  let generate = variants
+    .iter_mut()
+    .find(|variant| variant["properties"]["type"]["enum"] == json!(["generate"]))
+    .and_then(Value::as_object_mut)
+    .expect("Decl has a `generate` variant");
+
+  // `using` may come from a template instead
+  if let Some(Value::Array(required)) = generate.get_mut("required") {
+    required.retain(|field| field != "using");
+  }
+  generate.insert(
+    "anyOf".to_string(),
+    json!([{ "required": ["using"] }, { "required": ["extends"] }]),
+  );
+  generate.insert("allOf".to_string(), Value::Array(rules));
+
+  Ok(json!({
+    "$schema": root["$schema"],
+    "title": "drydoc configuration",
+    "$ref": "#/definitions/Decl",
+    "definitions": definitions,
+  }))
+}
+
+/// Print the schema of configuration files to stdout.
+pub fn print(
+  pkg_mgr: &PkgMgr<UrlFetcher>,
+  repositories: &HashMap<String, PkgMgr<UrlFetcher>>,
+) -> Result<(), GenError> {
+  let schema = schema(pkg_mgr, repositories)?;
+  println!(
+    "{}",
+    serde_json::to_string_pretty(&schema).expect("Schemas are valid JSON")
+  );
+  Ok(())
+}
@@ -0,0 +1,292 @@
+//! Positions of nodes within configuration files.
+
+use std::collections::HashMap;
+
+use yaml_rust::{
+  parser::{Event, MarkedEventReceiver, Parser},
+  scanner::Marker,
+};
+
+/// A position within a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+  /// 1-based line number
+  pub line: usize,
+  /// 1-based column
+  pub col: usize,
+}
+
+impl From<Marker> for Mark {
+  fn from(value: Marker) -> Self {
+    Self {
+      line: value.line(),
+      col: value.col() + 1,
+    }
+  }
+}
+
+/// Where each node of a document starts, keyed by a JSON pointer-like path
+/// (e.g., `/children/0/with/path`). The root node has the empty path.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+  text: String,
+  marks: HashMap<String, Mark>,
+}
+
+impl SourceMap {
+  /// Index the nodes of `text`. Indexing stops at the first syntax error;
+  /// the error itself is reported when the document is deserialized.
+  pub fn parse(text: &str) -> Self {
+    let mut builder = Builder {
+      marks: HashMap::new(),
+      stack: Vec::new(),
+    };
+
+    let mut parser = Parser::new(text.chars());
+    let _ = parser.load(&mut builder, false);
+
+    Self {
+      text: text.to_string(),
+      marks: builder.marks,
+    }
+  }
+
+  /// Index the tables and keys of the TOML document `text`. Only values
+  /// that start a line (`key = value`) or are tables (`[table]` and
+  /// `[[array]]`) are indexed, not the contents of inline tables and arrays.
+  pub fn parse_toml(text: &str) -> Self {
+    let mut marks = HashMap::new();
+    marks.insert(String::new(), Mark { line: 1, col: 1 });
+
+    // The number of tables in each array of tables, by pointer
+    let mut arrays: HashMap<String, usize> = HashMap::new();
+    // The pointer of the table that keys are currently added to
+    let mut table = String::new();
+    // The delimiter that ends the multi-line string being skipped
+    let mut multiline: Option<&str> = None;
+
+    // The pointer of the dotted key `keys` within `base`, where each array
+    // of tables along the way refers to its last table
+    let resolve = |arrays: &HashMap<String, usize>, base: &str, keys: &[String]| {
+      let mut pointer = base.to_string();
+      for key in keys {
+        pointer = format!("{}/{}", pointer, key);
+        if let Some(count) = arrays.get(&pointer) {
+          pointer = format!("{}/{}", pointer, count - 1);
+        }
+      }
+      pointer
+    };
+
+    for (i, line) in text.lines().enumerate() {
+      if let Some(delimiter) = multiline {
+        if line.contains(delimiter) {
+          multiline = None;
+        }
+        continue;
+      }
+
+      let trimmed = line.trim_start();
+      let start = line.len() - trimmed.len();
+      let mark = |offset: usize| Mark {
+        line: i + 1,
+        col: line[..offset].chars().count() + 1,
+      };
+
+      if let Some(header) = trimmed.strip_prefix("[[") {
+        let mut keys = toml_keys(header.split("]]").next().unwrap_or(""));
+        let array = match keys.pop() {
+          Some(array) => array,
+          None => continue,
+        };
+        let array = format!("{}/{}", resolve(&arrays, "", &keys), array);
+        let count = arrays.entry(array.clone()).or_insert(0);
+        table = format!("{}/{}", array, count);
+        *count += 1;
+        marks.insert(table.clone(), mark(start));
+      } else if let Some(header) = trimmed.strip_prefix('[') {
+        let keys = toml_keys(header.split(']').next().unwrap_or(""));
+        table = resolve(&arrays, "", &keys);
+        marks.insert(table.clone(), mark(start));
+      } else if let Some(equals) = find_unquoted(trimmed, '=') {
+        let keys = toml_keys(&trimmed[..equals]);
+        let value = &trimmed[equals + 1..];
+        let value_start = line.len() - value.trim_start().len();
+        marks.insert(resolve(&arrays, table.as_str(), &keys), mark(value_start));
+
+        let value = value.trim();
+        for delimiter in &["\"\"\"", "'''"] {
+          if value.starts_with(delimiter) && !value[3..].contains(delimiter) {
+            multiline = Some(delimiter);
+          }
+        }
+      }
+    }
+
+    Self {
+      text: text.to_string(),
+      marks,
+    }
+  }
+
+  /// The position of the node at `pointer`.
+  pub fn mark(&self, pointer: &str) -> Option<Mark> {
+    self.marks.get(pointer).copied()
+  }
+
+  /// The text of the 1-based line `line`.
+  pub fn line(&self, line: usize) -> Option<&str> {
+    self.text.lines().nth(line.checked_sub(1)?)
+  }
+}
+
+/// The byte offset of the first `c` in the TOML line `text` that isn't
+/// quoted or in a comment.
+fn find_unquoted(text: &str, c: char) -> Option<usize> {
+  let mut quote = None;
+  for (i, ch) in text.char_indices() {
+    match quote {
+      None if ch == '#' => return None,
+      None if ch == c => return Some(i),
+      None if ch == '"' || ch == '\'' => quote = Some(ch),
+      Some(q) if ch == q => quote = None,
+      _ => {}
+    }
+  }
+  None
+}
+
+/// The keys of the TOML dotted key `key` (e.g., `with."api.path"`).
+fn toml_keys(mut key: &str) -> Vec<String> {
+  let unquote = |key: &str| key.trim().trim_matches(&['"', '\''][..]).to_string();
+
+  let mut keys = Vec::new();
+  while let Some(dot) = find_unquoted(key, '.') {
+    keys.push(unquote(&key[..dot]));
+    key = &key[dot + 1..];
+  }
+  if !key.trim().is_empty() {
+    keys.push(unquote(key));
+  }
+  keys
+}
+
+enum Frame {
+  Mapping {
+    pointer: String,
+    key: Option<String>,
+  },
+  Sequence {
+    pointer: String,
+    index: usize,
+  },
+}
+
+struct Builder {
+  marks: HashMap<String, Mark>,
+  stack: Vec<Frame>,
+}
+
+impl Builder {
+  /// Called when a node starts. `scalar` is the node's value if it is a
+  /// scalar. Returns the pointer to the node.
+  fn begin_node(&mut self, mark: Marker, scalar: Option<&str>) -> String {
+    let pointer = match self.stack.last_mut() {
+      None => String::new(),
+      Some(Frame::Sequence { pointer, index }) => {
+        *index += 1;
+        format!("{}/{}", pointer, *index - 1)
+      }
+      Some(Frame::Mapping { pointer, key }) => match key.take() {
+        Some(key) => format!("{}/{}", pointer, key),
+        None => {
+          // This node is a key, which isn't recorded. Non-scalar keys can't
+          // be addressed by a pointer.
+          //
+          // Block mappings are reported as starting after their first key,
+          // so move the mapping's mark back to the key if necessary.
+          let key_mark = Mark::from(mark);
+          if let Some(map_mark) = self.marks.get_mut(pointer.as_str()) {
+            if (key_mark.line, key_mark.col) < (map_mark.line, map_mark.col) {
+              *map_mark = key_mark;
+            }
+          }
+
+          let pointer = format!("{}/?", pointer);
+          *key = Some(scalar.unwrap_or("?").to_string());
+          return pointer;
+        }
+      },
+    };
+
+    self.marks.insert(pointer.clone(), mark.into());
+    pointer
+  }
+}
+
+impl MarkedEventReceiver for Builder {
+  fn on_event(&mut self, ev: Event, mark: Marker) {
+    match ev {
+      Event::Scalar(value, ..) => {
+        self.begin_node(mark, Some(value.as_str()));
+      }
+      Event::Alias(_) => {
+        self.begin_node(mark, None);
+      }
+      Event::MappingStart(_) => {
+        let pointer = self.begin_node(mark, None);
+        self.stack.push(Frame::Mapping { pointer, key: None });
+      }
+      Event::SequenceStart(_) => {
+        let pointer = self.begin_node(mark, None);
+        self.stack.push(Frame::Sequence { pointer, index: 0 });
+      }
+      Event::MappingEnd | Event::SequenceEnd => {
+        self.stack.pop();
+      }
+      _ => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn mark(line: usize, col: usize) -> Option<Mark> {
+    Some(Mark { line, col })
+  }
+
+  #[test]
+  fn marks_yaml_nodes() {
+    let source = SourceMap::parse(
+      "type: generate\nid: root\nwith:\n  path: src\nchildren:\n  - type: import\n    path: a.yaml\n",
+    );
+
+    assert_eq!(source.mark(""), mark(1, 1));
+    assert_eq!(source.mark("/id"), mark(2, 5));
+    assert_eq!(source.mark("/with"), mark(4, 3));
+    assert_eq!(source.mark("/with/path"), mark(4, 9));
+    assert_eq!(source.mark("/children/0"), mark(6, 5));
+    assert_eq!(source.mark("/children/0/path"), mark(7, 11));
+    assert_eq!(source.mark("/children/1"), None);
+    assert_eq!(source.mark("/missing"), None);
+  }
+
+  #[test]
+  fn keeps_marks_before_syntax_errors() {
+    let source = SourceMap::parse("id: root\nwith: [\n");
+    assert_eq!(source.mark("/id"), mark(1, 5));
+  }
+
+  #[test]
+  fn looks_up_lines() {
+    let source = SourceMap::parse("id: root\n\nusing: copy\n");
+
+    assert_eq!(source.line(1), Some("id: root"));
+    assert_eq!(source.line(2), Some(""));
+    assert_eq!(source.line(3), Some("using: copy"));
+    assert_eq!(source.line(0), None);
+    assert_eq!(source.line(4), None);
+  }
+}
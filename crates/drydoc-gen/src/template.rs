@@ -0,0 +1,215 @@
+//! Application of the reusable decl fragments (`drydoc_model::decl::Template`)
+//! declared in a decl's `templates` field, to the `generate` decls that
+//! `extends` them.
+//!
+//! Templates are applied to decls before they're substituted, so that
+//! templates are substituted in the scope of the decl that extends them.
+
+use std::collections::HashMap;
+
+use crate::value::{Mapping, Sequence, Value};
+
+/// The templates in scope at a decl, by name, as declared.
+pub type Templates = HashMap<String, Mapping>;
+
+/// The fields a template may provide.
+static FIELDS: &[&str] = &["extends", "using", "with", "children"];
+
+/// Check that `template` is a mapping of fields a template may provide.
+pub fn check(name: &str, template: Value) -> Result<Mapping, String> {
+  let template = match template {
+    Value::Mapping(template) => template,
+    _ => return Err(format!("Expected template `{}` to be a mapping", name)),
+  };
+
+  for (key, _) in template.iter() {
+    if !FIELDS.contains(&key.as_str()) {
+      return Err(format!(
+        "Templates can't set `{}` (expected one of `{}`)",
+        key,
+        FIELDS.join("`, `")
+      ));
+    }
+  }
+
+  match template.get("with") {
+    None | Some(Value::Mapping(_)) => Ok(template),
+    Some(_) => Err(format!(
+      "Expected `with` of template `{}` to be a mapping",
+      name
+    )),
+  }
+}
+
+/// The `id` of the decl `decl`, if it has one.
+fn id(decl: &Value) -> Option<&str> {
+  match decl {
+    Value::Mapping(decl) => decl.get("id").and_then(Value::as_str),
+    _ => None,
+  }
+}
+
+/// The children of a template followed by `children`, where each child of
+/// `children` with the same `id` as one of the template's replaces it instead.
+/// Returns the index within `children` of each resulting child, or `None` for
+/// those taken from the template.
+fn merge_children(template: &Sequence, children: Sequence) -> (Sequence, Vec<Option<usize>>) {
+  let mut children: Vec<Option<Value>> = children.into_iter().map(Some).collect();
+  let mut merged = Sequence::new();
+  let mut sources = Vec::new();
+
+  for child in template {
+    let replacement = id(child).and_then(|template_id| {
+      children
+        .iter()
+        .position(|child| child.as_ref().and_then(id) == Some(template_id))
+    });
+
+    match replacement {
+      Some(i) => {
+        merged.extend(children[i].take());
+        sources.push(Some(i));
+      }
+      None => {
+        merged.push(child.clone());
+        sources.push(None);
+      }
+    }
+  }
+
+  for (i, child) in children.into_iter().enumerate() {
+    if let Some(child) = child {
+      merged.push(child);
+      sources.push(Some(i));
+    }
+  }
+
+  (merged, sources)
+}
+
+/// `decl`, with every field it doesn't set taken from `template`. `with` is
+/// merged key by key and `children` by `id`, preferring `decl`'s.
+///
+/// Also returns the index among `decl`'s children of each child of the
+/// result, or `None` for those taken from `template`.
+pub fn apply(template: &Mapping, mut decl: Mapping) -> (Mapping, Vec<Option<usize>>) {
+  let mut sources = match decl.get("children") {
+    Some(Value::Sequence(children)) => (0..children.len()).map(Some).collect(),
+    _ => Vec::new(),
+  };
+
+  for (key, value) in template {
+    match (decl.get_mut(key), value) {
+      (None, value) => {
+        if let Value::Sequence(children) = value {
+          if key == "children" {
+            sources = vec![None; children.len()];
+          }
+        }
+        decl.insert(key.clone(), value.clone());
+      }
+      (Some(Value::Mapping(with)), Value::Mapping(defaults)) if key == "with" => {
+        for (name, value) in defaults {
+          if !with.contains_key(name) {
+            with.insert(name.clone(), value.clone());
+          }
+        }
+      }
+      (Some(Value::Sequence(children)), Value::Sequence(defaults)) if key == "children" => {
+        let (merged, merged_sources) = merge_children(defaults, std::mem::take(children));
+        *children = merged;
+        sources = merged_sources;
+      }
+      (Some(_), _) => {}
+    }
+  }
+
+  (decl, sources)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use crate::value::Format;
+
+  fn mapping(yaml: &str) -> Mapping {
+    match Format::Yaml.parse(yaml).unwrap() {
+      Value::Mapping(mapping) => mapping,
+      value => panic!("Expected a mapping, found {:?}", value),
+    }
+  }
+
+  #[test]
+  fn takes_unset_fields() {
+    let template = mapping("using: markdown\nwith: { toc: true }\n");
+    let (decl, sources) = apply(&template, mapping("type: generate\nid: guide\n"));
+
+    assert_eq!(
+      decl,
+      mapping("type: generate\nid: guide\nusing: markdown\nwith: { toc: true }\n")
+    );
+    assert!(sources.is_empty());
+  }
+
+  #[test]
+  fn prefers_decl_fields() {
+    let template = mapping("using: markdown\nwith: { toc: true, path: a.md }\n");
+    let (decl, _) = apply(
+      &template,
+      mapping("using: copy\nwith: { toc: false, title: Guide }\n"),
+    );
+
+    assert_eq!(
+      decl,
+      mapping("using: copy\nwith: { toc: false, title: Guide, path: a.md }\n")
+    );
+  }
+
+  #[test]
+  fn takes_template_children() {
+    let template = mapping("children: [{ id: a }, { type: import, path: b.yaml }]\n");
+    let (decl, sources) = apply(&template, mapping("id: root\n"));
+
+    assert_eq!(
+      decl,
+      mapping("id: root\nchildren: [{ id: a }, { type: import, path: b.yaml }]\n")
+    );
+    assert_eq!(sources, vec![None, None]);
+  }
+
+  #[test]
+  fn merges_children() {
+    let template = mapping(
+      "children: [{ id: a, using: t }, { id: b, using: t }, { type: import, path: c.yaml }]\n",
+    );
+    let (decl, sources) = apply(
+      &template,
+      mapping(
+        "children: [{ id: d, using: x }, { id: b, using: x }, { type: import, path: e.yaml }]\n",
+      ),
+    );
+
+    assert_eq!(
+      decl,
+      mapping(
+        "children:
+          - { id: a, using: t }
+          - { id: b, using: x }
+          - { type: import, path: c.yaml }
+          - { id: d, using: x }
+          - { type: import, path: e.yaml }
+        "
+      )
+    );
+    assert_eq!(sources, vec![None, Some(1), None, Some(0), Some(2)]);
+  }
+
+  #[test]
+  fn keeps_own_children() {
+    let (decl, sources) = apply(&mapping("using: t\n"), mapping("children: [{ id: a }]\n"));
+
+    assert_eq!(decl, mapping("children: [{ id: a }]\nusing: t\n"));
+    assert_eq!(sources, vec![Some(0)]);
+  }
+}
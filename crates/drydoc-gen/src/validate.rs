@@ -0,0 +1,213 @@
+//! Validation of an entire decl tree before anything is generated.
+
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+use drydoc_model::{
+  decl::{Generate, Params},
+  ns::Namespace,
+};
+use drydoc_pkg_manager::Parameters;
+use log::warn;
+
+use crate::{
+  actor::Addr,
+  config::{Node, Origin},
+  error::{DeclInfo, GenError, Location},
+  generator_mgr::GeneratorMgrMsg,
+};
+
+/// Add the default value of every parameter missing from `with`.
+pub fn apply_defaults(parameters: &Parameters, with: &mut Params) {
+  for (name, schema) in parameters {
+    if let Some(default) = &schema.default {
+      with.entry(name.clone()).or_insert_with(|| default.clone());
+    }
+  }
+}
+
+/// Check `with` against the parameters a generator accepts, appending any
+/// problems to `errors`.
+fn check_params(
+  generate: &Generate,
+  parameters: &Parameters,
+  origin: &Origin,
+  errors: &mut Vec<GenError>,
+) {
+  let with = &generate.with;
+
+  for (name, schema) in parameters {
+    let hint = match &schema.doc {
+      Some(doc) => format!(" ({})", doc),
+      None => String::new(),
+    };
+
+    match with.get(name) {
+      None if schema.required && schema.default.is_none() => errors.push(GenError::config(
+        origin.field_location("with"),
+        format!(
+          "Missing parameter `{}` required by `{}`{}",
+          name, generate.using, hint
+        ),
+      )),
+      Some(value) if !schema.accepts(value) => errors.push(GenError::config(
+        origin.field_location(format!("with/{}", name).as_str()),
+        format!("Expected `with.{}` to be {}{}", name, schema.ty, hint),
+      )),
+      _ => {}
+    }
+  }
+
+  // Generators may accept parameters their schema doesn't mention, so this
+  // isn't an error
+  for name in with.keys() {
+    if !parameters.contains_key(name) {
+      warn!(
+        "{}: `{}` doesn't declare a parameter named `{}`",
+        origin.field_location(format!("with/{}", name).as_str()),
+        generate.using,
+        name
+      );
+    }
+  }
+}
+
+/// Statistics about a valid decl tree.
+#[derive(Debug, Default)]
+pub struct Summary {
+  /// The number of `generate` decls
+  pub decls: usize,
+  /// The number of distinct generators used (that could be found)
+  pub generators: usize,
+}
+
+/// The id and location of a visited `generate` decl.
+type Visited = (String, Location);
+
+struct Validator<'a> {
+  mgr: &'a Addr<GeneratorMgrMsg>,
+  decls: usize,
+  /// The number of distinct generators that were found
+  generators: usize,
+  /// The parameters of every generator looked up so far, keyed by spec.
+  /// `None` if the generator doesn't declare its parameters, or couldn't be
+  /// found (which has already been reported).
+  parameters: HashMap<String, Option<Parameters>>,
+  errors: Vec<GenError>,
+}
+
+impl<'a> Validator<'a> {
+  async fn parameters(
+    &mut self,
+    generate: &Generate,
+    namespace: &Namespace,
+    origin: &Origin,
+  ) -> Option<Parameters> {
+    let spec = origin.resolve_generator(&generate.using);
+    let key = spec.to_string();
+    if let Some(parameters) = self.parameters.get(&key) {
+      return parameters.clone();
+    }
+
+    let parameters = match self.mgr.get_parameters(spec).await {
+      Ok(parameters) => {
+        self.generators += 1;
+        parameters
+      }
+      Err(err) => {
+        self.errors.push(GenError::Generator {
+          decl: Box::new(DeclInfo {
+            id: generate.id.clone(),
+            path: namespace.to_string(),
+            location: origin.location(),
+          }),
+          generator: generate.using.to_string(),
+          cause: Box::new(err),
+        });
+        None
+      }
+    };
+
+    self.parameters.insert(key, parameters.clone());
+    parameters
+  }
+
+  /// Visit `node` and its descendants, returning the ids and locations of
+  /// the `generate` decls it resolves to (following imports).
+  fn visit<'b>(
+    &'b mut self,
+    node: &'b Node,
+    namespace: Arc<Namespace>,
+  ) -> Pin<Box<dyn Future<Output = Vec<Visited>> + 'b>> {
+    Box::pin(async move {
+      match node {
+        Node::Import { imported, .. } => {
+          let mut visited = Vec::new();
+          for node in imported {
+            visited.extend(self.visit(node, namespace.clone()).await);
+          }
+          visited
+        }
+        Node::Generate {
+          generate,
+          origin,
+          children,
+        } => {
+          self.decls += 1;
+          let child_ns = namespace.child(generate.id.as_str());
+
+          if let Some(parameters) = self.parameters(generate, &child_ns, origin).await {
+            check_params(generate, &parameters, origin, &mut self.errors);
+          }
+
+          // Siblings with the same id would be generated into the same
+          // namespace
+          let mut ids: HashMap<String, Location> = HashMap::new();
+          for child in children {
+            for (id, location) in self.visit(child, child_ns.clone()).await {
+              match ids.get(&id) {
+                Some(first) => self.errors.push(GenError::config(
+                  location,
+                  format!(
+                    "Duplicate id `{}` in `{}` (first used at {})",
+                    id, generate.id, first
+                  ),
+                )),
+                None => {
+                  ids.insert(id, location);
+                }
+              }
+            }
+          }
+
+          vec![(generate.id.clone(), origin.field_location("id"))]
+        }
+      }
+    })
+  }
+}
+
+/// Check the decl tree rooted at `root` for problems that can be found
+/// without generating anything (e.g., missing imports, unknown generators,
+/// invalid parameters and duplicate ids), reporting all of them at once,
+/// along with the `errors` found loading it.
+pub async fn validate(
+  root: &Node,
+  errors: Vec<GenError>,
+  mgr: &Addr<GeneratorMgrMsg>,
+) -> Result<Summary, GenError> {
+  let mut validator = Validator {
+    mgr,
+    decls: 0,
+    generators: 0,
+    parameters: HashMap::new(),
+    errors,
+  };
+
+  validator.visit(root, Namespace::new("root")).await;
+
+  GenError::all(validator.errors)?;
+  Ok(Summary {
+    decls: validator.decls,
+    generators: validator.generators,
+  })
+}
@@ -0,0 +1,237 @@
+//! A format-neutral tree of configuration values, so that YAML, JSON and
+//! TOML configuration files are preprocessed the same way.
+
+use std::{
+  convert::TryFrom,
+  error::Error,
+  fmt::{self, Formatter},
+  path::Path,
+};
+
+use indexmap::IndexMap;
+use serde::{
+  de::{self, MapAccess, SeqAccess, Visitor},
+  ser::{SerializeMap, SerializeSeq},
+  Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::source::{Mark, SourceMap};
+
+/// A mapping, in the order its keys were declared.
+pub type Mapping = IndexMap<String, Value>;
+
+pub type Sequence = Vec<Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Null,
+  Bool(bool),
+  Integer(i64),
+  Float(f64),
+  String(String),
+  Sequence(Sequence),
+  Mapping(Mapping),
+}
+
+impl Value {
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Self::String(value) => Some(value.as_str()),
+      _ => None,
+    }
+  }
+
+  /// The value of a string, number or boolean as a string.
+  pub fn to_scalar_string(&self) -> Option<String> {
+    match self {
+      Self::String(value) => Some(value.clone()),
+      Self::Integer(value) => Some(value.to_string()),
+      Self::Float(value) => Some(value.to_string()),
+      Self::Bool(value) => Some(value.to_string()),
+      _ => None,
+    }
+  }
+}
+
+impl Serialize for Value {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match self {
+      Self::Null => serializer.serialize_unit(),
+      Self::Bool(value) => serializer.serialize_bool(*value),
+      Self::Integer(value) => serializer.serialize_i64(*value),
+      Self::Float(value) => serializer.serialize_f64(*value),
+      Self::String(value) => serializer.serialize_str(value.as_str()),
+      Self::Sequence(values) => {
+        let mut seq = serializer.serialize_seq(Some(values.len()))?;
+        for value in values {
+          seq.serialize_element(value)?;
+        }
+        seq.end()
+      }
+      Self::Mapping(values) => {
+        let mut map = serializer.serialize_map(Some(values.len()))?;
+        for (key, value) in values {
+          map.serialize_entry(key, value)?;
+        }
+        map.end()
+      }
+    }
+  }
+}
+
+/// The key the `toml` crate represents dates and times with.
+static TOML_DATETIME: &str = "$__toml_private_datetime";
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+  type Value = Value;
+
+  fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "any value")
+  }
+
+  fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+    Ok(Value::Null)
+  }
+
+  fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+    Ok(Value::Null)
+  }
+
+  fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+    Value::deserialize(deserializer)
+  }
+
+  fn visit_bool<E: de::Error>(self, value: bool) -> Result<Value, E> {
+    Ok(Value::Bool(value))
+  }
+
+  fn visit_i64<E: de::Error>(self, value: i64) -> Result<Value, E> {
+    Ok(Value::Integer(value))
+  }
+
+  fn visit_u64<E: de::Error>(self, value: u64) -> Result<Value, E> {
+    match i64::try_from(value) {
+      Ok(value) => Ok(Value::Integer(value)),
+      Err(_) => Ok(Value::Float(value as f64)),
+    }
+  }
+
+  fn visit_f64<E: de::Error>(self, value: f64) -> Result<Value, E> {
+    Ok(Value::Float(value))
+  }
+
+  fn visit_str<E: de::Error>(self, value: &str) -> Result<Value, E> {
+    Ok(Value::String(value.to_string()))
+  }
+
+  fn visit_string<E: de::Error>(self, value: String) -> Result<Value, E> {
+    Ok(Value::String(value))
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+    let mut values = Sequence::new();
+    while let Some(value) = seq.next_element()? {
+      values.push(value);
+    }
+    Ok(Value::Sequence(values))
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+    let mut values = Mapping::new();
+    while let Some(key) = map.next_key::<Value>()? {
+      let key = key
+        .to_scalar_string()
+        .ok_or_else(|| de::Error::custom("mapping keys must be strings"))?;
+      let value = map.next_value()?;
+      values.insert(key, value);
+    }
+
+    // Dates and times are only meaningful to generators as strings
+    if values.len() == 1 {
+      if let Some(Value::String(datetime)) = values.get(TOML_DATETIME) {
+        return Ok(Value::String(datetime.clone()));
+      }
+    }
+
+    Ok(Value::Mapping(values))
+  }
+}
+
+impl<'de> Deserialize<'de> for Value {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(ValueVisitor)
+  }
+}
+
+/// A configuration file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Yaml,
+  Json,
+  Toml,
+}
+
+/// A configuration file that couldn't be parsed.
+#[derive(Debug)]
+pub struct ParseError {
+  /// Where parsing failed, if known
+  pub mark: Option<Mark>,
+  pub cause: Box<dyn Error>,
+}
+
+impl Format {
+  /// The format of the file at `path`, by its extension. Files with any
+  /// extension other than `.json` or `.toml` are YAML.
+  pub fn from_path(path: &Path) -> Self {
+    match path.extension().and_then(|extension| extension.to_str()) {
+      Some("json") => Self::Json,
+      Some("toml") => Self::Toml,
+      _ => Self::Yaml,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Yaml => "YAML",
+      Self::Json => "JSON",
+      Self::Toml => "TOML",
+    }
+  }
+
+  pub fn parse(self, text: &str) -> Result<Value, ParseError> {
+    match self {
+      Self::Yaml => serde_yaml::from_str(text).map_err(|err| ParseError {
+        mark: err.location().map(|location| Mark {
+          line: location.line(),
+          col: location.column(),
+        }),
+        cause: Box::new(err),
+      }),
+      Self::Json => serde_json::from_str(text).map_err(|err| ParseError {
+        mark: Some(Mark {
+          line: err.line(),
+          col: err.column(),
+        }),
+        cause: Box::new(err),
+      }),
+      Self::Toml => toml::from_str(text).map_err(|err| ParseError {
+        mark: err.line_col().map(|(line, col)| Mark {
+          line: line + 1,
+          col: col + 1,
+        }),
+        cause: Box::new(err),
+      }),
+    }
+  }
+
+  /// Index the nodes of `text`, which is in this format.
+  pub fn source_map(self, text: &str) -> SourceMap {
+    match self {
+      // JSON is (close enough to) a subset of YAML
+      Self::Yaml | Self::Json => SourceMap::parse(text),
+      Self::Toml => SourceMap::parse_toml(text),
+    }
+  }
+}
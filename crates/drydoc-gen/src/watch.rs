@@ -0,0 +1,308 @@
+//! Regenerate the website whenever its configuration or inputs change.
+//!
+//! Every input is mapped to the decls generated from it, so that only those
+//! are regenerated when it changes. The bundles of the others are reused from
+//! the previous build.
+
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Clap;
+use drydoc_model::{decl::GeneratorSpec, ns::Namespace};
+use futures::FutureExt;
+use log::{error, info};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{
+  build_tree,
+  cache::{self, Memo},
+  config::{self, Node},
+  error::GenError,
+  GenCtx, GenOpts,
+};
+
+/// Written into the output directory after every successful rebuild.
+/// `drydoc serve` polls this file and tells connected browsers to reload
+/// when it changes.
+static RELOAD_STAMP: &str = ".drydoc-reload";
+
+/// Regenerate the website whenever its configuration or inputs change.
+#[derive(Clap, Debug)]
+pub struct WatchOpts {
+  /// Milliseconds to wait for further changes before regenerating
+  #[clap(long, default_value = "200")]
+  delay: u64,
+}
+
+/// A file or directory the website is generated from.
+struct Input {
+  path: PathBuf,
+  /// The paths of the decls that must be regenerated when anything in
+  /// `path` changes
+  decls: Vec<String>,
+  /// Whether the descendants of `decls` must be regenerated too (i.e.,
+  /// `path` declares them)
+  descendants: bool,
+}
+
+/// The paths of the `generate` decls `node`, in `namespace`, resolves to
+/// (following imports).
+fn decl_paths(node: &Node, namespace: &Arc<Namespace>, paths: &mut Vec<String>) {
+  match node {
+    Node::Import { imported, .. } => {
+      for node in imported {
+        decl_paths(node, namespace, paths);
+      }
+    }
+    Node::Generate { generate, .. } => {
+      paths.push(namespace.child(generate.id.as_str()).to_string());
+    }
+  }
+}
+
+/// The configuration file or directory `path`, on which the decls `node`
+/// resolves to and their descendants depend.
+fn config_input(path: PathBuf, node: &Node, namespace: &Arc<Namespace>) -> Input {
+  let mut decls = Vec::new();
+  decl_paths(node, namespace, &mut decls);
+  Input {
+    path,
+    decls,
+    descendants: true,
+  }
+}
+
+/// Append every file the decl tree rooted at `node`, in `namespace`, is
+/// generated from to `inputs`. This includes imported configuration files and
+/// the paths named by generator parameters.
+///
+/// Decls depend on the file they're declared in through their topmost
+/// ancestor in it, since a change to it may change the variables and
+/// templates in scope for them too.
+fn collect_inputs(node: &Node, namespace: &Arc<Namespace>, inputs: &mut Vec<Input>) {
+  match node {
+    Node::Import {
+      import,
+      origin,
+      imported,
+    } => {
+      // Watch for files that would be matched if they were created. Those
+      // already matched may be renamed to make room for them.
+      if import.optional || config::is_glob(import.path.as_str()) {
+        inputs.push(config_input(
+          config::import_dir(origin, import),
+          node,
+          namespace,
+        ));
+      }
+
+      for node in imported {
+        // Files fetched over HTTP are pinned by their integrity, so they
+        // can't change
+        if let Some(file) = node.origin().local_file() {
+          inputs.push(config_input(file, node, namespace));
+        }
+        collect_inputs(node, namespace, inputs);
+      }
+    }
+    Node::Generate {
+      generate,
+      origin,
+      children,
+    } => {
+      let child_ns = namespace.child(generate.id.as_str());
+      let input = |path: PathBuf| Input {
+        path,
+        decls: vec![child_ns.to_string()],
+        descendants: false,
+      };
+
+      let dir = origin.dir();
+      if let GeneratorSpec::Path(generator) = &generate.using {
+        inputs.push(input(dir.join(generator)));
+      }
+
+      for value in generate.with.values() {
+        inputs.extend(cache::input_paths(dir, value).into_iter().map(input));
+      }
+
+      for child in children {
+        collect_inputs(child, &child_ns, inputs);
+      }
+    }
+  }
+}
+
+async fn write_stamp(opts: &GenOpts) -> std::io::Result<()> {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default();
+  let stamp = Path::new(opts.output.as_str()).join(RELOAD_STAMP);
+  tokio::fs::write(stamp, now.as_millis().to_string()).await
+}
+
+/// The paths an event is about.
+fn event_paths(event: DebouncedEvent) -> Result<Vec<PathBuf>, GenError> {
+  match event {
+    DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Remove(path) => {
+      Ok(vec![path])
+    }
+    DebouncedEvent::Rename(from, to) => Ok(vec![from, to]),
+    DebouncedEvent::Error(err, path) => Err(GenError::Io {
+      path,
+      cause: Box::new(err),
+    }),
+    _ => Ok(Vec::new()),
+  }
+}
+
+/// Wait until anything changes, returning every path that changed since the
+/// last call (including while building). Changes within `ignored`
+/// directories (i.e., our own output) don't count.
+async fn wait_for_changes(
+  events: &mut UnboundedReceiver<DebouncedEvent>,
+  ignored: &[PathBuf],
+) -> Result<Vec<PathBuf>, GenError> {
+  let mut changed = Vec::new();
+  while changed.is_empty() {
+    let event = match events.recv().await {
+      Some(event) => event,
+      None => return Ok(changed),
+    };
+    changed.extend(event_paths(event)?);
+
+    // Changes that arrived at the same time are handled together
+    while let Some(Some(event)) = events.recv().now_or_never() {
+      changed.extend(event_paths(event)?);
+    }
+
+    changed.retain(|path| !ignored.iter().any(|ignored| path.starts_with(ignored)));
+  }
+
+  for path in &changed {
+    info!("{} changed", path.display());
+  }
+  Ok(changed)
+}
+
+pub async fn watch(opts: &GenOpts, watch_opts: &WatchOpts, ctx: &GenCtx) -> Result<(), GenError> {
+  let memo = Memo::default();
+  let ctx = GenCtx {
+    memo: Some(memo.clone()),
+    ..ctx.clone()
+  };
+
+  // Created before the first build, and kept across builds, so that changes
+  // made while building aren't missed
+  let (tx, rx) = std::sync::mpsc::channel();
+  let mut watcher =
+    watcher(tx, Duration::from_millis(watch_opts.delay)).map_err(|err| GenError::Io {
+      path: None,
+      cause: Box::new(err),
+    })?;
+
+  // notify delivers events over a std channel. Forward them onto a
+  // tokio channel from a separate thread so we don't block the runtime.
+  let (async_tx, mut events) = unbounded_channel();
+  std::thread::spawn(move || {
+    while let Ok(event) = rx.recv() {
+      if async_tx.send(event).is_err() {
+        break;
+      }
+    }
+  });
+
+  let config_path = PathBuf::from(opts.config.as_str());
+  let mut watched: HashSet<PathBuf> = HashSet::new();
+  loop {
+    // Local imports may have changed since they were last read
+    ctx.resources.forget_local();
+
+    let root_ns = Namespace::new("root");
+    let mut inputs = Vec::new();
+    let tree = config::load_tree(&opts.config, &ctx.preprocess, &ctx.resources).await;
+    match &tree {
+      Ok((root, _)) => {
+        inputs.push(config_input(config_path.clone(), root, &root_ns));
+        collect_inputs(root, &root_ns, &mut inputs);
+      }
+      // Nothing is known to be unchanged until it loads again
+      Err(_) => memo.clear(),
+    }
+
+    // Event paths are always absolute, so inputs are canonicalized to match
+    let inputs: Vec<Input> = inputs
+      .into_iter()
+      .filter_map(|input| {
+        Some(Input {
+          path: std::fs::canonicalize(&input.path).ok()?,
+          ..input
+        })
+      })
+      .collect();
+
+    // Always watch the root config, even if it failed to load
+    let mut paths: HashSet<PathBuf> = inputs.iter().map(|input| input.path.clone()).collect();
+    paths.extend(std::fs::canonicalize(&config_path).ok());
+    for path in watched.difference(&paths) {
+      let _ = watcher.unwatch(path);
+    }
+    for path in paths.difference(&watched) {
+      watcher
+        .watch(path, RecursiveMode::Recursive)
+        .map_err(|err| GenError::io(path, err))?;
+    }
+    watched = paths;
+
+    let built = match tree {
+      Ok((root, errors)) => build_tree(opts, &ctx, root, errors).await,
+      Err(err) => Err(err),
+    };
+    match built {
+      Ok(()) => {
+        write_stamp(opts)
+          .await
+          .map_err(|err| GenError::io(&opts.output, err))?;
+        info!("Generated {}. Watching for changes...", opts.output);
+      }
+      Err(err) => {
+        crate::report(&err);
+        error!("Generation failed. Watching for changes...");
+      }
+    }
+
+    // Generating may have created these directories, so canonicalize them
+    // now
+    let ignored: Vec<PathBuf> = [opts.output.as_str(), opts.cache_dir.as_str()]
+      .iter()
+      .filter_map(|dir| std::fs::canonicalize(dir).ok())
+      .collect();
+
+    let changed = wait_for_changes(&mut events, &ignored).await?;
+    for path in &changed {
+      for input in inputs.iter().filter(|input| path.starts_with(&input.path)) {
+        for decl in &input.decls {
+          if input.descendants {
+            memo.invalidate_tree(decl);
+          } else {
+            memo.invalidate(decl);
+          }
+        }
+      }
+
+      // Editors often replace files rather than writing to them, which ends
+      // the watch on them. Watch them again before anything else changes.
+      if watched.remove(path) {
+        let _ = watcher.unwatch(path);
+        if watcher.watch(path, RecursiveMode::Recursive).is_ok() {
+          watched.insert(path.clone());
+        }
+      }
+    }
+  }
+}
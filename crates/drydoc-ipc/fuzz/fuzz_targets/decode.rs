@@ -0,0 +1,37 @@
+#![no_main]
+
+use bytes::BytesMut;
+use drydoc_ipc::MessageCodec;
+use drydoc_model::Message;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+  // The first byte picks where the rest is split in two, so partial reads
+  // (including split headers) are covered
+  let (split, data) = match data.split_first() {
+    Some((split, data)) => (*split as usize, data),
+    None => return,
+  };
+  let (first, second) = data.split_at(std::cmp::min(split, data.len()));
+
+  let mut codec = MessageCodec::with_max_frame_size(4096);
+  let mut buf = BytesMut::new();
+  for chunk in [first, second].iter() {
+    buf.extend_from_slice(chunk);
+    loop {
+      match codec.decode(&mut buf) {
+        Ok(Some(message)) => {
+          assert!(message.encoding().is_some());
+          assert!(message.size() <= 4096);
+          assert_eq!(
+            message.size() as usize + Message::HEADER_SIZE,
+            message.raw().len()
+          );
+        }
+        Ok(None) => break,
+        Err(_) => return,
+      }
+    }
+  }
+});
@@ -1,55 +1,308 @@
-use std::collections::VecDeque;
+//! The framing of messages exchanged with generators. Each frame is a
+//! `Message`: a little-endian `u32` size, followed by that many bytes holding
+//! the message's encoding and data.
 
+use bytes::BytesMut;
+use derive_more::{Display, Error};
 use drydoc_model::Message;
+use tokio_util::codec::{Decoder, Encoder};
 
-use std::mem::size_of;
+/// The largest frame a `MessageCodec` accepts by default (1 GiB).
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 1 << 30;
 
-use tokio::io::{AsyncRead, AsyncWrite};
+#[derive(Display, Debug, Error)]
+pub enum CodecError {
+  /// The underlying stream failed.
+  #[display(fmt = "{}", source)]
+  Io { source: std::io::Error },
+  /// A frame is larger than the codec accepts. The stream can't be read
+  /// past it.
+  #[display(fmt = "A {} byte frame exceeds the maximum of {} bytes", size, max)]
+  TooLarge { size: u32, max: u32 },
+  /// A frame isn't a valid message (it's empty, or has an unknown
+  /// encoding).
+  #[display(fmt = "Invalid message")]
+  Invalid,
+}
+
+impl From<std::io::Error> for CodecError {
+  fn from(source: std::io::Error) -> Self {
+    Self::Io { source }
+  }
+}
 
-pub struct MessageProcessor {
-  pending: VecDeque<u8>,
+/// Decodes and encodes `Message`s, for use with `FramedRead` and
+/// `FramedWrite`.
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+  max_frame_size: u32,
 }
 
-impl MessageProcessor {
+impl MessageCodec {
   pub fn new() -> Self {
-    Self {
-      pending: VecDeque::new(),
-    }
+    Self::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
   }
 
-  pub fn submit(&mut self, data: &[u8]) {
-    self.pending.extend(data);
+  /// A codec rejecting frames of more than `max_frame_size` bytes (excluding
+  /// their header).
+  pub fn with_max_frame_size(max_frame_size: u32) -> Self {
+    Self { max_frame_size }
   }
 
-  pub fn next(&mut self) -> Option<Message> {
-    if self.pending.len() < size_of::<u32>() {
-      return None;
+  fn check_size(&self, size: u32) -> Result<(), CodecError> {
+    if size > self.max_frame_size {
+      Err(CodecError::TooLarge {
+        size,
+        max: self.max_frame_size,
+      })
+    } else {
+      Ok(())
     }
+  }
+}
 
-    let mut size = [0u8; 4];
+impl Default for MessageCodec {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 
-    let (left, right) = self.pending.as_slices();
-    size.copy_from_slice(left);
-    if left.len() < size.len() {
-      size[left.len()..].copy_from_slice(right);
-    }
+impl Decoder for MessageCodec {
+  type Item = Message;
+  type Error = CodecError;
 
-    let size = u32::from_le_bytes(size) + size_of::<u8>() as u32;
-    if self.pending.len() - size_of::<u32>() < size as usize {
-      return None;
+  fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+    if src.len() < Message::HEADER_SIZE {
+      return Ok(None);
     }
 
-    let mut raw = Vec::new();
-    let pos = std::cmp::min(left.len(), size as usize);
-    raw.extend(&left[0..pos]);
-    raw.extend(&right[0..size as usize - pos]);
+    let mut size = [0u8; Message::HEADER_SIZE];
+    size.copy_from_slice(&src[..Message::HEADER_SIZE]);
+    let size = u32::from_le_bytes(size);
+    // Checked before waiting for the rest of the frame, so an oversized frame
+    // is never buffered
+    self.check_size(size)?;
 
-    Some(Message::decode(raw.into_boxed_slice()).unwrap())
+    let frame_size = Message::HEADER_SIZE + size as usize;
+    if src.len() < frame_size {
+      src.reserve(frame_size - src.len());
+      return Ok(None);
+    }
+
+    let frame = src.split_to(frame_size);
+    Message::decode(frame.to_vec().into_boxed_slice())
+      .map(Some)
+      .map_err(|_| CodecError::Invalid)
   }
 }
 
-pub struct MessageInputStream<R: AsyncRead> {
-  read: R,
+impl Encoder<Message> for MessageCodec {
+  type Error = CodecError;
+
+  fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+    self.check_size(message.size())?;
+    dst.extend_from_slice(message.raw());
+    Ok(())
+  }
 }
 
-impl<R: AsyncRead> MessageInputStream<R> {}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use drydoc_model::Encoding;
+  use proptest::{collection::vec, prelude::*};
+  use tokio::io::AsyncWriteExt;
+  use tokio_util::codec::FramedRead;
+
+  /// A raw frame holding `data` in `encoding`.
+  fn frame(encoding: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = (data.len() as u32 + 1).to_le_bytes().to_vec();
+    frame.push(encoding);
+    frame.extend_from_slice(data);
+    frame
+  }
+
+  fn encoding() -> impl Strategy<Value = u8> {
+    prop_oneof![
+      Just(Encoding::Json.as_byte()),
+      Just(Encoding::Pickle.as_byte()),
+      Just(Encoding::Bincode.as_byte()),
+    ]
+  }
+
+  fn frames() -> impl Strategy<Value = Vec<Vec<u8>>> {
+    vec(
+      (encoding(), vec(any::<u8>(), 0..300)).prop_map(|(encoding, data)| frame(encoding, &data)),
+      0..8,
+    )
+  }
+
+  /// Split `bytes` into chunks whose sizes cycle through `sizes`.
+  fn chunk<'a>(bytes: &'a [u8], sizes: &[usize]) -> Vec<&'a [u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = bytes;
+    for size in sizes.iter().cycle() {
+      if rest.is_empty() {
+        break;
+      }
+      let (chunk, next) = rest.split_at(std::cmp::min(*size, rest.len()));
+      chunks.push(chunk);
+      rest = next;
+    }
+    chunks
+  }
+
+  /// Feed `chunks` to a codec one at a time, decoding as many messages as
+  /// possible after each, until the codec fails.
+  fn decode_chunks(
+    codec: &mut MessageCodec,
+    chunks: &[&[u8]],
+  ) -> (Vec<Message>, Option<CodecError>, BytesMut) {
+    let mut buf = BytesMut::new();
+    let mut messages = Vec::new();
+    for chunk in chunks {
+      buf.extend_from_slice(chunk);
+      loop {
+        match codec.decode(&mut buf) {
+          Ok(Some(message)) => messages.push(message),
+          Ok(None) => break,
+          Err(err) => return (messages, Some(err), buf),
+        }
+      }
+    }
+    (messages, None, buf)
+  }
+
+  fn raw(messages: &[Message]) -> Vec<Vec<u8>> {
+    messages
+      .iter()
+      .map(|message| message.raw().to_vec())
+      .collect()
+  }
+
+  proptest! {
+    /// However the stream is split across reads (including through a
+    /// header), the same messages are decoded and every byte is consumed.
+    #[test]
+    fn decodes_partial_reads(frames in frames(), sizes in vec(1..64usize, 1..16)) {
+      let bytes = frames.concat();
+      let (messages, err, rest) = decode_chunks(&mut MessageCodec::new(), &chunk(&bytes, &sizes));
+      prop_assert!(err.is_none());
+      prop_assert!(rest.is_empty());
+      prop_assert_eq!(raw(&messages), frames);
+    }
+
+    /// Encoding decoded messages reproduces the stream.
+    #[test]
+    fn encodes_decoded_messages(frames in frames()) {
+      let bytes = frames.concat();
+      let (messages, _, _) = decode_chunks(&mut MessageCodec::new(), &[&bytes]);
+      let mut encoded = BytesMut::new();
+      for message in messages {
+        MessageCodec::new().encode(message, &mut encoded).unwrap();
+      }
+      prop_assert_eq!(encoded.to_vec(), bytes);
+    }
+
+    /// A frame larger than the maximum is rejected as soon as its header
+    /// arrives, without waiting for (or buffering) its data.
+    #[test]
+    fn rejects_oversized_frames(max in 0..1024u32, excess in 1..=u32::MAX - 1024, split in 0..4usize) {
+      let size = max + excess;
+      let header = size.to_le_bytes();
+      let mut codec = MessageCodec::with_max_frame_size(max);
+      let (messages, err, _) = decode_chunks(&mut codec, &[&header[..split], &header[split..]]);
+      prop_assert!(messages.is_empty());
+      match err {
+        Some(CodecError::TooLarge { size: actual, max: actual_max }) => {
+          prop_assert_eq!(actual, size);
+          prop_assert_eq!(actual_max, max);
+        }
+        err => prop_assert!(false, "Expected TooLarge, got {:?}", err),
+      }
+    }
+
+    /// Arbitrary input never panics the decoder. Whatever it decodes is a
+    /// well-formed message, and it never consumes more than it was given.
+    #[test]
+    fn fuzz_decode(bytes in vec(any::<u8>(), 0..2048), sizes in vec(1..256usize, 1..8), max in 0..4096u32) {
+      let mut codec = MessageCodec::with_max_frame_size(max);
+      let (messages, _, rest) = decode_chunks(&mut codec, &chunk(&bytes, &sizes));
+      let mut consumed = 0;
+      for message in &messages {
+        prop_assert!(message.encoding().is_some());
+        prop_assert!(message.size() <= max);
+        prop_assert_eq!(message.size() as usize + Message::HEADER_SIZE, message.raw().len());
+        consumed += message.raw().len();
+      }
+      prop_assert!(consumed + rest.len() <= bytes.len());
+      prop_assert_eq!(raw(&messages).concat(), bytes[..consumed].to_vec());
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_frames() {
+    // An empty frame has no encoding
+    let mut buf = BytesMut::from(&0u32.to_le_bytes()[..]);
+    assert!(matches!(
+      MessageCodec::new().decode(&mut buf),
+      Err(CodecError::Invalid)
+    ));
+
+    let mut buf = BytesMut::from(&frame(42, b"{}")[..]);
+    assert!(matches!(
+      MessageCodec::new().decode(&mut buf),
+      Err(CodecError::Invalid)
+    ));
+  }
+
+  #[test]
+  fn rejects_oversized_messages() {
+    let message = Message::encode(Encoding::Json, &"A long string").unwrap();
+    let mut buf = BytesMut::new();
+    assert!(matches!(
+      MessageCodec::with_max_frame_size(4).encode(message, &mut buf),
+      Err(CodecError::TooLarge { max: 4, .. })
+    ));
+    assert!(buf.is_empty());
+  }
+
+  #[tokio::test]
+  async fn reads_messages_from_a_stream() {
+    use futures::StreamExt;
+
+    let frames = vec![frame(0, b"\"first\""), frame(2, &[1, 2, 3]), frame(1, b"")];
+    let bytes = frames.concat();
+    let (mut tx, rx) = tokio::io::duplex(8);
+    let writer = tokio::spawn(async move {
+      // Written a byte at a time, so every read is partial
+      for byte in bytes {
+        tx.write_all(&[byte]).await.unwrap();
+      }
+    });
+
+    let messages: Vec<Message> = FramedRead::new(rx, MessageCodec::new())
+      .map(Result::unwrap)
+      .collect()
+      .await;
+    writer.await.unwrap();
+    assert_eq!(raw(&messages), frames);
+  }
+
+  #[tokio::test]
+  async fn reports_truncated_streams() {
+    use futures::StreamExt;
+
+    let bytes = frame(0, b"\"truncated\"");
+    let (mut tx, rx) = tokio::io::duplex(64);
+    tx.write_all(&bytes[..bytes.len() - 1]).await.unwrap();
+    drop(tx);
+
+    let mut read = FramedRead::new(rx, MessageCodec::new());
+    assert!(matches!(
+      read.next().await,
+      Some(Err(CodecError::Io { .. }))
+    ));
+  }
+}
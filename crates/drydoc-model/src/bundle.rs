@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap};
 
 use super::*;
 
@@ -6,24 +6,98 @@ use page::{Id, Page};
 
 use crate::fs::Entry;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Manifest {
   pub root: Id,
   pub symbols: HashMap<String, Vec<Id>>,
   pub pages: HashMap<Id, Page>,
 }
 
+/// Which of two conflicting items to keep when merging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+  First,
+  Second,
+}
+
 impl Manifest {
+  /// Merge `other` into this manifest. If both define a page with the same
+  /// id, the one from `other` is kept.
   pub fn merge(&mut self, other: Manifest) {
-    self.symbols.extend(other.symbols);
-    self.pages.extend(other.pages);
+    self.merge_reporting(other, Keep::Second);
+  }
+
+  /// Merge `other` into this manifest, returning the ids of the pages both
+  /// define. `keep` decides which of the two pages is kept.
+  pub fn merge_reporting(&mut self, other: Manifest, keep: Keep) -> Vec<Id> {
+    // A symbol may be documented by pages from both manifests
+    for (symbol, ids) in other.symbols {
+      let existing = self.symbols.entry(symbol).or_default();
+      for id in ids {
+        if !existing.contains(&id) {
+          existing.push(id);
+        }
+      }
+    }
+
+    let mut conflicts = Vec::new();
+    for (id, page) in other.pages {
+      match self.pages.entry(id) {
+        hash_map::Entry::Vacant(entry) => {
+          entry.insert(page);
+        }
+        hash_map::Entry::Occupied(mut entry) => {
+          conflicts.push(entry.key().clone());
+          if keep == Keep::Second {
+            entry.insert(page);
+          }
+        }
+      }
+    }
+
     if let Some(root) = self.pages.get_mut(&self.root) {
-      root.children.insert(other.root);
+      root.add_child(other.root);
     }
+
+    conflicts
   }
+
+  /// Sort the children of the page `id` by their `order`. Children without
+  /// an `order` keep their relative positions, after those with one.
+  pub fn sort_children(&mut self, id: &Id) {
+    let mut children = match self.pages.get_mut(id) {
+      Some(page) => std::mem::take(&mut page.children),
+      None => return,
+    };
+
+    let pages = &self.pages;
+    children.sort_by_key(|child| match pages.get(child).and_then(|page| page.order) {
+      Some(order) => (0, order),
+      None => (1, 0),
+    });
+
+    if let Some(page) = self.pages.get_mut(id) {
+      page.children = children;
+    }
+  }
+}
+
+/// The items two merged bundles both defined.
+#[derive(Debug, Default)]
+pub struct Conflicts {
+  /// Ids of pages
+  pub pages: Vec<Id>,
+  /// `/`-separated paths of resources
+  pub resources: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Conflicts {
+  pub fn is_empty(&self) -> bool {
+    self.pages.is_empty() && self.resources.is_empty()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Bundle {
   pub manifest: Manifest,
   pub resources: fs::Folder,
@@ -41,9 +115,26 @@ impl Bundle {
     Ok(self)
   }
 
-  pub fn merge(mut self, other: Bundle) -> std::io::Result<Bundle> {
-    self.manifest.merge(other.manifest);
-    self.resources = self.resources.merge(other.resources)?;
-    Ok(self)
+  pub fn merge(self, other: Bundle) -> std::io::Result<Bundle> {
+    Ok(self.merge_reporting(other, Keep::Second)?.0)
+  }
+
+  /// Merge `other` into this bundle, also returning the pages and resources
+  /// both define. `keep` decides which of each pair is kept.
+  pub fn merge_reporting(
+    mut self,
+    other: Bundle,
+    keep: Keep,
+  ) -> std::io::Result<(Bundle, Conflicts)> {
+    let pages = self.manifest.merge_reporting(other.manifest, keep);
+    let (resources, resource_conflicts) = self.resources.merge_reporting(other.resources, keep)?;
+    self.resources = resources;
+    Ok((
+      self,
+      Conflicts {
+        pages,
+        resources: resource_conflicts,
+      },
+    ))
   }
 }
@@ -15,11 +15,29 @@ pub enum Event {
   Progress(ProgressEvent),
 }
 
+/// Open a file through the host, for generators that can't read the file
+/// system themselves.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OpenRequest {
+  /// The file to open, relative to the configuration file of the decl being
+  /// generated (or to the project root, without `request`)
   pub path: String,
+  /// The id of the `Generate` request the file is opened for
+  #[serde(default)]
+  pub request: Option<u64>,
 }
 
+/// Read up to `len` bytes of an open file, starting at `offset`. Fewer bytes
+/// are returned at the end of the file, or if `len` is larger than the host
+/// reads at once.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadRequest {
+  pub handle: LinkedFileHandle,
+  pub offset: u64,
+  pub len: u32,
+}
+
+/// Close a file opened with an `OpenRequest`.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ReleaseRequest {
   pub handle: LinkedFileHandle,
@@ -28,6 +46,7 @@ pub struct ReleaseRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RequestData {
   Open(OpenRequest),
+  Read(ReadRequest),
   Release(ReleaseRequest),
 }
 
@@ -37,9 +56,16 @@ pub struct Request {
   pub data: RequestData,
 }
 
+/// The generator's half of the handshake.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InitializeResponse {
+  /// The IPC version the generator speaks, which must match the host's
+  pub version: u32,
+  /// The encoding messages are sent with after the handshake, chosen from
+  /// the host's `supported_encodings`
   pub encoding: Encoding,
+  /// Whether the generator reads its inputs from the file system itself,
+  /// rather than through `Open` requests
   pub requires_direct_fs_access: bool,
 }
 
@@ -56,12 +82,30 @@ pub struct GenerateResponse {
   pub bundle: Bundle,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadResponse {
+  /// The bytes read, which are empty at the end of the file
+  pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReleaseResponse {}
+
+/// Sent in place of a response when a request couldn't be handled.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+  pub message: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ResponseData {
   Initialize(InitializeResponse),
   OpenContext(OpenContextResponse),
   CloseContext(CloseContextResponse),
   Generate(GenerateResponse),
+  Read(ReadResponse),
+  Release(ReleaseResponse),
+  Error(ErrorResponse),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
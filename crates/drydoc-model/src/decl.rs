@@ -1,23 +1,447 @@
-use serde::{Deserialize, Serialize};
+use schemars::{
+  gen::SchemaGenerator,
+  schema::{InstanceType, Metadata, Schema, SchemaObject, StringValidation},
+  JsonSchema,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use std::collections::HashMap;
+use derive_more::{Display, Error};
+use semver::{ReqParseError, VersionReq};
 
-#[derive(Serialize, Deserialize, Debug)]
+use std::{collections::BTreeMap, fmt::Formatter, path::PathBuf, str::FromStr};
+
+use crate::page::Page;
+
+/// The parameters of a `Generate` decl. Values may be arbitrary YAML (strings,
+/// numbers, booleans, lists and mappings).
+pub type Params = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Display, Debug, Error)]
+pub enum GeneratorSpecError {
+  #[display(fmt = "No generator specified")]
+  Empty,
+  #[display(fmt = "No path specified after `path:`")]
+  EmptyPath,
+  #[display(
+    fmt = "`{}` is not a valid name (expected letters, digits, `-`, `_` and `.`)",
+    name
+  )]
+  InvalidName { name: String },
+  #[display(fmt = "`{}` is not a valid version requirement", version_req)]
+  InvalidVersionReq {
+    version_req: String,
+    source: ReqParseError,
+  },
+}
+
+/// The generator a `Generate` decl is generated with. Parsed from one of:
+///  - `name` or `name@version_req`, a package in the default repository
+///  - `repository/name` or `repository/name@version_req`, a package in the
+///    repository named `repository`
+///  - `path:./my-generator`, an unpackaged generator on the local filesystem
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeneratorSpec {
+  Package {
+    repository: Option<String>,
+    name: String,
+    version_req: VersionReq,
+  },
+  Path(PathBuf),
+}
+
+/// Check that `name` is a valid package or repository name. Names only
+/// contain letters, digits, `-`, `_` and `.`, since they're used as
+/// directory names (so can't be `.` or `..` either).
+pub fn validate_name(name: &str) -> Result<(), GeneratorSpecError> {
+  let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+  if name.is_empty() || name == "." || name == ".." || !name.chars().all(valid) {
+    return Err(GeneratorSpecError::InvalidName {
+      name: name.to_string(),
+    });
+  }
+
+  Ok(())
+}
+
+impl FromStr for GeneratorSpec {
+  type Err = GeneratorSpecError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    if s.is_empty() {
+      return Err(GeneratorSpecError::Empty);
+    }
+
+    if let Some(path) = s.strip_prefix("path:") {
+      if path.is_empty() {
+        return Err(GeneratorSpecError::EmptyPath);
+      }
+
+      return Ok(Self::Path(PathBuf::from(path)));
+    }
+
+    let (qualified_name, version_req) = match s.split_once('@') {
+      Some((qualified_name, version_req)) => (
+        qualified_name,
+        VersionReq::parse(version_req).map_err(|source| GeneratorSpecError::InvalidVersionReq {
+          version_req: version_req.to_string(),
+          source,
+        })?,
+      ),
+      None => (s, VersionReq::any()),
+    };
+
+    let (repository, name) = match qualified_name.split_once('/') {
+      Some((repository, name)) => {
+        validate_name(repository)?;
+        (Some(repository.to_string()), name)
+      }
+      None => (None, qualified_name),
+    };
+
+    validate_name(name)?;
+
+    Ok(Self::Package {
+      repository,
+      name: name.to_string(),
+      version_req,
+    })
+  }
+}
+
+impl std::fmt::Display for GeneratorSpec {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Package {
+        repository,
+        name,
+        version_req,
+      } => {
+        if let Some(repository) = repository {
+          write!(f, "{}/", repository)?;
+        }
+        write!(f, "{}", name)?;
+        if *version_req != VersionReq::any() {
+          write!(f, "@{}", version_req)?;
+        }
+        Ok(())
+      }
+      Self::Path(path) => write!(f, "path:{}", path.display()),
+    }
+  }
+}
+
+impl Serialize for GeneratorSpec {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for GeneratorSpec {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(de::Error::custom)
+  }
+}
+
+impl JsonSchema for GeneratorSpec {
+  fn schema_name() -> String {
+    "GeneratorSpec".to_string()
+  }
+
+  fn json_schema(_: &mut SchemaGenerator) -> Schema {
+    Schema::Object(SchemaObject {
+      instance_type: Some(InstanceType::String.into()),
+      metadata: Some(Box::new(Metadata {
+        description: Some(
+          "The generator to generate the decl with: `name`, `name@version_req`, \
+           `repository/name@version_req` or `path:./my-generator`"
+            .to_string(),
+        ),
+        ..Default::default()
+      })),
+      // Mirrors `GeneratorSpec::from_str` and `validate_name`
+      string: Some(Box::new(StringValidation {
+        pattern: Some(r"^\s*(path:.+|([A-Za-z0-9._-]+/)?[A-Za-z0-9._-]+(@.+)?)\s*$".to_string()),
+        ..Default::default()
+      })),
+      ..Default::default()
+    })
+  }
+}
+
+/// Overrides applied to the root page of the bundle a `Generate` decl
+/// generates.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+pub struct PageOverrides {
+  /// Where the page is listed among its siblings. Pages with an `order` come
+  /// first, in ascending order, followed by the rest in the order they're
+  /// declared.
+  #[serde(default)]
+  pub order: Option<i64>,
+  /// Replaces the page's name
+  #[serde(default)]
+  pub title: Option<String>,
+  /// Hides the page from navigation
+  #[serde(default)]
+  pub hidden: Option<bool>,
+  /// The icon shown next to the page in navigation (a Font Awesome icon
+  /// name, e.g. `book`)
+  #[serde(default)]
+  pub icon: Option<String>,
+  /// Merged into the page's metadata, replacing existing keys
+  #[serde(default)]
+  pub metadata: BTreeMap<String, String>,
+}
+
+impl PageOverrides {
+  /// Apply the overrides to `page`. The icon is stored in the `icon`
+  /// metadata key.
+  pub fn apply(&self, page: &mut Page) {
+    if let Some(order) = self.order {
+      page.order = Some(order);
+    }
+    if let Some(title) = &self.title {
+      page.name = title.clone();
+    }
+    if let Some(hidden) = self.hidden {
+      page.hidden = Some(hidden);
+    }
+    if let Some(icon) = &self.icon {
+      page.metadata.insert("icon".to_string(), icon.clone());
+    }
+    page.metadata.extend(
+      self
+        .metadata
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone())),
+    );
+  }
+}
+
+/// Fields shared by the `generate` decls that extend it. Each field is used
+/// unless the decl sets it itself, except that `with` is merged parameter by
+/// parameter and `children` child by child, preferring the decl's.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+  /// The template this one extends, which must be declared before it
+  #[serde(default)]
+  pub extends: Option<String>,
+  #[serde(default)]
+  pub using: Option<GeneratorSpec>,
+  /// Default parameters
+  #[serde(default)]
+  pub with: Option<Params>,
+  /// Children generated before the decl's own. A child of the decl with the
+  /// same `id` replaces the template's.
+  #[serde(default)]
+  pub children: Option<Vec<Decl>>,
+}
+
+/// Templates, by name. Names may only contain letters, digits, `_` and `-`.
+pub type Templates = BTreeMap<String, Template>;
+
+/// A string, or a list of strings.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Strings {
+  One(String),
+  Many(Vec<String>),
+}
+
+/// Conditions that must all hold.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Conditions {
+  /// Any of the profiles is selected with `--profile`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub profile: Option<Strings>,
+  /// Every environment variable is set to a non-empty value
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub env: Option<Strings>,
+  /// The target is any of the operating systems (e.g., `linux`, `macos` or
+  /// `windows`)
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub os: Option<Strings>,
+  /// The condition doesn't hold
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub not: Option<Box<Condition>>,
+  /// Every condition holds
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub all: Option<Vec<Condition>>,
+  /// Any of the conditions holds
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub any: Option<Vec<Condition>>,
+}
+
+/// Whether a decl is included: `true`, `false`, or conditions that must all
+/// hold.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Condition {
+  Constant(bool),
+  All(Conditions),
+}
+
+/// The value of a variable. Numbers and booleans are substituted as strings.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Var {
+  String(String),
+  Integer(i64),
+  Float(f64),
+  Boolean(bool),
+}
+
+/// The fields any decl may set that are applied while the configuration is
+/// preprocessed, so they're empty once it's loaded.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+pub struct Preprocess {
+  /// Only include the decl (and its descendants) if this condition holds
+  #[serde(default)]
+  pub when: Option<Box<Condition>>,
+  /// Variables for `${name}` substitutions in this decl, its descendants and
+  /// the files they import. Names may only contain letters, digits, `_` and
+  /// `-`.
+  #[serde(default)]
+  pub vars: BTreeMap<String, Var>,
+  /// Templates that `generate` decls in this decl, its descendants and the
+  /// files they import can extend
+  #[serde(default)]
+  pub templates: Templates,
+}
+
+/// Generates pages and resources with a generator.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct Generate {
+  /// Identifies the decl among its siblings. Can't be empty or contain `/`.
   pub id: String,
-  pub using: String,
-  pub with: HashMap<String, String>,
+  pub using: GeneratorSpec,
+  /// The generator's parameters. Defaults to none.
+  #[serde(default)]
+  pub with: Params,
+  /// Decls generated beneath this one
   pub children: Option<Vec<Decl>>,
+  /// The template to take `using`, `with` and `children` from. Applied while
+  /// the configuration is preprocessed.
+  #[serde(default)]
+  pub extends: Option<String>,
+  #[serde(flatten)]
+  pub preprocess: Preprocess,
+  #[serde(flatten)]
+  pub page: PageOverrides,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Includes the decl declared by another configuration file.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct Import {
+  /// The configuration file to import, relative to the importing file. May
+  /// be a glob (e.g., `packages/*/drydoc.yaml`), a directory containing a
+  /// `drydoc.yaml`, or a URI (e.g., `https://example.com/drydoc.yaml` or
+  /// `tar+file://docs.tar#drydoc.yaml`).
   pub path: String,
+  /// The expected hash of the imported file (or archive), e.g.
+  /// `sha256-<base64 digest>`. Required for HTTP imports.
+  #[serde(default)]
+  pub integrity: Option<String>,
+  /// Skip the import, rather than failing, if nothing matches `path`.
+  #[serde(default)]
+  pub optional: bool,
+  #[serde(flatten)]
+  pub preprocess: Preprocess,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(rename_all = "lowercase", tag = "type")]
 pub enum Decl {
-  Generate(Generate),
+  Generate(Box<Generate>),
   Import(Import),
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn package(repository: Option<&str>, name: &str, version_req: Option<&str>) -> GeneratorSpec {
+    GeneratorSpec::Package {
+      repository: repository.map(str::to_string),
+      name: name.to_string(),
+      version_req: version_req.map_or_else(VersionReq::any, |version_req| {
+        VersionReq::parse(version_req).unwrap()
+      }),
+    }
+  }
+
+  #[test]
+  fn parses_packages() {
+    assert_eq!(
+      "clang".parse::<GeneratorSpec>().unwrap(),
+      package(None, "clang", None)
+    );
+    assert_eq!(
+      " clang@^1.2 ".parse::<GeneratorSpec>().unwrap(),
+      package(None, "clang", Some("^1.2"))
+    );
+    assert_eq!(
+      "acme/clang-docs_2@>=1.0.0, <2.0.0"
+        .parse::<GeneratorSpec>()
+        .unwrap(),
+      package(Some("acme"), "clang-docs_2", Some(">=1.0.0, <2.0.0"))
+    );
+  }
+
+  #[test]
+  fn parses_paths() {
+    assert_eq!(
+      "path:./gen".parse::<GeneratorSpec>().unwrap(),
+      GeneratorSpec::Path(PathBuf::from("./gen"))
+    );
+    // Paths aren't validated like names
+    assert_eq!(
+      "path:../my gen@2".parse::<GeneratorSpec>().unwrap(),
+      GeneratorSpec::Path(PathBuf::from("../my gen@2"))
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_specs() {
+    let error = |s: &str| s.parse::<GeneratorSpec>().unwrap_err();
+
+    assert!(matches!(error(""), GeneratorSpecError::Empty));
+    assert!(matches!(error("  "), GeneratorSpecError::Empty));
+    assert!(matches!(error("path:"), GeneratorSpecError::EmptyPath));
+    assert!(matches!(
+      error("clang@one"),
+      GeneratorSpecError::InvalidVersionReq { .. }
+    ));
+    assert!(matches!(
+      error("cl ang"),
+      GeneratorSpecError::InvalidName { name } if name == "cl ang"
+    ));
+    assert!(matches!(
+      error("/clang"),
+      GeneratorSpecError::InvalidName { name } if name.is_empty()
+    ));
+    assert!(matches!(
+      error("acme/"),
+      GeneratorSpecError::InvalidName { name } if name.is_empty()
+    ));
+    assert!(matches!(
+      error("a/b/c"),
+      GeneratorSpecError::InvalidName { name } if name == "b/c"
+    ));
+    assert!(matches!(
+      error("../clang"),
+      GeneratorSpecError::InvalidName { name } if name == ".."
+    ));
+  }
+
+  #[test]
+  fn parses_own_display() {
+    for spec in &["clang", "clang@^1.2", "acme/clang@~1", "path:./gen"] {
+      let spec = spec.parse::<GeneratorSpec>().unwrap();
+      assert_eq!(spec.to_string().parse::<GeneratorSpec>().unwrap(), spec);
+    }
+  }
+}
@@ -1,4 +1,6 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::error::Error;
 
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, Clone, Copy)]
 pub enum Encoding {
@@ -24,4 +26,311 @@ impl Encoding {
       _ => None,
     }
   }
+
+  /// Serialize `data` in this encoding.
+  pub fn serialize<T: Serialize>(&self, data: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(match self {
+      Self::Json => serde_json::to_vec(data)?,
+      // Protocol 3 is understood by every Python 3
+      Self::Pickle => serde_pickle::to_vec(data, true)?,
+      Self::Bincode => bincode::serialize(data)?,
+    })
+  }
+
+  /// Deserialize `data`, which is in this encoding.
+  pub fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Box<dyn Error>> {
+    Ok(match self {
+      Self::Json => serde_json::from_slice(data)?,
+      // Deserializing enums straight from a pickle fails on the memo
+      // references Python's pickler emits, which decoding to a `Value` first
+      // resolves
+      Self::Pickle => serde_pickle::from_value(serde_pickle::value_from_slice(data)?)?,
+      Self::Bincode => bincode::deserialize(data)?,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use serde_json::json;
+
+  use std::collections::HashSet;
+
+  use crate::{
+    bundle::{Bundle, Manifest},
+    client,
+    fs::{File, LinkedFile, LinkedFileHandle, LocalFile, VirtualFile, VirtualFolder},
+    page::Page,
+    server, Message,
+  };
+
+  static ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::Pickle, Encoding::Bincode];
+
+  /// Encode `data` in every encoding, checking that decoding it again gives
+  /// an equal value (compared as JSON, as messages don't implement `Eq`).
+  fn assert_round_trips<T: Serialize + DeserializeOwned>(data: &T) {
+    let expected = serde_json::to_value(data).unwrap();
+    for encoding in ENCODINGS.iter() {
+      let encoded = encoding.serialize(data).unwrap();
+      let decoded: T = encoding.deserialize(&encoded).unwrap();
+      assert_eq!(
+        serde_json::to_value(&decoded).unwrap(),
+        expected,
+        "{:?} doesn't round trip",
+        encoding
+      );
+    }
+  }
+
+  fn bundle() -> Bundle {
+    let root = Page::builder()
+      .id("root")
+      .name("Root")
+      .content_type("text/markdown")
+      .child("root/child")
+      .meta("icon", "book")
+      .order(-1)
+      .build()
+      .unwrap();
+    let child = Page::builder()
+      .id("root/child")
+      .name("Child")
+      .content_type("text/html")
+      .url("child.html")
+      .hidden(true)
+      .build()
+      .unwrap();
+
+    let mut nested = VirtualFolder::new();
+    nested.insert("image.bin", VirtualFile::new(vec![0u8, 1, 2, 255]));
+    let mut resources = VirtualFolder::new();
+    resources.insert("readme.md", VirtualFile::new(b"# Hello".to_vec()));
+    resources.insert("local.txt", LocalFile::new("/tmp/local.txt"));
+    resources.insert(
+      "video.mp4",
+      File::from(LinkedFile::new(LinkedFileHandle::new(3))),
+    );
+    resources.insert("nested", nested);
+
+    Bundle {
+      manifest: Manifest {
+        root: root.id.clone(),
+        symbols: vec![("Child".to_string(), vec![child.id.clone()])]
+          .into_iter()
+          .collect(),
+        pages: vec![(root.id.clone(), root), (child.id.clone(), child)]
+          .into_iter()
+          .collect(),
+      },
+      resources: resources.into(),
+    }
+  }
+
+  fn server_messages() -> Vec<server::MessageData> {
+    let with = json!({
+      "path": "include",
+      "depth": 2,
+      "ratio": 0.5,
+      "recursive": true,
+      "arguments": ["-std=c++17", "-Iinclude"],
+      "env": { "CC": "clang" },
+      "missing": null,
+    });
+
+    // `InitializeRequest` is tested on its own, as the order its encodings
+    // serialize in varies
+    vec![
+      server::Request {
+        id: 2,
+        data: server::OpenContextRequest { id: 7 }.into(),
+      }
+      .into(),
+      server::Request {
+        id: 3,
+        data: server::GenerateRequest::new(
+          7,
+          with.as_object().unwrap().clone(),
+          "docs/drydoc.yaml".to_string(),
+        )
+        .into(),
+      }
+      .into(),
+      server::Request {
+        id: 7,
+        data: server::ReadRequest {
+          handle: LinkedFileHandle::new(3),
+          offset: 0,
+          len: 1 << 20,
+        }
+        .into(),
+      }
+      .into(),
+      server::Response {
+        id: 4,
+        data: server::OpenResponse {
+          handle: LinkedFileHandle::new(1),
+          size: 4,
+        }
+        .into(),
+      }
+      .into(),
+      server::Response {
+        id: 5,
+        data: server::ReadResponse {
+          data: vec![0, 1, 2, 255],
+        }
+        .into(),
+      }
+      .into(),
+      server::Response {
+        id: 6,
+        data: server::ErrorResponse {
+          message: "Unable to open ../secret".to_string(),
+        }
+        .into(),
+      }
+      .into(),
+    ]
+  }
+
+  fn client_messages() -> Vec<client::MessageData> {
+    use client::{MessageData, Request, RequestData, Response, ResponseData};
+
+    let handle = LinkedFileHandle::new(1);
+    vec![
+      MessageData::Request(Request {
+        id: 1,
+        data: RequestData::Open(client::OpenRequest {
+          path: "include/lib.h".to_string(),
+          request: Some(3),
+        }),
+      }),
+      MessageData::Request(Request {
+        id: 2,
+        data: RequestData::Read(client::ReadRequest {
+          handle,
+          offset: 4096,
+          len: 1024,
+        }),
+      }),
+      MessageData::Request(Request {
+        id: 3,
+        data: RequestData::Release(client::ReleaseRequest { handle }),
+      }),
+      MessageData::Event(client::Event::Progress(client::ProgressEvent {
+        context: 7,
+        job: 2,
+        completion: 0.25,
+      })),
+      MessageData::Response(Response {
+        id: 1,
+        data: ResponseData::Initialize(client::InitializeResponse {
+          version: 1,
+          encoding: Encoding::Bincode,
+          requires_direct_fs_access: false,
+        }),
+      }),
+      MessageData::Response(Response {
+        id: 3,
+        data: ResponseData::Generate(client::GenerateResponse { bundle: bundle() }),
+      }),
+      MessageData::Response(Response {
+        id: 4,
+        data: ResponseData::CloseContext(client::CloseContextResponse { bundle: None }),
+      }),
+      MessageData::Response(Response {
+        id: 7,
+        data: ResponseData::Read(client::ReadResponse {
+          data: vec![0, 1, 2, 255],
+        }),
+      }),
+      MessageData::Response(Response {
+        id: 5,
+        data: ResponseData::Error(client::ErrorResponse {
+          message: "Unable to read include".to_string(),
+        }),
+      }),
+    ]
+  }
+
+  #[test]
+  fn server_messages_round_trip() {
+    for message in server_messages() {
+      assert_round_trips(&message);
+    }
+  }
+
+  #[test]
+  fn initialize_request_round_trips() {
+    let supported_encodings: HashSet<Encoding> = ENCODINGS.iter().copied().collect();
+    let request = server::InitializeRequest {
+      version: 1,
+      supported_encodings: supported_encodings.clone(),
+    };
+    for encoding in ENCODINGS.iter() {
+      let encoded = encoding.serialize(&request).unwrap();
+      let decoded: server::InitializeRequest = encoding.deserialize(&encoded).unwrap();
+      assert_eq!(decoded.version, 1);
+      assert_eq!(decoded.supported_encodings, supported_encodings);
+    }
+  }
+
+  #[test]
+  fn client_messages_round_trip() {
+    for message in client_messages() {
+      assert_round_trips(&message);
+    }
+  }
+
+  #[test]
+  fn decodes_python_pickles() {
+    // `pickle.dumps(("Response", {"id": 1, "data": ("Error", {"message": "x"})}), protocol=3)`
+    let pickle = b"\x80\x03X\x08\x00\x00\x00Responseq\x00}q\x01(X\x02\x00\x00\x00idq\x02K\x01X\x04\x00\x00\x00dataq\x03X\x05\x00\x00\x00Errorq\x04}q\x05X\x07\x00\x00\x00messageq\x06X\x01\x00\x00\x00xq\x07s\x86q\x08u\x86q\x09.";
+    let decoded: client::MessageData = Encoding::Pickle.deserialize(pickle).unwrap();
+    assert_eq!(
+      serde_json::to_value(&decoded).unwrap(),
+      json!({ "Response": { "id": 1, "data": { "Error": { "message": "x" } } } })
+    );
+  }
+
+  #[test]
+  fn bincode_carries_params_as_json() {
+    let messages = server_messages();
+    let encoded = Encoding::Bincode.serialize(&messages[1]).unwrap();
+    let decoded: server::MessageData = Encoding::Bincode.deserialize(&encoded).unwrap();
+    match decoded {
+      server::MessageData::Request(server::Request {
+        data: server::RequestData::Generate(generate),
+        ..
+      }) => {
+        assert_eq!(
+          generate.with["arguments"],
+          json!(["-std=c++17", "-Iinclude"])
+        );
+        assert_eq!(generate.with["env"]["CC"], json!("clang"));
+        assert_eq!(generate.params["depth"], "2");
+      }
+      other => panic!("Expected a generate request, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn messages_are_encoded_with_their_encoding() {
+    for encoding in ENCODINGS.iter() {
+      for message in server_messages() {
+        let encoded = Message::encode(*encoding, &message).unwrap();
+        assert_eq!(encoded.encoding(), Some(*encoding));
+        assert_eq!(
+          encoded.data(),
+          encoding.serialize(&message).unwrap().as_slice()
+        );
+        assert_eq!(
+          encoded.size() as usize,
+          encoded.raw().len() - Message::HEADER_SIZE
+        );
+      }
+    }
+  }
 }
@@ -8,8 +8,10 @@ use std::{
 
 use super::*;
 
+use bundle::Keep;
+
 /// An in-memory file.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VirtualFile {
   content: Box<[u8]>,
 }
@@ -32,7 +34,7 @@ impl VirtualFile {
   }
 
   pub fn write_into<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
-    let mut file = std::fs::File::open(path)?;
+    let mut file = std::fs::File::create(path)?;
     file.write_all(&self.content)?;
     Ok(())
   }
@@ -43,7 +45,7 @@ impl VirtualFile {
 }
 
 /// A reference to a file on the local filesystem.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LocalFile {
   path: String,
 }
@@ -68,16 +70,39 @@ impl LocalFile {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq)]
+/// Identifies a file opened by the other end of an IPC connection.
+#[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub struct LinkedFileHandle(u32);
 
-#[derive(Serialize, Deserialize, Debug)]
+impl LinkedFileHandle {
+  pub fn new(id: u32) -> Self {
+    Self(id)
+  }
+
+  pub fn id(&self) -> u32 {
+    self.0
+  }
+}
+
+/// A file held by a generator, whose contents the host reads over IPC, so
+/// that large files don't have to be part of a response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinkedFile {
   handle: LinkedFileHandle,
 }
 
+impl LinkedFile {
+  pub fn new(handle: LinkedFileHandle) -> Self {
+    Self { handle }
+  }
+
+  pub fn handle(&self) -> LinkedFileHandle {
+    self.handle
+  }
+}
+
 /// A file
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum File {
   Virtual(VirtualFile),
   Local(LocalFile),
@@ -89,7 +114,10 @@ impl File {
     match self {
       Self::Virtual(f) => f.write_into(path),
       Self::Local(f) => f.write_into(path),
-      Self::Linked(f) => panic!("Can't write a linked file"),
+      // Only the connection to the generator can read it
+      Self::Linked(_) => Err(std::io::Error::other(
+        "Linked files must be read from their generator before being written",
+      )),
     }
   }
 }
@@ -113,7 +141,7 @@ impl From<LinkedFile> for File {
 }
 
 /// A reference to a folder on the local filesystem.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LocalFolder {
   path: String,
 }
@@ -130,6 +158,8 @@ impl LocalFolder {
   }
 
   pub fn write_into<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    std::fs::create_dir_all(&path)?;
+
     let mut entry_path = path.as_ref().to_path_buf();
     for entry in std::fs::read_dir(&self.path)? {
       let entry = entry?;
@@ -171,7 +201,7 @@ impl LocalFolder {
 }
 
 /// An in-memory folder
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VirtualFolder {
   entries: HashMap<String, Entry>,
 }
@@ -199,6 +229,10 @@ impl VirtualFolder {
     self.entries.iter()
   }
 
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = (&String, &mut Entry)> {
+    self.entries.iter_mut()
+  }
+
   pub fn write_into<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
     std::fs::create_dir_all(&path)?;
 
@@ -215,6 +249,40 @@ impl VirtualFolder {
   }
 }
 
+impl VirtualFolder {
+  fn merge_into(
+    &mut self,
+    prefix: &str,
+    other: VirtualFolder,
+    keep: Keep,
+    conflicts: &mut Vec<String>,
+  ) -> std::io::Result<()> {
+    for (name, entry) in other.entries {
+      let path = format!("{}{}", prefix, name);
+      let merged = match (self.entries.remove(&name), entry) {
+        (None, entry) => entry,
+        (Some(Entry::Folder(existing)), Entry::Folder(entry)) => {
+          let mut existing = existing.to_virtual()?;
+          let prefix = format!("{}/", path);
+          existing.merge_into(prefix.as_str(), entry.to_virtual()?, keep, conflicts)?;
+          existing.into()
+        }
+        (Some(existing), entry) => {
+          conflicts.push(path);
+          match keep {
+            Keep::First => existing,
+            Keep::Second => entry,
+          }
+        }
+      };
+
+      self.entries.insert(name, merged);
+    }
+
+    Ok(())
+  }
+}
+
 impl IntoIterator for VirtualFolder {
   type Item = (String, Entry);
   type IntoIter = std::collections::hash_map::IntoIter<String, Entry>;
@@ -225,7 +293,7 @@ impl IntoIterator for VirtualFolder {
 }
 
 /// A folder
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Folder {
   Virtual(VirtualFolder),
   Local(LocalFolder),
@@ -234,11 +302,25 @@ pub enum Folder {
 impl Folder {
   /// Merge the contents of the folder `other` into this one,
   /// returning the resulting folder.
+  ///
+  /// Subfolders present in both are merged recursively. If both contain a
+  /// file at the same path, the one from `other` is kept.
   pub fn merge<O: Into<Self>>(self, other: O) -> std::io::Result<Self> {
-    let other = other.into().to_virtual()?;
+    Ok(self.merge_reporting(other, Keep::Second)?.0)
+  }
+
+  /// Merge the contents of the folder `other` into this one, returning the
+  /// resulting folder and the `/`-separated paths of the entries both
+  /// contain. `keep` decides which of each pair is kept.
+  pub fn merge_reporting<O: Into<Self>>(
+    self,
+    other: O,
+    keep: Keep,
+  ) -> std::io::Result<(Self, Vec<String>)> {
     let mut this = self.to_virtual()?;
-    this.entries.extend(other.entries);
-    Ok(this.into())
+    let mut conflicts = Vec::new();
+    this.merge_into("", other.into().to_virtual()?, keep, &mut conflicts)?;
+    Ok((this.into(), conflicts))
   }
 
   /// Writes the folder into the directory `path`.
@@ -271,7 +353,7 @@ impl From<LocalFolder> for Folder {
 }
 
 /// An entry in a folder (can be either a child file or folder)
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Entry {
   File(File),
   Folder(Folder),
@@ -1,5 +1,7 @@
 use super::Encoding;
 
+use std::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
 
 use derive_more::*;
@@ -9,18 +11,24 @@ pub enum DecodeError {
   Invalid,
 }
 
+/// A message as sent over the wire: a header holding the size of the rest of
+/// the message, a byte identifying its encoding, and its data in that
+/// encoding.
 #[derive(Serialize, Deserialize)]
 pub struct Message(Box<[u8]>);
 
 impl Message {
+  /// The size of a message's header: its size as a little-endian `u32`.
+  pub const HEADER_SIZE: usize = std::mem::size_of::<u32>();
+
   pub fn decode(raw: Box<[u8]>) -> Result<Self, DecodeError> {
     let len = raw.len();
-    if len < std::mem::size_of::<u32>() + std::mem::size_of::<u8>() {
+    if len < Self::HEADER_SIZE + std::mem::size_of::<u8>() {
       return Err(DecodeError::Invalid);
     }
 
     let ret = Self(raw);
-    if ret.size() as usize != len - (std::mem::size_of::<u32>() + std::mem::size_of::<u8>()) {
+    if ret.size() as usize != len - Self::HEADER_SIZE || ret.encoding().is_none() {
       return Err(DecodeError::Invalid);
     }
 
@@ -31,9 +39,9 @@ impl Message {
     encoding: Encoding,
     data: &T,
   ) -> Result<Self, Box<dyn std::error::Error>> {
-    let data = serde_json::to_vec(data)?;
-    let size = (std::mem::size_of::<u8>() + data.len()) as u32;
-    let mut encoded = Vec::with_capacity(std::mem::size_of::<u32>() + size as usize);
+    let data = encoding.serialize(data)?;
+    let size = u32::try_from(std::mem::size_of::<u8>() + data.len())?;
+    let mut encoded = Vec::with_capacity(Self::HEADER_SIZE + size as usize);
 
     encoded.extend(size.to_le_bytes().iter());
     encoded.push(encoding.as_byte());
@@ -41,18 +49,19 @@ impl Message {
     Ok(Self(encoded.into_boxed_slice()))
   }
 
+  /// The size of the message, excluding its header.
   pub fn size(&self) -> u32 {
-    let mut size = [0u8; 4];
-    size.copy_from_slice(&self.0[0..3]);
+    let mut size = [0u8; Self::HEADER_SIZE];
+    size.copy_from_slice(&self.0[..Self::HEADER_SIZE]);
     u32::from_le_bytes(size)
   }
 
   pub fn encoding(&self) -> Option<Encoding> {
-    Encoding::from_byte(self.0[0])
+    Encoding::from_byte(self.0[Self::HEADER_SIZE])
   }
 
   pub fn data(&self) -> &[u8] {
-    &self.0[1..]
+    &self.0[Self::HEADER_SIZE + 1..]
   }
 
   pub fn raw(&self) -> &[u8] {
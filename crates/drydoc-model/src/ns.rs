@@ -18,35 +18,37 @@ pub struct Namespace {
 
 impl Namespace {
   pub fn new<N: Into<String>>(name: N) -> Arc<Self> {
-    let name: String = name.into();
-    assert!(!name.contains('.'));
-
-    Arc::new(Self { parent: None, name })
+    Arc::new(Self {
+      parent: None,
+      name: name.into(),
+    })
   }
 
   pub fn child<N: Into<String>>(self: &Arc<Self>, name: N) -> Arc<Self> {
-    let name: String = name.into();
-    assert!(!name.contains('.'));
-
     Arc::new(Self {
       parent: Some(self.clone()),
-      name,
+      name: name.into(),
     })
   }
 
+  /// Parse a namespace written by `Display` (i.e., `/`-separated).
   pub fn from_str<S: AsRef<str>>(str: S) -> Result<Self, ParseError> {
     let str = str.as_ref();
-    if let Some(last_start) = str.rfind('.') {
-      Ok(Self {
-        parent: Some(Arc::new(Self::from_str(&str[..last_start])?)),
-        name: str[last_start + 1..].to_string(),
-      })
-    } else {
-      Ok(Self {
-        parent: None,
-        name: str.to_string(),
-      })
+    let (parent, name) = match str.rfind('/') {
+      Some(last_start) => (
+        Some(Arc::new(Self::from_str(&str[..last_start])?)),
+        &str[last_start + 1..],
+      ),
+      None => (None, str),
+    };
+
+    if name.is_empty() {
+      return Err(ParseError::EmptyComponent);
     }
+    Ok(Self {
+      parent,
+      name: name.to_string(),
+    })
   }
 }
 
@@ -85,3 +87,24 @@ impl Serialize for Namespace {
 //     deserializer.deserialize_str();
 //   }
 // }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_displayed_namespaces() {
+    let ns = Namespace::new("root").child("api.v2").child("types");
+    let parsed = Namespace::from_str(ns.to_string()).unwrap();
+    assert_eq!(parsed.to_string(), "root/api.v2/types");
+    assert_eq!(parsed.name, "types");
+    assert_eq!(parsed.parent.unwrap().name, "api.v2");
+  }
+
+  #[test]
+  fn rejects_empty_components() {
+    assert!(Namespace::from_str("").is_err());
+    assert!(Namespace::from_str("root//types").is_err());
+    assert!(Namespace::from_str("root/").is_err());
+  }
+}
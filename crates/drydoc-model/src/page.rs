@@ -3,7 +3,6 @@ use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 
 use std::collections::HashMap;
-use std::collections::HashSet;
 
 use derive_more::*;
 
@@ -40,15 +39,27 @@ pub struct Page {
   pub name: String,
   pub content_type: String,
   pub metadata: HashMap<String, String>,
-  pub children: HashSet<Id>,
+  /// In the order they're listed in, without duplicates
+  pub children: Vec<Id>,
   pub url: Option<String>,
   pub hidden: Option<bool>,
+  /// Where the page is listed among its siblings. Pages with an `order`
+  /// come first, in ascending order.
+  #[serde(default)]
+  pub order: Option<i64>,
 }
 
 impl Page {
   pub fn builder() -> PageBuilder {
     PageBuilder::new()
   }
+
+  /// Append `child` to the children, unless it's already a child.
+  pub fn add_child(&mut self, child: Id) {
+    if !self.children.contains(&child) {
+      self.children.push(child);
+    }
+  }
 }
 
 pub struct PageBuilder {
@@ -56,9 +67,10 @@ pub struct PageBuilder {
   name: Option<String>,
   content_type: Option<String>,
   metadata: HashMap<String, String>,
-  children: HashSet<Id>,
+  children: Vec<Id>,
   url: Option<String>,
   hidden: Option<bool>,
+  order: Option<i64>,
 }
 
 #[derive(Display, Debug, Error)]
@@ -76,8 +88,9 @@ impl PageBuilder {
       content_type: None,
       url: None,
       hidden: None,
+      order: None,
       metadata: HashMap::new(),
-      children: HashSet::new(),
+      children: Vec::new(),
     }
   }
 
@@ -97,13 +110,15 @@ impl PageBuilder {
   }
 
   pub fn child<C: Into<Id>>(mut self, child: C) -> Self {
-    self.children.insert(child.into());
+    let child = child.into();
+    if !self.children.contains(&child) {
+      self.children.push(child);
+    }
     self
   }
 
-  pub fn children<T: Into<Id>, I: Iterator<Item = T>>(mut self, iter: I) -> Self {
-    self.children.extend(iter.map(|i| i.into()));
-    self
+  pub fn children<T: Into<Id>, I: Iterator<Item = T>>(self, iter: I) -> Self {
+    iter.fold(self, |builder, child| builder.child(child))
   }
 
   pub fn meta<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
@@ -121,6 +136,11 @@ impl PageBuilder {
     self
   }
 
+  pub fn order(mut self, order: i64) -> Self {
+    self.order = Some(order);
+    self
+  }
+
   pub fn build(mut self) -> Result<Page, BuildError> {
     let id = match self.id.take() {
       Some(id) => id,
@@ -143,6 +163,7 @@ impl PageBuilder {
       content_type,
       url: self.url,
       hidden: self.hidden,
+      order: self.order,
       metadata: self.metadata,
       children: self.children,
     })
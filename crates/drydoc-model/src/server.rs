@@ -2,6 +2,8 @@ use super::*;
 
 use std::collections::{HashMap, HashSet};
 
+use fs::LinkedFileHandle;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Event {
   Log(Log),
@@ -10,13 +12,72 @@ pub enum Event {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GenerateRequest {
   pub context_id: u32,
+  /// `with` flattened to strings, for generators that predate structured
+  /// parameters. Strings are passed as-is, numbers and booleans as their
+  /// text, `null` as the empty string, and lists and mappings as JSON.
   pub params: HashMap<String, String>,
+  /// The decl's parameters, as written in the configuration
+  #[serde(default, with = "params")]
+  pub with: decl::Params,
   pub path: String,
 }
 
+/// Parameters may be any value, which formats that aren't self-describing
+/// (Bincode) can't deserialize, so those carry them as JSON text instead.
+mod params {
+  use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+  use crate::decl::Params;
+
+  pub fn serialize<S: Serializer>(params: &Params, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+      params.serialize(serializer)
+    } else {
+      let json = serde_json::to_string(params).map_err(ser::Error::custom)?;
+      serializer.serialize_str(json.as_str())
+    }
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Params, D::Error> {
+    if deserializer.is_human_readable() {
+      Params::deserialize(deserializer)
+    } else {
+      let json = String::deserialize(deserializer)?;
+      serde_json::from_str(json.as_str()).map_err(de::Error::custom)
+    }
+  }
+}
+
+impl GenerateRequest {
+  pub fn new(context_id: u32, with: decl::Params, path: String) -> Self {
+    let params = with
+      .iter()
+      .map(|(key, value)| {
+        let value = match value {
+          serde_json::Value::String(value) => value.clone(),
+          serde_json::Value::Null => String::new(),
+          value => value.to_string(),
+        };
+        (key.clone(), value)
+      })
+      .collect();
+
+    Self {
+      context_id,
+      params,
+      with,
+      path,
+    }
+  }
+}
+
+/// The first request sent to a generator. Until the generator responds, both
+/// sides send messages as JSON.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InitializeRequest {
+  /// The IPC version the host speaks
   pub version: u32,
+  /// The encodings the host can send and receive
   pub supported_encodings: HashSet<Encoding>,
 }
 
@@ -30,12 +91,30 @@ pub struct CloseContextRequest {
   pub id: u32,
 }
 
+/// Read up to `len` bytes of a `LinkedFile` the generator returned, starting
+/// at `offset`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadRequest {
+  pub handle: LinkedFileHandle,
+  pub offset: u64,
+  pub len: u32,
+}
+
+/// Sent once the host has read a `LinkedFile`, after which the generator may
+/// discard it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReleaseRequest {
+  pub handle: LinkedFileHandle,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RequestData {
   Initialize(InitializeRequest),
   OpenContext(OpenContextRequest),
   CloseContext(CloseContextRequest),
   Generate(GenerateRequest),
+  Read(ReadRequest),
+  Release(ReleaseRequest),
 }
 
 impl From<InitializeRequest> for RequestData {
@@ -62,6 +141,18 @@ impl From<GenerateRequest> for RequestData {
   }
 }
 
+impl From<ReadRequest> for RequestData {
+  fn from(value: ReadRequest) -> Self {
+    Self::Read(value)
+  }
+}
+
+impl From<ReleaseRequest> for RequestData {
+  fn from(value: ReleaseRequest) -> Self {
+    Self::Release(value)
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
   pub id: u64,
@@ -69,7 +160,58 @@ pub struct Request {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub enum ResponseData {}
+pub struct OpenResponse {
+  pub handle: LinkedFileHandle,
+  /// The size of the file when it was opened
+  pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadResponse {
+  /// The bytes read, which are empty at the end of the file
+  pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReleaseResponse {}
+
+/// Sent in place of a response when a request couldn't be handled.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+  pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ResponseData {
+  Open(OpenResponse),
+  Read(ReadResponse),
+  Release(ReleaseResponse),
+  Error(ErrorResponse),
+}
+
+impl From<OpenResponse> for ResponseData {
+  fn from(value: OpenResponse) -> Self {
+    Self::Open(value)
+  }
+}
+
+impl From<ReadResponse> for ResponseData {
+  fn from(value: ReadResponse) -> Self {
+    Self::Read(value)
+  }
+}
+
+impl From<ReleaseResponse> for ResponseData {
+  fn from(value: ReleaseResponse) -> Self {
+    Self::Release(value)
+  }
+}
+
+impl From<ErrorResponse> for ResponseData {
+  fn from(value: ErrorResponse) -> Self {
+    Self::Error(value)
+  }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
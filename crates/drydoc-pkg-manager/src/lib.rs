@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 use std::{
-  collections::{HashMap, HashSet},
+  collections::{BTreeMap, HashMap, HashSet},
   fs::read_dir,
   path::{Path, PathBuf},
   str::FromStr,
@@ -132,10 +132,79 @@ pub enum IpcChannel {
   Tcp { port: u16 },
 }
 
+/// The type of a generator parameter's value.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+  String,
+  Number,
+  Integer,
+  Boolean,
+  List,
+  Map,
+  #[default]
+  Any,
+}
+
+impl std::fmt::Display for ParameterType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let name = match self {
+      Self::String => "a string",
+      Self::Number => "a number",
+      Self::Integer => "an integer",
+      Self::Boolean => "a boolean",
+      Self::List => "a list",
+      Self::Map => "a map",
+      Self::Any => "any value",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// Describes a parameter a generator accepts in `with`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ParameterSchema {
+  #[serde(rename = "type", default)]
+  pub ty: ParameterType,
+  #[serde(default)]
+  pub required: bool,
+  /// Used when the parameter isn't given
+  #[serde(default)]
+  pub default: Option<serde_json::Value>,
+  /// A description of the parameter, shown in diagnostics
+  #[serde(default)]
+  pub doc: Option<String>,
+}
+
+impl ParameterSchema {
+  /// Whether `value` is of this parameter's type.
+  pub fn accepts(&self, value: &serde_json::Value) -> bool {
+    use serde_json::Value;
+
+    match (self.ty, value) {
+      (ParameterType::Any, _) => true,
+      (ParameterType::String, Value::String(_)) => true,
+      (ParameterType::Number, Value::Number(_)) => true,
+      (ParameterType::Integer, Value::Number(number)) => number.is_i64() || number.is_u64(),
+      (ParameterType::Boolean, Value::Bool(_)) => true,
+      (ParameterType::List, Value::Array(_)) => true,
+      (ParameterType::Map, Value::Object(_)) => true,
+      _ => false,
+    }
+  }
+}
+
+/// The parameters a generator accepts, by name.
+pub type Parameters = BTreeMap<String, ParameterSchema>;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GeneratorArtifact {
   pub entrypoint: String,
   pub ipc_channel: IpcChannel,
+  /// The parameters the generator accepts. If omitted, `with` isn't
+  /// validated.
+  #[serde(default)]
+  pub parameters: Option<Parameters>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -172,6 +241,14 @@ impl Artifact {
   }
 }
 
+/// The artifact of an installed package version.
+#[derive(Debug)]
+pub struct InstalledArtifact {
+  pub name: String,
+  pub version: Version,
+  pub artifact: Artifact,
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PackageVersion {
@@ -344,6 +421,46 @@ impl<F: Fetcher> Manager<F> {
     Ok(ret)
   }
 
+  /// The artifact of every installed package version. Directories that
+  /// aren't installed packages (e.g., the `repositories` directory of
+  /// additional repositories) are skipped.
+  pub fn installed_artifacts(&self) -> Result<Vec<InstalledArtifact>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+    if !self.dir.is_dir() {
+      return Ok(ret);
+    }
+
+    for entry in read_dir(&self.dir)? {
+      let package_dir = entry?.path();
+      let package_name = match package_dir.file_name().and_then(|name| name.to_str()) {
+        Some(name) if package_dir.is_dir() => name.to_string(),
+        _ => continue,
+      };
+
+      for entry in read_dir(&package_dir)? {
+        let path = entry?.path().join("artifact.json");
+        let version = match path
+          .parent()
+          .and_then(Path::file_name)
+          .and_then(|name| name.to_str())
+          .and_then(|name| Version::from_str(name).ok())
+        {
+          Some(version) if path.is_file() => version,
+          _ => continue,
+        };
+
+        let artifact = serde_json::from_str(std::fs::read_to_string(&path)?.as_str())?;
+        ret.push(InstalledArtifact {
+          name: package_name.clone(),
+          version,
+          artifact,
+        });
+      }
+    }
+
+    Ok(ret)
+  }
+
   pub async fn get(
     &mut self,
     name: &str,
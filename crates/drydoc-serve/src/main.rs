@@ -1,5 +1,20 @@
 use clap::Clap;
 
+use std::{
+  convert::Infallible,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+use futures::Stream;
+use warp::{sse::ServerSentEvent, Filter};
+
+/// Written into the output directory by `drydoc watch` after every rebuild.
+static RELOAD_STAMP: &str = ".drydoc-reload";
+
+/// How often to check whether the site has been regenerated.
+static POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(Clap, Debug)]
 pub struct ServeOpts {
   #[clap(short, long, default_value = "html")]
@@ -12,12 +27,39 @@ pub struct ServeOpts {
   port: u16,
 }
 
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// A stream that emits a `reload` event every time `stamp` is modified.
+fn reload_events(stamp: PathBuf) -> impl Stream<Item = Result<impl ServerSentEvent, Infallible>> {
+  let last = modified(&stamp);
+  futures::stream::unfold((stamp, last), |(stamp, last)| async move {
+    loop {
+      tokio::time::delay_for(POLL_INTERVAL).await;
+      let current = modified(&stamp);
+      if current != last {
+        let event = (warp::sse::event("reload"), warp::sse::data(""));
+        return Some((Ok(event), (stamp, current)));
+      }
+    }
+  })
+}
+
 #[tokio::main]
 async fn main() {
   let opts = ServeOpts::parse();
+  let stamp = Path::new(opts.dir.as_str()).join(RELOAD_STAMP);
+
+  // Browsers connected to this endpoint are told to reload the page whenever
+  // `drydoc watch` regenerates the site.
+  let reload = warp::path!("__drydoc" / "reload")
+    .and(warp::get())
+    .map(move || warp::sse::reply(warp::sse::keep_alive().stream(reload_events(stamp.clone()))));
+
   let hello = warp::fs::dir(opts.dir);
 
-  warp::serve(hello)
+  warp::serve(reload.or(hello))
     .run((opts.address.parse::<std::net::IpAddr>().unwrap(), opts.port))
     .await;
 }
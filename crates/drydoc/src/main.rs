@@ -3,26 +3,41 @@ use std::fs;
 use std::io::Result;
 use std::process::{Command, Stdio};
 
+/// Commands that are implemented as subcommands of another handler.
+/// For example, `drydoc watch [args...]` is transformed into
+/// `drydoc-gen watch [args...]`.
+static SUBCOMMANDS: &[(&str, &str)] = &[("check", "gen"), ("schema", "gen"), ("watch", "gen")];
+
 fn run() -> Result<()> {
   let current_exe = current_exe()?;
   let exe_dir = current_exe.parent().unwrap();
   let args: Vec<String> = args().skip(1).collect();
 
   if let Some((head, tail)) = args.split_first() {
+    let (handler, subcommand) = match SUBCOMMANDS.iter().find(|(name, _)| name == head) {
+      Some((name, handler)) => (*handler, Some(*name)),
+      None => (head.as_str(), None),
+    };
+
     let mut path = exe_dir.to_path_buf();
     path.push(format!(
       "drydoc-{}{}",
-      head,
+      handler,
       if cfg!(windows) { ".exe" } else { "" }
     ));
 
     let cmd = Command::new(path)
+      .args(subcommand)
       .args(tail)
       .stdout(Stdio::inherit())
       .stderr(Stdio::inherit())
       .spawn()?;
 
-    cmd.wait_with_output()?;
+    // Forward the handler's exit code so scripts (e.g., CI) can rely on it
+    let status = cmd.wait_with_output()?.status;
+    if !status.success() {
+      std::process::exit(status.code().unwrap_or(1));
+    }
   } else {
     for entry in fs::read_dir(exe_dir)? {
       let entry = entry?;